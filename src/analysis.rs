@@ -0,0 +1,522 @@
+//! Ranking sites by their place in the site graph.
+//!
+//! The site graph is loaded from the `Database` into a `LinkGraph`,
+//! which holds every site's outgoing and incoming links as lists of node indices.
+//! Every score is deterministic: the same graph always gets the same scores,
+//! including betweenness, whose sampled sources come from a seeded generator.
+
+use crate::database::{Database, DatabaseError, ScoreColumn, ScoreEntry};
+use std::collections::{HashMap, HashSet, VecDeque};
+use url::Url;
+
+/// A directed graph of sites, with at most one link from any site to another and no self-links.
+///
+/// Nodes are numbered in the order they were added, `load()` adds them ordered by url.
+#[derive(Debug, Clone, Default)]
+pub struct LinkGraph {
+    urls: Vec<String>,
+    index: HashMap<String, usize>,
+    outlinks: Vec<Vec<usize>>,
+    inlinks: Vec<Vec<usize>>,
+    links: HashSet<(usize, usize)>,
+}
+
+impl LinkGraph {
+    pub fn new() -> LinkGraph {
+        LinkGraph::default()
+    }
+
+    /// Reads every site and every link between sites from `db`,
+    /// with redirect-only sites collapsed (see `Database::get_site_graph()`).
+    pub fn load(db: &Database) -> Result<LinkGraph, DatabaseError> {
+        let redirects = db.get_site_redirects()?;
+        let mut graph = LinkGraph::new();
+        for s in db.get_sites()? {
+            if !redirects.contains_key(s.url()) {
+                graph.add_site(s.url());
+            }
+        }
+        for l in db.get_site_graph()? {
+            graph.add_link(l.srcurl(), l.dsturl());
+        }
+        Ok(graph)
+    }
+
+    /// Adds the site at `url` if it isn't in the graph yet, and returns its node.
+    pub fn add_site(&mut self, url: &str) -> usize {
+        if let Some(&i) = self.index.get(url) {
+            return i;
+        }
+        let i = self.urls.len();
+        self.urls.push(url.to_string());
+        self.index.insert(url.to_string(), i);
+        self.outlinks.push(Vec::new());
+        self.inlinks.push(Vec::new());
+        i
+    }
+
+    /// Adds a link between two sites, adding the sites too if needed.
+    /// Links a site already has and links to itself are ignored.
+    pub fn add_link(&mut self, srcurl: &str, dsturl: &str) {
+        let src = self.add_site(srcurl);
+        let dst = self.add_site(dsturl);
+        if src == dst || !self.links.insert((src, dst)) {
+            return;
+        }
+        self.outlinks[src].push(dst);
+        self.inlinks[dst].push(src);
+    }
+
+    pub fn len(&self) -> usize {
+        self.urls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.urls.is_empty()
+    }
+
+    /// Returns the number of links in the graph.
+    pub fn link_count(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn url(&self, node: usize) -> &str {
+        &self.urls[node]
+    }
+
+    pub fn node(&self, url: &str) -> Option<usize> {
+        self.index.get(url).copied()
+    }
+
+    /// Returns the nodes `node` links to, in the order the links were added.
+    pub fn outlinks(&self, node: usize) -> &[usize] {
+        &self.outlinks[node]
+    }
+
+    /// Returns the nodes that link to `node`, in the order the links were added.
+    pub fn inlinks(&self, node: usize) -> &[usize] {
+        &self.inlinks[node]
+    }
+
+    /// Computes the PageRank of every node. The scores add up to 1.
+    ///
+    /// Iterates until the scores change by less than `config.tolerance` in total,
+    /// or `config.iterations` times at most.
+    pub fn pagerank(&self, config: &PageRankConfig) -> Vec<f64> {
+        let n = self.len();
+        if n == 0 {
+            return Vec::new();
+        }
+        let d = config.damping;
+        let mut rank = vec![1.0 / n as f64; n];
+        for _ in 0..config.iterations {
+            let mut next = vec![0.0; n];
+            let mut spread = 0.0; /* rank that goes to every site alike */
+            for (i, r) in rank.iter().enumerate() {
+                /* a site that links nowhere passes its rank on as if it did */
+                let targets = match (self.outlinks[i].is_empty(), config.dangling) {
+                    (false, _) => &self.outlinks[i],
+                    (true, Dangling::Back) if !self.inlinks[i].is_empty() => &self.inlinks[i],
+                    (true, _) => {
+                        spread += r / n as f64;
+                        continue;
+                    }
+                };
+                let share = r / targets.len() as f64;
+                for &j in targets {
+                    next[j] += share;
+                }
+            }
+            for r in &mut next {
+                *r = (1.0 - d) / n as f64 + d * (*r + spread);
+            }
+            let change: f64 = rank.iter().zip(&next).map(|(a, b)| (a - b).abs()).sum();
+            rank = next;
+            if change < config.tolerance {
+                break;
+            }
+        }
+        rank
+    }
+
+    /// Computes HITS hub and authority scores, each scaled to a length of 1.
+    ///
+    /// A good hub links to good authorities, a good authority is linked from good hubs.
+    pub fn hits(&self, iterations: usize, tolerance: f64) -> Hits {
+        let n = self.len();
+        let mut hubs = vec![1.0; n];
+        let mut authorities = vec![0.0; n];
+        for _ in 0..iterations {
+            for (i, a) in authorities.iter_mut().enumerate() {
+                *a = self.inlinks[i].iter().map(|&j| hubs[j]).sum();
+            }
+            normalize(&mut authorities);
+            let mut next: Vec<f64> = (0..n)
+                .map(|i| self.outlinks[i].iter().map(|&j| authorities[j]).sum())
+                .collect();
+            normalize(&mut next);
+            let change: f64 = hubs.iter().zip(&next).map(|(a, b)| (a - b).abs()).sum();
+            hubs = next;
+            if change < tolerance {
+                break;
+            }
+        }
+        Hits { hubs, authorities }
+    }
+
+    /// Computes the betweenness centrality of every node: the number of shortest paths
+    /// between other nodes that go through it (split evenly between equally short paths).
+    ///
+    /// Exact when `samples` is at least the number of nodes, otherwise estimated from
+    /// the shortest paths out of `samples` nodes picked with `seed`, scaled up to the whole graph.
+    pub fn betweenness(&self, samples: usize, seed: u64) -> Vec<f64> {
+        let n = self.len();
+        let mut sources: Vec<usize> = (0..n).collect();
+        if samples < n {
+            /* the first `samples` steps of a fisher-yates shuffle */
+            let mut rng = XorShift::new(seed);
+            for i in 0..samples {
+                let j = i + (rng.next() % (n - i) as u64) as usize;
+                sources.swap(i, j);
+            }
+            sources.truncate(samples);
+        }
+
+        /* brandes' algorithm, from each source in turn */
+        let mut centrality = vec![0.0; n];
+        let mut distance: Vec<Option<usize>> = vec![None; n];
+        let mut paths = vec![0.0f64; n];
+        let mut dependency = vec![0.0f64; n];
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for &s in &sources {
+            let mut order = Vec::new();
+            for i in 0..n {
+                distance[i] = None;
+                paths[i] = 0.0;
+                dependency[i] = 0.0;
+                predecessors[i].clear();
+            }
+            distance[s] = Some(0);
+            paths[s] = 1.0;
+            let mut queue = VecDeque::from([s]);
+            while let Some(v) = queue.pop_front() {
+                order.push(v);
+                let dv = distance[v].unwrap();
+                for &w in &self.outlinks[v] {
+                    if distance[w].is_none() {
+                        distance[w] = Some(dv + 1);
+                        queue.push_back(w);
+                    }
+                    if distance[w] == Some(dv + 1) {
+                        paths[w] += paths[v];
+                        predecessors[w].push(v);
+                    }
+                }
+            }
+            while let Some(w) = order.pop() {
+                for &v in &predecessors[w] {
+                    dependency[v] += paths[v] / paths[w] * (1.0 + dependency[w]);
+                }
+                if w != s {
+                    centrality[w] += dependency[w];
+                }
+            }
+        }
+
+        if !sources.is_empty() && sources.len() < n {
+            let scale = n as f64 / sources.len() as f64;
+            for c in &mut centrality {
+                *c *= scale;
+            }
+        }
+        centrality
+    }
+}
+
+/// What PageRank does with the rank of sites that don't link anywhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dangling {
+    Distribute, // spread it evenly over every site, as if they linked to all of them
+    Back,       // give it back to the sites linking to them, like a visitor pressing back
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRankConfig {
+    pub damping: f64, // chance of following a link rather than jumping to a random site
+    pub dangling: Dangling,
+    pub iterations: usize, // most iterations before giving up on converging
+    pub tolerance: f64,    // total change in scores that counts as converged
+}
+
+impl Default for PageRankConfig {
+    fn default() -> Self {
+        PageRankConfig {
+            damping: 0.85,
+            dangling: Dangling::Distribute,
+            iterations: 100,
+            tolerance: 1e-10,
+        }
+    }
+}
+
+/// HITS scores, indexed by node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hits {
+    pub hubs: Vec<f64>,
+    pub authorities: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisConfig {
+    pub pagerank: PageRankConfig,
+    pub samples: usize, // betweenness sources, see `LinkGraph::betweenness()`
+    pub seed: u64,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            pagerank: PageRankConfig::default(),
+            samples: 200,
+            seed: 0x6e656f6d6170, // "neomap"
+        }
+    }
+}
+
+/// A score that sites can be ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    PageRank,
+    Hub,
+    Authority,
+    InDegree,
+    OutDegree,
+    Betweenness,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 6] = [
+        Metric::PageRank,
+        Metric::Hub,
+        Metric::Authority,
+        Metric::InDegree,
+        Metric::OutDegree,
+        Metric::Betweenness,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::PageRank => "pagerank",
+            Metric::Hub => "hub",
+            Metric::Authority => "authority",
+            Metric::InDegree => "indegree",
+            Metric::OutDegree => "outdegree",
+            Metric::Betweenness => "betweenness",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Metric> {
+        Metric::ALL.into_iter().find(|m| m.as_str() == s)
+    }
+
+    /// Returns the column of the `sitescore` table the score is kept in.
+    pub fn column(&self) -> ScoreColumn {
+        match self {
+            Metric::PageRank => ScoreColumn::PageRank,
+            Metric::Hub => ScoreColumn::Hub,
+            Metric::Authority => ScoreColumn::Authority,
+            Metric::InDegree => ScoreColumn::InDegree,
+            Metric::OutDegree => ScoreColumn::OutDegree,
+            Metric::Betweenness => ScoreColumn::Betweenness,
+        }
+    }
+}
+
+/// Computes every score for every site in `graph`.
+pub fn analyze(
+    graph: &LinkGraph,
+    config: &AnalysisConfig,
+) -> Result<Vec<ScoreEntry>, DatabaseError> {
+    let pagerank = graph.pagerank(&config.pagerank);
+    let hits = graph.hits(config.pagerank.iterations, config.pagerank.tolerance);
+    let betweenness = graph.betweenness(config.samples, config.seed);
+    let mut scores = Vec::with_capacity(graph.len());
+    for i in 0..graph.len() {
+        let url = Url::parse(graph.url(i))
+            .map_err(|_| DatabaseError::InvalidUrl(graph.url(i).to_string()))?;
+        scores.push(
+            ScoreEntry::new(url, pagerank[i])?
+                .with_hits(hits.hubs[i], hits.authorities[i])
+                .with_degree(
+                    graph.inlinks(i).len() as i64,
+                    graph.outlinks(i).len() as i64,
+                )
+                .with_betweenness(betweenness[i]),
+        );
+    }
+    Ok(scores)
+}
+
+/// Replaces the scores in the database with `scores`.
+/// Scores of sites that aren't in the database are left out.
+pub fn save_scores(db: &Database, scores: Vec<ScoreEntry>) -> Result<(), DatabaseError> {
+    let sites: HashSet<String> = db
+        .get_sites()?
+        .iter()
+        .map(|s| s.url().to_string())
+        .collect();
+    db.in_transaction(|db| {
+        db.delete_scores()?;
+        for s in scores {
+            if sites.contains(s.siteurl()) {
+                db.set_score(s)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Scales `v` to a length of 1, unless it is all zeros.
+fn normalize(v: &mut [f64]) {
+    let length = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if length > 0.0 {
+        for x in v.iter_mut() {
+            *x /= length;
+        }
+    }
+}
+
+/// xorshift64, a small generator that is plenty for picking samples.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        /* an all-zero state would only ever give zeros */
+        XorShift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        analyze, save_scores, AnalysisConfig, Dangling, LinkGraph, Metric, PageRankConfig,
+    };
+    use crate::testgraph::{database, graph};
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn adjacency() {
+        let g = graph(&[("a", "b"), ("a", "b"), ("b", "b"), ("b", "c")]);
+        assert_eq!(g.len(), 3);
+        assert_eq!(g.link_count(), 2);
+        let b = g.node("b").unwrap();
+        assert_eq!(g.outlinks(b), &[g.node("c").unwrap()]);
+        assert_eq!(g.inlinks(b), &[g.node("a").unwrap()]);
+    }
+
+    #[test]
+    fn pagerank_cycle() {
+        // every site in a cycle is as important as the others
+        let g = graph(&[("a", "b"), ("b", "c"), ("c", "a")]);
+        let third = 1.0 / 3.0;
+        assert_close(&g.pagerank(&PageRankConfig::default()), &[third; 3]);
+    }
+
+    #[test]
+    fn pagerank_dangling() {
+        // a -> b -> c, and c links nowhere
+        let g = graph(&[("a", "b"), ("b", "c")]);
+        let config = PageRankConfig {
+            damping: 0.5,
+            ..PageRankConfig::default()
+        };
+        // with c's rank spread over everyone, by hand:
+        // a = 1/6 + c/6, b = a + a/2, c = a + b/2, adding up to 1
+        assert_close(&g.pagerank(&config), &[4.0 / 17.0, 6.0 / 17.0, 7.0 / 17.0]);
+
+        // with c's rank going back to b: a = 1/6, b = 1/6 + a/2 + c/2, c = 1/6 + b/2
+        let config = PageRankConfig {
+            dangling: Dangling::Back,
+            ..config
+        };
+        assert_close(&g.pagerank(&config), &[1.0 / 6.0, 4.0 / 9.0, 7.0 / 18.0]);
+        assert!(LinkGraph::new().pagerank(&config).is_empty());
+    }
+
+    #[test]
+    fn hits() {
+        // a -> c, b -> c, b -> d
+        let g = graph(&[("a", "c"), ("b", "c"), ("b", "d")]);
+        let hits = g.hits(100, 1e-12);
+        let golden = (1.0 + 5f64.sqrt()) / 2.0;
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|n| g.node(n).unwrap());
+        // c is the better authority and b the better hub, both by the golden ratio
+        assert!((hits.authorities[c] / hits.authorities[d] - golden).abs() < 1e-6);
+        assert!((hits.hubs[b] / hits.hubs[a] - golden).abs() < 1e-6);
+        assert_eq!(hits.hubs[c], 0.0);
+        assert_eq!(hits.authorities[a], 0.0);
+        let length: f64 = hits.authorities.iter().map(|x| x * x).sum();
+        assert!((length - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn betweenness() {
+        // a -> b -> c, and a -> d -> c: b and d each carry half of the paths from a to c
+        let g = graph(&[("a", "b"), ("b", "c"), ("a", "d"), ("d", "c"), ("c", "e")]);
+        let exact = g.betweenness(usize::MAX, 0);
+        // b: half of a->c and half of a->e, c: a->e, b->e and d->e
+        assert_close(&exact, &[0.0, 1.0, 3.0, 1.0, 0.0]);
+
+        // samples are picked the same way every time
+        let sampled = g.betweenness(2, 7);
+        assert_eq!(sampled, g.betweenness(2, 7));
+        assert!(sampled.iter().all(|&b| b >= 0.0));
+    }
+
+    #[test]
+    fn scores() {
+        let db = database(&[
+            ("alice", "bob"),
+            ("bob", "carol"),
+            ("carol", "alice"),
+            ("dave", "alice"),
+        ]);
+
+        let g = LinkGraph::load(&db).unwrap();
+        assert_eq!((g.len(), g.link_count()), (4, 4));
+        let scores = analyze(&g, &AnalysisConfig::default()).unwrap();
+        save_scores(&db, scores.clone()).unwrap();
+        save_scores(&db, scores).unwrap();
+
+        let ranked = db.get_scores_by(Metric::PageRank.column(), 10).unwrap();
+        let ranked: Vec<&str> = ranked.iter().map(|s| s.siteurl()).collect();
+        assert_eq!(
+            ranked,
+            vec![
+                "https://alice.neocities.org/",
+                "https://bob.neocities.org/",
+                "https://carol.neocities.org/",
+                "https://dave.neocities.org/",
+            ]
+        );
+        let top = &db.get_scores_by(Metric::InDegree.column(), 1).unwrap()[0];
+        assert_eq!(top.siteurl(), "https://alice.neocities.org/");
+        assert_eq!((top.indegree(), top.outdegree()), (2, 1));
+        // dave links to alice, and nothing else goes through him
+        let dave = &db.get_scores_by(Metric::Betweenness.column(), 10).unwrap()[3];
+        assert_eq!(dave.betweenness(), 0.0);
+    }
+}
@@ -1,57 +1,281 @@
+use neomap::canonical::canonicalize;
+use neomap::cli::{parse_rate, parse_wait, OptionArg};
+use neomap::database::{Database, SiteEntry};
+use neomap::fetcher::{Fetcher, FetcherConfig};
+use neomap::{page::Page, pagecrawler, pagecrawler::PageCrawler};
 use std::env;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 use url::Url;
-use neomap::{page::Page, pagecrawler, pagecrawler::PageCrawler};
+
+const HELP: &str = "\
+neolinks - print the links on neocities pages
+
+Usage:
+  neolinks page [options] [url...]   print the links on the given pages
+  neolinks site [options] [url...]   crawl the given sites and print the links on all their pages
+  neolinks backlinks [options] [url...]
+                                     print the sites that link to the given sites, from a neomap database
+  neolinks help                      print this help
+
+Urls are read from stdin, one per line, if none are given or one of them is \"-\".
+Links from all urls are merged, sorted and printed once each.
+
+Options:
+  -d, --domain <domain>   only print links whose domain ends with <domain>
+      --html              only print links to html pages
+  -w, --wait <seconds>    seconds to wait between requests to the same host (default 1)
+  -q, --rate <rps>        requests per second over all hosts, 0 for no limit (default 5)
+      --db <path>         neomap database to look up backlinks in (default neomap.db)
+  -h, --help              print this help
+
+Exit status is 0 on success, 1 if any url couldn't be fetched, and 2 for invalid arguments.";
+
+/// What to do with the urls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Page,      // just the given pages
+    Site,      // every page of the given sites
+    Backlinks, // the sites linking to the given sites, from the database
+}
+
+#[derive(Debug)]
+struct Options {
+    mode: Mode,
+    domain: String,
+    is_html_only: bool,
+    config: FetcherConfig,
+    dbpath: PathBuf,
+    urls: Vec<String>, // as given, "-" stands for stdin
+}
+
+/// What the arguments ask for.
+#[derive(Debug)]
+enum Command {
+    Help,
+    Run(Box<Options>),
+}
 
 fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(Command::Run(o)) => *o,
+        Ok(Command::Help) => {
+            let _ = writeln!(io::stdout(), "{HELP}");
+            return;
+        }
+        Err(e) => fail(&e),
+    };
 
-    let mut domain = String::new();
-    let mut is_recursive = false;
-    let mut is_html_only = false;
-
-    /* find and parse any options */
-    let options: Vec<&String> = args.iter().filter(|&a| a.starts_with("-")).collect();
-    for o in options {
-        if o.starts_with("-d") {
-            domain = o.chars().skip(2).collect();
-        } else if o == "-r" {
-            is_recursive = true;
-        } else if o == "-h" {
-            is_html_only = true;
+    let urls = match read_urls(&options.urls) {
+        Ok(u) => u,
+        Err(e) => fail(&e),
+    };
+    if urls.is_empty() {
+        fail("no urls given");
+    }
+
+    let (mut links, ok) = match options.mode {
+        Mode::Backlinks => backlinks(urls, &options.dbpath),
+        Mode::Page | Mode::Site => crawl(urls, options.mode, &options.config),
+    };
+    output(&mut links, &options.domain, options.is_html_only);
+    if !ok {
+        process::exit(1);
+    }
+}
+
+/// Prints `message` and the usage hint, then exits with status 2.
+fn fail(message: &str) -> ! {
+    eprintln!("neolinks: {message}");
+    eprintln!("try \"neolinks --help\" for more information");
+    process::exit(2);
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+    let mode = match args.next().map(String::as_str) {
+        Some("page") => Mode::Page,
+        Some("site") => Mode::Site,
+        Some("backlinks") => Mode::Backlinks,
+        Some("help") | Some("-h") | Some("--help") | None => return Ok(Command::Help),
+        Some(other) => return Err(format!("unknown command \"{other}\"")),
+    };
+
+    let mut options = Options {
+        mode,
+        domain: String::new(),
+        is_html_only: false,
+        config: FetcherConfig::default(),
+        dbpath: PathBuf::from("neomap.db"),
+        urls: Vec::new(),
+    };
+    let mut only_urls = false; /* after "--", everything is a url */
+    while let Some(arg) = args.next() {
+        if only_urls || arg == "-" || !arg.starts_with('-') {
+            options.urls.push(arg.clone());
+            continue;
+        }
+        if arg == "--" {
+            only_urls = true;
+            continue;
+        }
+
+        /* options can have their value in the next argument, after "=" or glued on (-dfoo) */
+        let option = OptionArg::parse(arg);
+        let name = option.name;
+        let mut value = || option.value(&mut args);
+
+        match name {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--html" => options.is_html_only = true,
+            "-d" | "--domain" => options.domain = value()?,
+            "--db" => options.dbpath = PathBuf::from(value()?),
+            "-w" | "--wait" => options.config.politeness.hostdelay = parse_wait(&value()?)?,
+            "-q" | "--rate" => options.config.politeness.maxrps = parse_rate(&value()?)?,
+            _ => return Err(format!("unknown option \"{arg}\"")),
         }
     }
+    Ok(Command::Run(Box::new(options)))
+}
 
-    /* get anything that comes after options */
-    let sites: Vec<&String> = args.iter().skip_while(|&a| a.starts_with("-")).collect();
+/// Parses `args` into urls, reading urls from stdin in place of "-" (or if there are no `args`).
+fn read_urls(args: &[String]) -> Result<Vec<Url>, String> {
+    let mut lines: Vec<String> = Vec::new();
+    if args.is_empty() || args.iter().any(|a| a == "-") {
+        for line in io::stdin().lock().lines() {
+            let line = line.map_err(|e| format!("can't read stdin: {e}"))?;
+            lines.push(line);
+        }
+    }
+    let stdin = lines
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'));
 
-    let mut links: Vec<Url> = Vec::new();
-    for s in sites {
-        if is_recursive {
-            links = crawl_site(s);
-        } else {
-            links = crawl_page(s);
+    let mut urls = Vec::new();
+    for a in args
+        .iter()
+        .map(String::as_str)
+        .filter(|&a| a != "-")
+        .chain(stdin)
+    {
+        urls.push(parse_url(a)?);
+    }
+    Ok(urls)
+}
+
+/// Parses a url given on the command line.
+/// Urls without a scheme are assumed to be https (e.g. "dawnvoid.neocities.org").
+fn parse_url(s: &str) -> Result<Url, String> {
+    let url = match Url::parse(s) {
+        Ok(u) => u,
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            Url::parse(&format!("https://{s}")).map_err(|e| format!("invalid url \"{s}\": {e}"))?
         }
+        Err(e) => return Err(format!("invalid url \"{s}\": {e}")),
+    };
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("invalid url \"{s}\": not http(s)"));
     }
+    Ok(url)
+}
+
+/// Fetches each of `urls` (or crawls its site, depending on `mode`) and returns the links found.
+/// Also returns whether every url could be fetched.
+fn crawl(urls: Vec<Url>, mode: Mode, config: &FetcherConfig) -> (Vec<Url>, bool) {
+    let fetcher = match Fetcher::new(config.clone()) {
+        Ok(f) => f,
+        Err(e) => fail(&e.to_string()),
+    };
 
-    output(&mut links, &domain, is_html_only);
+    let mut links: Vec<Url> = Vec::new();
+    let mut ok = true;
+    for url in urls {
+        let found = match mode {
+            Mode::Site => crawl_site(url, &fetcher),
+            _ => crawl_page(url, &fetcher),
+        };
+        match found {
+            Some(l) => links.extend(l),
+            None => ok = false,
+        }
+    }
+    (links, ok)
 }
 
-fn crawl_site(site: &str) -> Vec<Url> {
-    let url = Url::parse(site).unwrap();
-    let mut crawler = match PageCrawler::new(url) {
-        Ok(c) => c,
-        Err(_) => todo!(),
+/// Looks up the sites linking to each of `urls` in the database at `dbpath`.
+/// Also returns whether every url was a site the database could be asked about.
+fn backlinks(urls: Vec<Url>, dbpath: &Path) -> (Vec<Url>, bool) {
+    /* connecting would create an empty database */
+    if !dbpath.exists() {
+        eprintln!("neolinks: {} doesn't exist", dbpath.display());
+        process::exit(1);
+    }
+    let db = match Database::connect(dbpath) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("neolinks: {}: {e}", dbpath.display());
+            process::exit(1);
+        }
     };
+    let sites = match db.get_site_resolver() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("neolinks: {e}");
+            process::exit(1);
+        }
+    };
+
+    let mut links = Vec::new();
+    let mut ok = true;
+    for url in urls {
+        let Some(site) = sites.resolve(&url) else {
+            eprintln!("neolinks: {url} isn't a neocities site");
+            ok = false;
+            continue;
+        };
+        let found = SiteEntry::new(site.url(), 0).and_then(|s| db.get_site_backlinks(s));
+        match found {
+            Ok(l) => links.extend(l.iter().filter_map(|l| Url::parse(l.srcurl()).ok())),
+            Err(e) => {
+                eprintln!("neolinks: {e}");
+                ok = false;
+            }
+        }
+    }
+    (links, ok)
+}
+
+/// Returns `None` if the start page couldn't be fetched.
+fn crawl_site(url: Url, fetcher: &Fetcher) -> Option<Vec<Url>> {
+    /* parse_url() only lets through http(s) urls, which can always be a base */
+    let mut crawler = PageCrawler::new(url.clone(), fetcher.clone()).ok()?;
     crawler.crawl();
 
-    crawler.get_links()
+    let start = canonicalize(&url);
+    let mut failed = false;
+    for p in crawler.get_pages() {
+        if let Some(e) = &p.error {
+            eprintln!("neolinks: {e}");
+            failed |= p.url == start;
+        }
+    }
+    if failed {
+        return None;
+    }
+    Some(crawler.get_links())
 }
 
-fn crawl_page(site: &str) -> Vec<Url> {
-    let url = Url::parse(site).unwrap();
-    let mut page = Page::new(url.clone()).unwrap();
-    let _ = page.fetch();
-    page.get_links()
+/// Returns `None` if the page couldn't be fetched.
+fn crawl_page(url: Url, fetcher: &Fetcher) -> Option<Vec<Url>> {
+    let mut page = Page::new(url).unwrap();
+    if let Err(e) = page.fetch(fetcher) {
+        eprintln!("neolinks: {e}");
+        return None;
+    }
+    Some(page.get_links())
 }
 
 fn is_in_domain(url: &Url, domain: &str) -> bool {
@@ -62,10 +286,14 @@ fn is_in_domain(url: &Url, domain: &str) -> bool {
 }
 
 fn output(links: &mut Vec<Url>, domain: &str, is_html_only: bool) {
+    for l in links.iter_mut() {
+        *l = canonicalize(l);
+    }
     links.sort();
     links.dedup();
+    let mut stdout = io::stdout().lock();
     for l in links {
-        if !is_in_domain(&l, domain) {
+        if !is_in_domain(l, domain) {
             continue;
         }
 
@@ -73,6 +301,90 @@ fn output(links: &mut Vec<Url>, domain: &str, is_html_only: bool) {
             continue;
         }
 
-        println!("{}", l.as_str());
+        /* stop quietly when whatever reads the output goes away (e.g. `| head`) */
+        if writeln!(stdout, "{}", l.as_str()).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, parse_url, read_urls, Command, Mode, Options};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        match parse_args(&args)? {
+            Command::Run(o) => Ok(*o),
+            Command::Help => Err(String::from("help")),
+        }
+    }
+
+    #[test]
+    fn options() {
+        let o = parse(&[
+            "site",
+            "https://a.neocities.org/",
+            "--domain",
+            "neocities.org",
+            "--html",
+            "-w0.5",
+            "--rate=0",
+            "b.neocities.org",
+        ])
+        .unwrap();
+        assert_eq!(o.mode, Mode::Site);
+        assert_eq!(o.domain, "neocities.org");
+        assert!(o.is_html_only);
+        assert_eq!(o.config.politeness.hostdelay, Duration::from_millis(500));
+        assert_eq!(o.config.politeness.maxrps, None);
+        // urls can come anywhere, not just before or after the options
+        assert_eq!(o.urls, vec!["https://a.neocities.org/", "b.neocities.org"]);
+
+        let o = parse(&["backlinks", "--db", "/tmp/map.db", "dawnvoid.neocities.org"]).unwrap();
+        assert_eq!(o.mode, Mode::Backlinks);
+        assert_eq!(o.dbpath, PathBuf::from("/tmp/map.db"));
+
+        let o = parse(&["page", "-d", "example.org", "--", "-weird"]).unwrap();
+        assert_eq!(o.mode, Mode::Page);
+        assert_eq!(o.domain, "example.org");
+        assert_eq!(o.urls, vec!["-weird"]);
+    }
+
+    #[test]
+    fn help() {
+        assert_eq!(parse(&[]).unwrap_err(), "help");
+        assert_eq!(parse(&["--help"]).unwrap_err(), "help");
+        assert_eq!(parse(&["site", "-h", "x"]).unwrap_err(), "help");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&["crawl"]).is_err());
+        assert!(parse(&["page", "--nope"]).is_err());
+        assert!(parse(&["page", "--domain"]).is_err());
+        assert!(parse(&["page", "-w", "soon"]).is_err());
+        assert!(parse(&["page", "-q", "-1"]).is_err());
+        assert!(parse(&["page", "-w", "1e30"]).is_err());
+        assert!(parse(&["page", "-w", "inf"]).is_err());
+        assert!(parse(&["page", "-q", "1e-300"]).is_err());
+        assert!(parse(&["page", "-é"]).is_err());
+        assert!(parse(&["page", "-éa"]).is_err());
+        assert!(read_urls(&[String::from("http://[oops/")]).is_err());
+        assert!(read_urls(&[String::from("mailto:someone@example.org")]).is_err());
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            parse_url("dawnvoid.neocities.org/about").unwrap().as_str(),
+            "https://dawnvoid.neocities.org/about"
+        );
+        assert_eq!(
+            parse_url("http://example.org").unwrap().as_str(),
+            "http://example.org/"
+        );
     }
 }
@@ -0,0 +1,159 @@
+//! Turns the many ways of writing the same url into one, so urls can be compared.
+//!
+//! `canonicalize()` is applied to every url before it is queued, deduplicated or stored.
+
+use url::Url;
+
+/// Returns the canonical form of `url`:
+///
+/// - no fragment (`/about#top` -> `/about`)
+/// - lowercase scheme and host, no default port (`HTTP://Site.neocities.org:80/` -> `http://site.neocities.org/`)
+/// - no empty query (`/about?` -> `/about`) and no `utm_*` tracking parameters
+/// - no trailing `index.html` (`/blog/index.html` -> `/blog/`)
+///
+/// and for neocities sites, which serve `page.html` at `page` as well and are always https:
+///
+/// - no `.html` extension (`/about.html` -> `/about`)
+/// - https instead of http
+///
+/// `/about/` is left alone, since on neocities it is `about/index.html`,
+/// which is a different file than `about.html`.
+pub fn canonicalize(url: &Url) -> Url {
+    let mut url = url.clone();
+    if url.cannot_be_a_base() {
+        return url;
+    }
+
+    url.set_fragment(None);
+
+    /* `Url` already lowercases the scheme and host and drops default ports while parsing,
+    but not when a port is set afterwards */
+    if url.port().is_some() && url.port() == default_port(url.scheme()) {
+        let _ = url.set_port(None);
+    }
+
+    if let Some(query) = url.query() {
+        let kept: Vec<&str> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .filter(|p| !p.to_ascii_lowercase().starts_with("utm_"))
+            .collect();
+        if kept.is_empty() {
+            url.set_query(None);
+        } else {
+            url.set_query(Some(&kept.join("&")));
+        }
+    }
+
+    let neocities = is_neocities(&url);
+    let path = url.path().to_string();
+    if let Some(dir) = path.strip_suffix("index.html").filter(|d| d.ends_with('/')) {
+        url.set_path(dir);
+    } else if neocities {
+        if let Some(page) = path.strip_suffix(".html").filter(|p| !p.ends_with('/')) {
+            url.set_path(page);
+        }
+    }
+
+    if neocities && url.scheme() == "http" {
+        let _ = url.set_scheme("https");
+    }
+
+    url
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+fn is_neocities(url: &Url) -> bool {
+    url.domain()
+        .is_some_and(|d| d == "neocities.org" || d.ends_with(".neocities.org"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::canonicalize;
+    use url::Url;
+
+    fn canonical(url: &str) -> String {
+        canonicalize(&Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn variants_of_one_page() {
+        let variants = [
+            "https://dawnvoid.neocities.org/about",
+            "https://dawnvoid.neocities.org/about.html",
+            "https://dawnvoid.neocities.org/about#top",
+            "https://dawnvoid.neocities.org/about.html#top",
+            "HTTP://DawnVoid.Neocities.org/about",
+            "http://dawnvoid.neocities.org:80/about.html",
+            "https://dawnvoid.neocities.org:443/about",
+            "https://dawnvoid.neocities.org/about?",
+            "https://dawnvoid.neocities.org/about?utm_source=twitter&utm_medium=social",
+        ];
+        for v in variants {
+            assert_eq!(canonical(v), "https://dawnvoid.neocities.org/about", "{v}");
+        }
+        // a directory is a different file
+        assert_eq!(
+            canonical("https://dawnvoid.neocities.org/about/"),
+            "https://dawnvoid.neocities.org/about/"
+        );
+    }
+
+    #[test]
+    fn index_pages() {
+        assert_eq!(
+            canonical("https://dawnvoid.neocities.org/index.html"),
+            "https://dawnvoid.neocities.org/"
+        );
+        assert_eq!(
+            canonical("http://dawnvoid.neocities.org/blog/index.html#latest"),
+            "https://dawnvoid.neocities.org/blog/"
+        );
+        assert_eq!(
+            canonical("https://example.com/index.html"),
+            "https://example.com/"
+        );
+        // only a whole file name counts
+        assert_eq!(
+            canonical("https://dawnvoid.neocities.org/myindex.html"),
+            "https://dawnvoid.neocities.org/myindex"
+        );
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(
+            canonical("https://dawnvoid.neocities.org/search?q=cats&utm_campaign=x&page=2"),
+            "https://dawnvoid.neocities.org/search?q=cats&page=2"
+        );
+        assert_eq!(
+            canonical("https://dawnvoid.neocities.org/search?q=a%20b"),
+            "https://dawnvoid.neocities.org/search?q=a%20b"
+        );
+    }
+
+    #[test]
+    fn other_hosts() {
+        // not neocities, so no guessing about extensions or https
+        assert_eq!(
+            canonical("http://Example.com:8080/about.html#top"),
+            "http://example.com:8080/about.html"
+        );
+        assert_eq!(
+            canonical("http://localhost:1234/page.html"),
+            "http://localhost:1234/page.html"
+        );
+        assert_eq!(
+            canonical("mailto:someone@example.org"),
+            "mailto:someone@example.org"
+        );
+    }
+}
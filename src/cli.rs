@@ -0,0 +1,100 @@
+//! Command line parsing shared by the `neomap` and `neolinks` binaries.
+
+use std::time::Duration;
+
+/// An option argument (e.g. "--sites=5", "-n5" or "-n"), split into its name
+/// and the value glued onto it, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionArg<'a> {
+    pub name: &'a str,
+    inline: Option<&'a str>,
+}
+
+impl<'a> OptionArg<'a> {
+    /// Splits `arg`, which must start with '-'.
+    /// Long options take their value after "=", short ones right after the letter.
+    pub fn parse(arg: &'a str) -> OptionArg<'a> {
+        let (name, inline) = match (arg.split_once('='), arg.char_indices().nth(2)) {
+            (Some((n, v)), _) if arg.starts_with("--") => (n, Some(v)),
+            (_, Some((i, _))) if !arg.starts_with("--") => (&arg[..i], Some(&arg[i..])),
+            _ => (arg, None),
+        };
+        OptionArg { name, inline }
+    }
+
+    /// Returns the value of the option: the one glued onto it, or else the next of `args`.
+    pub fn value<'b>(&self, args: &mut impl Iterator<Item = &'b String>) -> Result<String, String> {
+        match self.inline {
+            Some(v) => Ok(v.to_string()),
+            None => args
+                .next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", self.name)),
+        }
+    }
+}
+
+/// Parses the number of seconds to wait between requests to the same host (e.g. "0.5").
+pub fn parse_wait(v: &str) -> Result<Duration, String> {
+    match v.parse::<f64>().map(Duration::try_from_secs_f64) {
+        Ok(Ok(d)) => Ok(d),
+        _ => Err(format!("invalid wait \"{v}\"")),
+    }
+}
+
+/// Parses a number of requests per second, where 0 (`None`) means no limit.
+pub fn parse_rate(v: &str) -> Result<Option<f64>, String> {
+    match v.parse::<f64>() {
+        Ok(0.0) => Ok(None),
+        /* the time between requests has to fit in a Duration */
+        Ok(r) if r.is_finite() && r > 0.0 && Duration::try_from_secs_f64(1.0 / r).is_ok() => {
+            Ok(Some(r))
+        }
+        _ => Err(format!("invalid rate \"{v}\"")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rate, parse_wait, OptionArg};
+    use std::time::Duration;
+
+    #[test]
+    fn options() {
+        let split = |arg| {
+            let o = OptionArg::parse(arg);
+            (o.name, o.inline)
+        };
+        assert_eq!(split("--sites=5"), ("--sites", Some("5")));
+        assert_eq!(split("--sites"), ("--sites", None));
+        assert_eq!(split("-n5"), ("-n", Some("5")));
+        assert_eq!(split("-n=5"), ("-n", Some("=5")));
+        assert_eq!(split("-n"), ("-n", None));
+        // a multibyte short option isn't cut in half
+        assert_eq!(split("-éa"), ("-é", Some("a")));
+
+        let args = [String::from("7"), String::from("x")];
+        let mut rest = args.iter();
+        assert_eq!(OptionArg::parse("-n5").value(&mut rest).unwrap(), "5");
+        assert_eq!(OptionArg::parse("-n").value(&mut rest).unwrap(), "7");
+        assert_eq!(rest.next().unwrap(), "x");
+        assert_eq!(
+            OptionArg::parse("--sites").value(&mut rest).unwrap_err(),
+            "--sites needs a value"
+        );
+    }
+
+    #[test]
+    fn politeness() {
+        assert_eq!(parse_wait("0.5"), Ok(Duration::from_millis(500)));
+        assert!(parse_wait("soon").is_err());
+        assert!(parse_wait("-1").is_err());
+        assert!(parse_wait("1e30").is_err());
+        assert!(parse_wait("inf").is_err());
+        assert_eq!(parse_rate("0"), Ok(None));
+        assert_eq!(parse_rate("2.5"), Ok(Some(2.5)));
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("1e-300").is_err());
+    }
+}
@@ -0,0 +1,514 @@
+//! Finding groups of sites in the site graph: communities, components and webrings.
+//!
+//! Communities are found with the Louvain method over the site graph with its links
+//! taken both ways, components are the strongly connected components of the graph as it is.
+//! Both are numbered from the largest group down, so the same graph always gets the same numbers.
+
+use crate::analysis::LinkGraph;
+use crate::database::{ClusterEntry, Database, DatabaseError, PageLinkEntry};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use url::Url;
+
+/// The fewest sites that count as a webring.
+const MIN_WEBRING_MEMBERS: usize = 3;
+
+/// Returns the strongly connected component of every node: the nodes that can all
+/// reach each other through links share one. Components are numbered from 0, largest first.
+pub fn components(graph: &LinkGraph) -> Vec<usize> {
+    let n = graph.len();
+    /* tarjan's algorithm, with an explicit stack so long chains of links can't overflow ours */
+    let mut index: Vec<Option<usize>> = vec![None; n];
+    let mut lowlink = vec![0; n];
+    let mut onstack = vec![false; n];
+    let mut stack = Vec::new();
+    let mut component = vec![0; n];
+    let mut count = 0;
+    let mut next = 0;
+    for root in 0..n {
+        if index[root].is_some() {
+            continue;
+        }
+        let mut work = vec![(root, 0)];
+        while let Some((v, i)) = work.pop() {
+            if i == 0 {
+                index[v] = Some(next);
+                lowlink[v] = next;
+                next += 1;
+                stack.push(v);
+                onstack[v] = true;
+            }
+            if let Some(&w) = graph.outlinks(v).get(i) {
+                work.push((v, i + 1));
+                match index[w] {
+                    None => work.push((w, 0)),
+                    Some(wi) if onstack[w] => lowlink[v] = lowlink[v].min(wi),
+                    Some(_) => {}
+                }
+                continue;
+            }
+            /* done with v, so pass its lowlink up to whoever visited it */
+            if let Some(&(parent, _)) = work.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[v]);
+            }
+            if Some(lowlink[v]) == index[v] {
+                while let Some(w) = stack.pop() {
+                    onstack[w] = false;
+                    component[w] = count;
+                    if w == v {
+                        break;
+                    }
+                }
+                count += 1;
+            }
+        }
+    }
+    renumber(&component)
+}
+
+/// Returns the community of every node, found with the Louvain method: nodes are moved
+/// between communities for as long as that raises the modularity, then every community
+/// becomes a single node and it starts over, until nothing moves anymore.
+///
+/// Links count both ways, a pair of sites linking to each other twice as much as a one-way link.
+/// Communities are numbered from 0, largest first.
+pub fn communities(graph: &LinkGraph) -> Vec<usize> {
+    let n = graph.len();
+    let mut adjacency: Vec<Vec<(usize, f64)>> = vec![Vec::new(); n];
+    for v in 0..n {
+        for &w in graph.outlinks(v) {
+            adjacency[v].push((w, 1.0));
+            adjacency[w].push((v, 1.0));
+        }
+    }
+    let mut community: Vec<usize> = (0..n).collect();
+    loop {
+        let (moved, level) = move_nodes(&adjacency);
+        if !moved {
+            break;
+        }
+        /* every node of the original graph follows its community into the next level */
+        let level = renumber(&level);
+        for c in community.iter_mut() {
+            *c = level[*c];
+        }
+        adjacency = aggregate(&adjacency, &level);
+    }
+    renumber(&community)
+}
+
+/// One level of the Louvain method: moves single nodes of the (undirected, weighted)
+/// graph to the community of a neighbour while that gains modularity.
+/// Returns whether anything moved, and the community of every node.
+fn move_nodes(adjacency: &[Vec<(usize, f64)>]) -> (bool, Vec<usize>) {
+    let n = adjacency.len();
+    let degree: Vec<f64> = adjacency
+        .iter()
+        .map(|edges| edges.iter().map(|&(_, w)| w).sum())
+        .collect();
+    let total: f64 = degree.iter().sum();
+    let mut community: Vec<usize> = (0..n).collect();
+    if total == 0.0 {
+        return (false, community);
+    }
+    let mut totals = degree.clone(); // sum of the degrees in each community
+    let mut weights = vec![0.0; n]; // from the current node to each community
+    let mut touched = Vec::new();
+    let mut moved = false;
+    loop {
+        let mut improved = false;
+        for v in 0..n {
+            let own = community[v];
+            for &(w, weight) in &adjacency[v] {
+                if w == v {
+                    continue;
+                }
+                let c = community[w];
+                if weights[c] == 0.0 {
+                    touched.push(c);
+                }
+                weights[c] += weight;
+            }
+            totals[own] -= degree[v];
+            /* the gain of putting v in c, up to a factor that is the same for every c */
+            let gain = |c: usize| weights[c] - totals[c] * degree[v] / total;
+            let mut best = own;
+            let mut bestgain = gain(own);
+            for &c in &touched {
+                let g = gain(c);
+                if g > bestgain + 1e-12 {
+                    best = c;
+                    bestgain = g;
+                }
+            }
+            totals[best] += degree[v];
+            if best != own {
+                community[v] = best;
+                improved = true;
+                moved = true;
+            }
+            for c in touched.drain(..) {
+                weights[c] = 0.0;
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    (moved, community)
+}
+
+/// Merges the nodes of every community into one node, with the links inside
+/// a community as links of that node to itself.
+fn aggregate(adjacency: &[Vec<(usize, f64)>], community: &[usize]) -> Vec<Vec<(usize, f64)>> {
+    let count = community.iter().max().map_or(0, |&c| c + 1);
+    let mut merged: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); count];
+    for (v, edges) in adjacency.iter().enumerate() {
+        for &(w, weight) in edges {
+            *merged[community[v]].entry(community[w]).or_default() += weight;
+        }
+    }
+    merged
+        .into_iter()
+        .map(|edges| edges.into_iter().collect())
+        .collect()
+}
+
+/// Numbers the groups in `labels` from 0, largest first and then by their first node.
+fn renumber(labels: &[usize]) -> Vec<usize> {
+    let count = labels.iter().max().map_or(0, |&l| l + 1);
+    let mut sizes = vec![0; count];
+    let mut first = vec![usize::MAX; count];
+    for (v, &l) in labels.iter().enumerate() {
+        sizes[l] += 1;
+        first[l] = first[l].min(v);
+    }
+    let mut order: Vec<usize> = (0..count).filter(|&l| sizes[l] > 0).collect();
+    order.sort_by_key(|&l| (std::cmp::Reverse(sizes[l]), first[l]));
+    let mut number = vec![0; count];
+    for (i, &l) in order.iter().enumerate() {
+        number[l] = i;
+    }
+    labels.iter().map(|&l| number[l]).collect()
+}
+
+/// Finds the community and component of every site in `graph`.
+pub fn find_clusters(graph: &LinkGraph) -> Result<Vec<ClusterEntry>, DatabaseError> {
+    let communities = communities(graph);
+    let components = components(graph);
+    let mut clusters = Vec::with_capacity(graph.len());
+    for i in 0..graph.len() {
+        let url = Url::parse(graph.url(i))
+            .map_err(|_| DatabaseError::InvalidUrl(graph.url(i).to_string()))?;
+        clusters.push(ClusterEntry::new(
+            url,
+            communities[i] as i64,
+            components[i] as i64,
+        )?);
+    }
+    Ok(clusters)
+}
+
+/// Replaces the clusters in the database with `clusters`.
+/// Clusters of sites that aren't in the database are left out.
+pub fn save_clusters(db: &Database, clusters: Vec<ClusterEntry>) -> Result<(), DatabaseError> {
+    let sites: HashSet<String> = db
+        .get_sites()?
+        .iter()
+        .map(|s| s.url().to_string())
+        .collect();
+    db.in_transaction(|db| {
+        db.delete_clusters()?;
+        for c in clusters {
+            if sites.contains(c.siteurl()) {
+                db.set_cluster(c)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Sites that are linked together by "next" and "previous" links.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webring {
+    pub hub: Option<String>, // the site every member's links go through, if there is one
+    pub members: Vec<String>, // sorted
+}
+
+/// Which way a navigation link goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Next,
+    Prev,
+}
+
+/// Finds webrings in the links stored in `db`, with redirect-only sites collapsed
+/// (see `Database::get_site_graph()`).
+///
+/// A webring either has a hub, a site that at least `MIN_WEBRING_MEMBERS` sites have both
+/// a "next" and a "previous" link to (e.g. https://hub.neocities.org/next?from=member),
+/// or it is a cycle of that many sites linking straight to each other.
+/// Hubs don't have to be on neocities. Webrings are ordered by hub and then by members.
+pub fn find_webrings(db: &Database) -> Result<Vec<Webring>, DatabaseError> {
+    let sites = db.get_site_resolver()?;
+    let redirects = db.get_site_redirects()?;
+    let collapse = |url: String| redirects.get(&url).cloned().unwrap_or(url);
+
+    /* which ways each site links to each other site */
+    let mut navigation: BTreeMap<(String, String), (bool, bool)> = BTreeMap::new();
+    for (siteurl, link) in db.get_navigation_links()? {
+        let Some(direction) = direction(&link) else {
+            continue;
+        };
+        let Ok(dsturl) = Url::parse(link.dsturl()) else {
+            continue;
+        };
+        let dsturl = match sites.resolve(&dsturl) {
+            Some(site) => site.url().to_string(),
+            None => match dsturl.host_str() {
+                Some(host) => format!("{}://{host}/", dsturl.scheme()),
+                None => continue,
+            },
+        };
+        let (srcurl, dsturl) = (collapse(siteurl), collapse(dsturl));
+        if srcurl == dsturl {
+            continue;
+        }
+        let ways = navigation.entry((srcurl, dsturl)).or_default();
+        match direction {
+            Direction::Next => ways.0 = true,
+            Direction::Prev => ways.1 = true,
+        }
+    }
+
+    let mut hubs: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for ((srcurl, dsturl), ways) in &navigation {
+        if *ways == (true, true) {
+            hubs.entry(dsturl).or_default().push(srcurl.clone());
+        }
+    }
+    hubs.retain(|_, members| members.len() >= MIN_WEBRING_MEMBERS);
+    let mut webrings: Vec<Webring> = hubs
+        .iter()
+        .map(|(hub, members)| Webring {
+            hub: Some(hub.to_string()),
+            members: members.clone(),
+        })
+        .collect();
+
+    /* the rest point straight at the next site, so look for cycles */
+    let mut graph = LinkGraph::new();
+    for ((srcurl, dsturl), (next, prev)) in &navigation {
+        if hubs.contains_key(dsturl.as_str()) {
+            continue;
+        }
+        if *next {
+            graph.add_link(srcurl, dsturl);
+        }
+        if *prev {
+            graph.add_link(dsturl, srcurl);
+        }
+    }
+    let mut rings: BTreeMap<usize, BTreeSet<String>> = BTreeMap::new();
+    for (node, component) in components(&graph).into_iter().enumerate() {
+        rings
+            .entry(component)
+            .or_default()
+            .insert(graph.url(node).to_string());
+    }
+    let mut cycles: Vec<Webring> = rings
+        .into_values()
+        .filter(|members| members.len() >= MIN_WEBRING_MEMBERS)
+        .map(|members| Webring {
+            hub: None,
+            members: members.into_iter().collect(),
+        })
+        .collect();
+    cycles.sort_by(|a, b| a.members.cmp(&b.members));
+    webrings.extend(cycles);
+    Ok(webrings)
+}
+
+/// Tells whether `link` is a "next" or a "previous" link, going by its rel,
+/// then its text (words or arrows) and then the words in its url.
+fn direction(link: &PageLinkEntry) -> Option<Direction> {
+    let rel = link.rel();
+    if rel.contains(&"next") {
+        return Some(Direction::Next);
+    }
+    if rel.contains(&"prev") || rel.contains(&"previous") {
+        return Some(Direction::Prev);
+    }
+
+    let text = link.text().unwrap_or("").to_lowercase();
+    if let Some(d) = direction_of_words(&text) {
+        return Some(d);
+    }
+    /* arrows only count on their own, "<3" or "a > b" says nothing about direction */
+    if !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_whitespace() || "<>«»←→".contains(c))
+    {
+        let next = text.contains(['>', '»', '→']);
+        let prev = text.contains(['<', '«', '←']);
+        match (next, prev) {
+            (true, false) => return Some(Direction::Next),
+            (false, true) => return Some(Direction::Prev),
+            _ => {}
+        }
+    }
+
+    let url = Url::parse(link.dsturl()).ok()?;
+    let path = url.path().to_lowercase();
+    let query = url.query().unwrap_or("").to_lowercase();
+    direction_of_words(&path).or_else(|| direction_of_words(&query))
+}
+
+/// Looks for the words "next", "prev" or "previous" in `s`, whichever comes first.
+fn direction_of_words(s: &str) -> Option<Direction> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .find_map(|word| match word {
+            "next" => Some(Direction::Next),
+            "prev" | "previous" => Some(Direction::Prev),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{communities, components, find_clusters, find_webrings, save_clusters, Webring};
+    use crate::analysis::LinkGraph;
+    use crate::database::{Database, PageEntry, PageLinkEntry, SiteEntry};
+    use crate::testgraph::graph;
+    use url::Url;
+
+    fn url(name: &str, path: &str) -> Url {
+        Url::parse(&format!("https://{name}.neocities.org{path}")).unwrap()
+    }
+
+    #[test]
+    fn strongly_connected() {
+        // a -> b -> c -> a is one component, d and e hang off it
+        let g = graph(&[("a", "b"), ("b", "c"), ("c", "a"), ("c", "d"), ("e", "a")]);
+        assert_eq!(components(&g), vec![0, 0, 0, 1, 2]);
+        assert!(components(&LinkGraph::new()).is_empty());
+
+        // a long chain can't overflow the stack
+        let mut g = LinkGraph::new();
+        for i in 0..100_000 {
+            g.add_link(&format!("{i:06}"), &format!("{:06}", i + 1));
+        }
+        g.add_link("100000", "000000");
+        assert!(components(&g).iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn louvain() {
+        // two triangles, joined by a single link from c to d
+        let g = graph(&[
+            ("a", "b"),
+            ("b", "c"),
+            ("c", "a"),
+            ("c", "d"),
+            ("d", "e"),
+            ("e", "f"),
+            ("f", "d"),
+        ]);
+        assert_eq!(communities(&g), vec![0, 0, 0, 1, 1, 1]);
+
+        // sites without links stay on their own
+        let mut g = graph(&[("a", "b")]);
+        g.add_site("c");
+        assert_eq!(communities(&g), vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn clusters() {
+        let db = Database::connect_virtual().unwrap();
+        let mut g = graph(&[
+            ("https://alice.neocities.org/", "https://bob.neocities.org/"),
+            ("https://bob.neocities.org/", "https://alice.neocities.org/"),
+        ]);
+        g.add_link(
+            "https://carol.neocities.org/",
+            "https://alice.neocities.org/",
+        );
+        for name in ["alice", "bob"] {
+            db.add_site(SiteEntry::new(url(name, "/"), 0).unwrap())
+                .unwrap();
+        }
+
+        let clusters = find_clusters(&g).unwrap();
+        save_clusters(&db, clusters.clone()).unwrap();
+        save_clusters(&db, clusters).unwrap();
+        // carol isn't in the database
+        let stored = db.get_clusters().unwrap();
+        let stored: Vec<(&str, i64, i64)> = stored
+            .iter()
+            .map(|c| (c.siteurl(), c.community(), c.component()))
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                ("https://alice.neocities.org/", 0, 0),
+                ("https://bob.neocities.org/", 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn webrings() {
+        let db = Database::connect_virtual().unwrap();
+        let mut links: Vec<(&str, &str, Option<&str>, &str)> = Vec::new();
+        // alice, bob and carol are in a webring run from ring.example.com
+        for name in ["alice", "bob", "carol"] {
+            links.push((name, "https://ring.example.com/prev?from=x", Some("«"), ""));
+            links.push((name, "https://ring.example.com/next?from=x", Some("»"), ""));
+            links.push((name, "https://ring.example.com/", Some("the ring"), ""));
+        }
+        // dave, erin and frank link straight to the next and previous site
+        links.push(("dave", "https://erin.neocities.org/", Some("next site"), ""));
+        links.push(("erin", "https://frank.neocities.org/", None, "next"));
+        links.push(("dave", "https://frank.neocities.org/", Some("previous"), ""));
+        // a comic's pages and a pair of friends aren't webrings
+        links.push(("gina", "https://gina.neocities.org/2", Some("next"), ""));
+        links.push(("gina", "https://henry.neocities.org/", Some("next"), ""));
+        links.push(("henry", "https://gina.neocities.org/", Some("next"), ""));
+        links.push(("henry", "https://ring.example.com/next", Some("<3"), ""));
+
+        for (name, dsturl, text, rel) in links {
+            let page = url(name, "/");
+            db.add_site(SiteEntry::new(page.clone(), 0).unwrap())
+                .unwrap();
+            db.set_page(
+                PageEntry::new(page.clone(), page.clone(), Some(200), None, 0, 0, None).unwrap(),
+            )
+            .unwrap();
+            let rel: Vec<String> = rel.split_whitespace().map(str::to_string).collect();
+            db.set_page_link(
+                PageLinkEntry::new(page, Url::parse(dsturl).unwrap())
+                    .unwrap()
+                    .with_text(text.map(str::to_string))
+                    .with_rel(&rel),
+            )
+            .unwrap();
+        }
+
+        let members = |names: &[&str]| -> Vec<String> {
+            names.iter().map(|n| url(n, "/").to_string()).collect()
+        };
+        assert_eq!(
+            find_webrings(&db).unwrap(),
+            vec![
+                Webring {
+                    hub: Some("https://ring.example.com/".to_string()),
+                    members: members(&["alice", "bob", "carol"]),
+                },
+                Webring {
+                    hub: None,
+                    members: members(&["dave", "erin", "frank"]),
+                },
+            ]
+        );
+    }
+}
@@ -1,7 +1,102 @@
-use rusqlite::{self, Connection, OptionalExtension, Statement};
+use crate::canonical::canonicalize;
+use crate::migration;
+use crate::page::{Link, LinkKind};
+use crate::site::{self, SiteId, SiteResolver};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{self, Connection, ErrorCode, OptionalExtension, Row, ToSql};
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
+use std::time::Duration;
 use url::Url;
 
+pub use crate::migration::SCHEMA_VERSION;
+
+/// How long a statement waits for a locked database before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything that can go wrong when talking to a `Database`.
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// Any sqlite failure that doesn't have a more specific variant.
+    Sqlite(rusqlite::Error),
+    /// A url that can't be stored (e.g. it has no domain).
+    InvalidUrl(String),
+    /// A constraint such as a foreign key or primary key was violated.
+    ConstraintViolation(String),
+    /// A statement changed a different number of rows than it should have.
+    UnexpectedRowCount { expected: usize, actual: usize },
+    /// The database was written by a newer version of neomap.
+    UnsupportedSchemaVersion { found: u32, supported: u32 },
+    /// A schema upgrade failed and was rolled back.
+    MigrationFailed {
+        version: u32,
+        description: &'static str,
+        source: rusqlite::Error,
+    },
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Sqlite(e) => write!(f, "sqlite error: {e}"),
+            DatabaseError::InvalidUrl(u) => write!(f, r#"invalid url "{u}""#),
+            DatabaseError::ConstraintViolation(m) => write!(f, "constraint violation: {m}"),
+            DatabaseError::UnexpectedRowCount { expected, actual } => write!(
+                f,
+                "statement should change exactly {expected} row(s), but {actual} were changed"
+            ),
+            DatabaseError::UnsupportedSchemaVersion { found, supported } => write!(
+                f,
+                "database has schema version {found}, but only versions up to {supported} are supported"
+            ),
+            DatabaseError::MigrationFailed {
+                version,
+                description,
+                source,
+            } => write!(
+                f,
+                r#"migration to schema version {version} ("{description}") failed: {source}"#
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DatabaseError::Sqlite(e) => Some(e),
+            DatabaseError::MigrationFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::SqliteFailure(f, m) if f.code == ErrorCode::ConstraintViolation => {
+                DatabaseError::ConstraintViolation(m.unwrap_or_else(|| f.to_string()))
+            }
+            e => DatabaseError::Sqlite(e),
+        }
+    }
+}
+
+impl ToSql for LinkKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for LinkKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let s = value.as_str()?;
+        LinkKind::parse(s)
+            .ok_or_else(|| FromSqlError::Other(format!(r#"unknown link kind "{s}""#).into()))
+    }
+}
+
 #[derive(Debug)]
 pub struct Database {
     connection: Connection,
@@ -11,166 +106,1030 @@ impl Database {
     /// Connects to a sqlite database file.
     /// If the file doesn't exist, it will be created.
     /// If the tables don't exist, they will be created.
-    /// However, if the tables do exist, but have the wrong configuration,
-    /// they won't be fixed.
-    pub fn connect(path: &Path) -> Result<Database, String> {
-        // let con = Connection::open_in_memory()?;
-        let con = Connection::open(path).map_err(|e| e.to_string())?;
-        let d = Database { connection: con };
-
-        // create tables if needed
-        d.try_create_tables()?;
-
-        Ok(d)
+    /// Databases with an older schema are upgraded to `SCHEMA_VERSION`,
+    /// databases with a newer schema are refused.
+    pub fn connect(path: &Path) -> Result<Database, DatabaseError> {
+        let con = Connection::open(path)?;
+        Database::setup(con)
     }
 
     /// Exactly the same as `Database::connect()`,
     /// but a new in-memory database is created.
     ///
     /// Intended for testing.
-    pub fn connect_virtual() -> Result<Database, String> {
-        let con = Connection::open_in_memory().map_err(|e| e.to_string())?;
-        let d = Database { connection: con };
+    pub fn connect_virtual() -> Result<Database, DatabaseError> {
+        let con = Connection::open_in_memory()?;
+        Database::setup(con)
+    }
+
+    /// Configures a freshly opened connection and brings its schema up to date.
+    ///
+    /// Every connection gets the same settings no matter how sqlite was built:
+    /// - foreign keys are enforced, so deleting a site cascades to its links
+    /// - the journal is in WAL mode (in-memory databases ignore this)
+    /// - a busy connection is retried for `BUSY_TIMEOUT` instead of failing immediately
+    fn setup(mut con: Connection) -> Result<Database, DatabaseError> {
+        con.busy_timeout(BUSY_TIMEOUT)?;
+        con.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
 
-        // create tables if needed
-        d.try_create_tables()?;
+        // migrations may rebuild tables, which must not trigger cascades
+        con.pragma_update(None, "foreign_keys", false)?;
+        migration::migrate(&mut con)?;
+        con.pragma_update(None, "foreign_keys", true)?;
 
-        Ok(d)
+        Ok(Database { connection: con })
     }
 
-    pub fn disconnect(mut self) -> Result<(), (Database, String)> {
+    #[allow(clippy::result_large_err)] // hands the connection back so the caller can retry
+    pub fn disconnect(mut self) -> Result<(), (Database, DatabaseError)> {
         match self.connection.close() {
             Ok(_) => Ok(()),
             Err((c, e)) => {
                 self.connection = c;
-                Err((self, e.to_string()))
+                Err((self, e.into()))
             }
         }
     }
 
-    /// Attempts to create the sqlite tables.
-    pub fn try_create_tables(&self) -> Result<(), String> {
-        // create site table if needed
-        self.connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS site (
-                url TEXT NOT NULL PRIMARY KEY,
-                crawltime INTEGER NOT NULL
-            )",
-                (),
-            )
-            .map_err(|e| e.to_string())?;
-
-        // create link table if needed
-        self.connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS link (
-                srcurl TEXT NOT NULL,
-                dsturl TEXT NOT NULL,
-                PRIMARY KEY (srcurl, dsturl),
-                FOREIGN KEY (srcurl) REFERENCES site (url)
-                    ON UPDATE CASCADE
-                    ON DELETE CASCADE
-            )",
-                (),
-            )
-            .map_err(|e| e.to_string())?;
-        Ok(())
+    /// Returns the schema version of the connected database.
+    pub fn schema_version(&self) -> Result<u32, DatabaseError> {
+        migration::get_version(&self.connection)
     }
 
     /// Updates a site entry, or creates one if no site with the url exists.
-    pub fn set_site(&self, site: SiteEntry) -> Result<(), String> {
-        // site.insert_new(&self.connection)?;
-        self.connection
-            .execute(
-                "INSERT INTO site (url, crawltime) VALUES (?1, ?2)
+    pub fn set_site(&self, site: SiteEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO site (url, crawltime) VALUES (?1, ?2)
             ON CONFLICT(url) DO UPDATE SET crawltime = excluded.crawltime",
-                (site.url, site.crawltime),
-            )
-            .map_err(|e| e.to_string())?;
+            (site.url, site.crawltime),
+        )?;
         Ok(())
     }
 
-    /// Updates a link entry, or creates one if no link with the srcurl and dsturl exists.
-    pub fn set_link(&self, link: LinkEntry) -> Result<(), String> {
+    /// Creates a site entry, but only if no site with the url exists.
+    /// Unlike `set_site()`, an existing site keeps its crawltime.
+    pub fn add_site(&self, site: SiteEntry) -> Result<(), DatabaseError> {
         self.connection.execute(
-            "INSERT INTO link (srcurl, dsturl) VALUES (?1, ?2)
-            ON CONFLICT(srcurl, dsturl) DO UPDATE SET srcurl = excluded.srcurl, dsturl = excluded.dsturl",
-            (link.srcurl, link.dsturl),
-        )
-        .map_err(|e| e.to_string())?;
+            "INSERT INTO site (url, crawltime) VALUES (?1, ?2)
+            ON CONFLICT(url) DO NOTHING",
+            (site.url, site.crawltime),
+        )?;
         Ok(())
     }
 
-    pub fn delete_links_by_srcurl(&self, link: LinkEntry) -> Result<(), String> {
+    /// Creates a link entry if no link with the srcurl, dsturl and kind exists.
+    pub fn set_link(&self, link: LinkEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO link (srcurl, dsturl, kind) VALUES (?1, ?2, ?3)
+            ON CONFLICT(srcurl, dsturl, kind) DO NOTHING",
+            (link.srcurl, link.dsturl, link.kind),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_links_by_srcurl(&self, link: LinkEntry) -> Result<(), DatabaseError> {
         self.connection
-            .execute("DELETE FROM link WHERE srcurl = ?1", (link.srcurl,))
-            .map_err(|e| e.to_string())?;
+            .execute("DELETE FROM link WHERE srcurl = ?1", (link.srcurl,))?;
         Ok(())
     }
 
-    pub fn delete_site_by_url(&self, site: SiteEntry) -> Result<(), String> {
+    pub fn delete_site_by_url(&self, site: SiteEntry) -> Result<(), DatabaseError> {
         self.connection
-            .execute("DELETE FROM site WHERE url = ?1", (site.url,))
-            .map_err(|e| e.to_string())?;
+            .execute("DELETE FROM site WHERE url = ?1", (site.url,))?;
         Ok(())
     }
 
-    pub fn get_site_with_oldest_crawltime(&self) -> Result<Option<SiteEntry>, String> {
-        // see https://www.db-fiddle.com/f/kUoFMMUfYyNnrpnyWWvUXG/1
+    /// Maps a custom domain to a site, replacing whatever site it was mapped to before.
+    pub fn set_site_domain(&self, sitedomain: SiteDomainEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO sitedomain (domain, siteurl) VALUES (?1, ?2)
+            ON CONFLICT(domain) DO UPDATE SET siteurl = excluded.siteurl",
+            (sitedomain.domain, sitedomain.siteurl),
+        )?;
+        Ok(())
+    }
+
+    pub fn get_site_domains(&self) -> Result<Vec<SiteDomainEntry>, DatabaseError> {
         let mut statement = self
             .connection
-            .prepare("SELECT url, MIN(crawltime) FROM site")
-            .unwrap();
-        let result = statement
-            .query_row((), |row| {
-                Ok(SiteEntry {
-                    url: row.get(0).unwrap(),
-                    crawltime: row.get(1).unwrap(),
-                })
+            .prepare("SELECT domain, siteurl FROM sitedomain ORDER BY domain")?;
+        let result = statement.query_map((), SiteDomainEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<SiteDomainEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns a `SiteResolver` that knows every custom domain in the database.
+    pub fn get_site_resolver(&self) -> Result<SiteResolver, DatabaseError> {
+        let mut resolver = SiteResolver::new();
+        for d in self.get_site_domains()? {
+            let site = Url::parse(&d.siteurl)
+                .ok()
+                .and_then(|u| SiteId::from_url(&u))
+                .ok_or_else(|| DatabaseError::InvalidUrl(d.siteurl.clone()))?;
+            resolver.add_domain(&d.domain, site);
+        }
+        Ok(resolver)
+    }
+
+    pub fn get_site_with_oldest_crawltime(&self) -> Result<Option<SiteEntry>, DatabaseError> {
+        Ok(self.get_sites_with_oldest_crawltime(1)?.into_iter().next())
+    }
+
+    /// Returns up to `limit` sites, least recently crawled first.
+    pub fn get_sites_with_oldest_crawltime(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<SiteEntry>, DatabaseError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT url, crawltime FROM site ORDER BY crawltime ASC, url ASC LIMIT ?1")?;
+        let result = statement.query_map((limit as i64,), |row| {
+            Ok(SiteEntry {
+                url: row.get(0)?,
+                crawltime: row.get(1)?,
             })
-            .optional()
-            .map_err(|e| e.to_string())?;
+        })?;
+        let resultlist = result.collect::<Result<Vec<SiteEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Sets when a site is due to be crawled again, replacing its previous schedule.
+    pub fn set_schedule(&self, schedule: ScheduleEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO siteschedule (siteurl, nextcrawl, interval, crawls, changes)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(siteurl) DO UPDATE SET
+                nextcrawl = excluded.nextcrawl,
+                interval = excluded.interval,
+                crawls = excluded.crawls,
+                changes = excluded.changes",
+            (
+                schedule.siteurl,
+                schedule.nextcrawl,
+                schedule.interval,
+                schedule.crawls,
+                schedule.changes,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the schedule of `site`, or `None` if it was never scheduled.
+    pub fn get_schedule_by_siteurl(
+        &self,
+        site: SiteEntry,
+    ) -> Result<Option<ScheduleEntry>, DatabaseError> {
+        let result = self
+            .connection
+            .query_row(
+                "SELECT siteurl, nextcrawl, interval, crawls, changes
+                FROM siteschedule WHERE siteurl = ?1",
+                (site.url,),
+                ScheduleEntry::from_row,
+            )
+            .optional()?;
         Ok(result)
     }
 
-    pub fn update_site_crawltime(&self, site: SiteEntry) -> Result<(), String> {
+    /// Returns up to `limit` sites that are due to be crawled at `now`, most overdue first.
+    ///
+    /// Sites that were never scheduled come before everything else,
+    /// with a `nextcrawl` of 0.
+    pub fn get_due_schedule(
+        &self,
+        now: i64,
+        limit: usize,
+    ) -> Result<Vec<ScheduleEntry>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT site.url,
+                COALESCE(siteschedule.nextcrawl, 0),
+                COALESCE(siteschedule.interval, 0),
+                COALESCE(siteschedule.crawls, 0),
+                COALESCE(siteschedule.changes, 0)
+            FROM site
+            LEFT JOIN siteschedule ON siteschedule.siteurl = site.url
+            WHERE COALESCE(siteschedule.nextcrawl, 0) <= ?1
+            ORDER BY COALESCE(siteschedule.nextcrawl, 0) ASC, site.crawltime ASC, site.url ASC
+            LIMIT ?2",
+        )?;
+        let result = statement.query_map((now, limit as i64), ScheduleEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<ScheduleEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns the next `limit` sites to be crawled, due or not, in the order they are due.
+    pub fn get_schedule(&self, limit: usize) -> Result<Vec<ScheduleEntry>, DatabaseError> {
+        self.get_due_schedule(i64::MAX, limit)
+    }
+
+    pub fn update_site_crawltime(&self, site: SiteEntry) -> Result<(), DatabaseError> {
         // see https://www.db-fiddle.com/f/kUoFMMUfYyNnrpnyWWvUXG/2
         let mut statement = self
             .connection
-            .prepare("UPDATE site SET crawltime = ?2 WHERE url = ?1")
-            .unwrap();
-        let result = statement
-            .execute((site.url, site.crawltime))
-            .map_err(|e| e.to_string())?;
+            .prepare("UPDATE site SET crawltime = ?2 WHERE url = ?1")?;
+        let result = statement.execute((site.url, site.crawltime))?;
         if result != 1 {
-            return Err(format!(
-                "update_site_crawltime() should change exactly 1 row, but {result} were changed"
-            ));
+            return Err(DatabaseError::UnexpectedRowCount {
+                expected: 1,
+                actual: result,
+            });
         }
         Ok(())
     }
 
-    pub fn get_links_by_srcurl(&self, link: LinkEntry) -> Result<Vec<LinkEntry>, String> {
+    /// Like `get_links_by_srcurl()`, but only returns links of the same kind as `link`
+    /// (e.g. only hyperlinks, ignoring hotlinked images).
+    pub fn get_links_by_srcurl_and_kind(
+        &self,
+        link: LinkEntry,
+    ) -> Result<Vec<LinkEntry>, DatabaseError> {
         let mut statement = self
             .connection
-            .prepare("SELECT srcurl, dsturl FROM link WHERE srcurl = ?1")
-            .unwrap();
-        let result = statement
-            .query_map((link.srcurl,), |row| {
-                Ok(LinkEntry {
-                    srcurl: row.get(0).unwrap(),
-                    dsturl: row.get(1).unwrap(),
-                })
+            .prepare("SELECT srcurl, dsturl, kind FROM link WHERE srcurl = ?1 AND kind = ?2")?;
+        let result = statement.query_map((link.srcurl, link.kind), LinkEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<LinkEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    pub fn get_links_by_srcurl(&self, link: LinkEntry) -> Result<Vec<LinkEntry>, DatabaseError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT srcurl, dsturl, kind FROM link WHERE srcurl = ?1")?;
+        let result = statement.query_map((link.srcurl,), LinkEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<LinkEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns the links to the site in `link.dsturl`, i.e. which sites link to it.
+    pub fn get_links_by_dsturl(&self, link: LinkEntry) -> Result<Vec<LinkEntry>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT srcurl, dsturl, kind FROM link WHERE dsturl = ?1 ORDER BY srcurl, kind",
+        )?;
+        let result = statement.query_map((link.dsturl,), LinkEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<LinkEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Like `get_links_by_dsturl()`, but links to sites that moved to `site`
+    /// (see `get_site_redirects()`) count as links to it. Ordered by srcurl.
+    pub fn get_site_backlinks(&self, site: SiteEntry) -> Result<Vec<LinkEntry>, DatabaseError> {
+        let mut targets = vec![site.url];
+        for (from, to) in self.get_site_redirects()? {
+            if to == targets[0] {
+                targets.push(from);
+            }
+        }
+
+        let mut resultlist = Vec::new();
+        for t in &targets {
+            let mut statement = self.connection.prepare_cached(
+                "SELECT srcurl, dsturl, kind FROM link WHERE dsturl = ?1 ORDER BY srcurl, kind",
+            )?;
+            let result = statement.query_map((t,), LinkEntry::from_row)?;
+            for link in result {
+                let mut link = link?;
+                /* a moved site's link to where it moved isn't a backlink */
+                if targets.contains(&link.srcurl) {
+                    continue;
+                }
+                link.dsturl = targets[0].clone();
+                resultlist.push(link);
+            }
+        }
+        resultlist.sort_by(|a: &LinkEntry, b: &LinkEntry| {
+            (&a.srcurl, a.kind.as_str()).cmp(&(&b.srcurl, b.kind.as_str()))
+        });
+        resultlist.dedup();
+        Ok(resultlist)
+    }
+
+    /// Returns the number of sites that link to `site` (with links of any kind).
+    pub fn get_inbound_link_count(&self, site: SiteEntry) -> Result<u64, DatabaseError> {
+        let count: i64 = self.connection.query_row(
+            "SELECT COUNT(DISTINCT srcurl) FROM link WHERE dsturl = ?1",
+            (site.url,),
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    /// Returns up to `limit` sites with the number of sites that link to each,
+    /// most linked first. Sites nothing links to are left out.
+    pub fn get_inbound_link_counts(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT dsturl, COUNT(DISTINCT srcurl) AS inbound FROM link
+            GROUP BY dsturl
+            ORDER BY inbound DESC, dsturl ASC
+            LIMIT ?1",
+        )?;
+        let result = statement.query_map((limit as i64,), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+        let resultlist = result.collect::<Result<Vec<(String, u64)>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns the url of every other site that `site` links to (with links of any kind),
+    /// ordered by url.
+    pub fn get_linked_sites(&self, site: SiteEntry) -> Result<Vec<String>, DatabaseError> {
+        /* this runs for every site a path search reaches, so it is cached */
+        let mut statement = self.connection.prepare_cached(
+            "SELECT DISTINCT dsturl FROM link WHERE srcurl = ?1 AND dsturl != ?1 ORDER BY dsturl",
+        )?;
+        let result = statement.query_map((site.url,), |row| row.get::<_, String>(0))?;
+        let resultlist = result.collect::<Result<Vec<String>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns the url of every other site that links to `site` (with links of any kind),
+    /// ordered by url.
+    pub fn get_linking_sites(&self, site: SiteEntry) -> Result<Vec<String>, DatabaseError> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT DISTINCT srcurl FROM link WHERE dsturl = ?1 AND srcurl != ?1 ORDER BY srcurl",
+        )?;
+        let result = statement.query_map((site.url,), |row| row.get::<_, String>(0))?;
+        let resultlist = result.collect::<Result<Vec<String>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Updates the scores of a site, or creates them if the site has none yet.
+    /// The site must already exist.
+    pub fn set_score(&self, score: ScoreEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO sitescore
+                (siteurl, pagerank, hub, authority, indegree, outdegree, betweenness)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT(siteurl) DO UPDATE SET
+                pagerank = excluded.pagerank,
+                hub = excluded.hub,
+                authority = excluded.authority,
+                indegree = excluded.indegree,
+                outdegree = excluded.outdegree,
+                betweenness = excluded.betweenness",
+            (
+                score.siteurl,
+                score.pagerank,
+                score.hub,
+                score.authority,
+                score.indegree,
+                score.outdegree,
+                score.betweenness,
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_scores(&self) -> Result<(), DatabaseError> {
+        self.connection.execute("DELETE FROM sitescore", ())?;
+        Ok(())
+    }
+
+    /// Returns the scores of the `limit` sites with the highest `metric`.
+    pub fn get_scores_by(
+        &self,
+        column: ScoreColumn,
+        limit: usize,
+    ) -> Result<Vec<ScoreEntry>, DatabaseError> {
+        /* `column` is only ever one of ours, so it can go into the query as is */
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT siteurl, pagerank, hub, authority, indegree, outdegree, betweenness
+            FROM sitescore ORDER BY {} DESC, siteurl ASC LIMIT ?1",
+            column.name()
+        ))?;
+        let result = statement.query_map((limit as i64,), ScoreEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<ScoreEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Updates the community and component of a site, or sets them if the site has none yet.
+    /// The site must already exist.
+    pub fn set_cluster(&self, cluster: ClusterEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO sitecluster (siteurl, community, component) VALUES (?1, ?2, ?3)
+            ON CONFLICT(siteurl) DO UPDATE SET
+                community = excluded.community,
+                component = excluded.component",
+            (cluster.siteurl, cluster.community, cluster.component),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_clusters(&self) -> Result<(), DatabaseError> {
+        self.connection.execute("DELETE FROM sitecluster", ())?;
+        Ok(())
+    }
+
+    /// Returns the community and component of every site that has them,
+    /// ordered by community and then by url.
+    pub fn get_clusters(&self) -> Result<Vec<ClusterEntry>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT siteurl, community, component FROM sitecluster ORDER BY community, siteurl",
+        )?;
+        let result = statement.query_map((), ClusterEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<ClusterEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns the hyperlinks that might be "next" or "previous" links (e.g. of a webring),
+    /// along with the site of the page each is on, ordered by site and page.
+    ///
+    /// This only narrows the links down by their rel, text and url, so the caller
+    /// has to look closer at each of them.
+    pub fn get_navigation_links(&self) -> Result<Vec<(String, PageLinkEntry)>, DatabaseError> {
+        /* like is case insensitive for ascii, which is what we want here */
+        let mut statement = self.connection.prepare(
+            "SELECT page.siteurl,
+                pagelink.srcurl, pagelink.dsturl, pagelink.kind, pagelink.text, pagelink.rel
+            FROM pagelink
+            JOIN page ON page.url = pagelink.srcurl
+            WHERE pagelink.kind = ?1 AND (
+                pagelink.rel LIKE '%next%' OR pagelink.rel LIKE '%prev%'
+                OR pagelink.dsturl LIKE '%next%' OR pagelink.dsturl LIKE '%prev%'
+                OR pagelink.text LIKE '%next%' OR pagelink.text LIKE '%prev%'
+                OR pagelink.text GLOB '*[<>«»←→]*'
+            )
+            ORDER BY page.siteurl, pagelink.srcurl, pagelink.dsturl",
+        )?;
+        let result = statement.query_map((LinkKind::Hyperlink,), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                PageLinkEntry {
+                    srcurl: row.get(1)?,
+                    dsturl: row.get(2)?,
+                    kind: row.get(3)?,
+                    text: row.get(4)?,
+                    rel: row.get(5)?,
+                },
+            ))
+        })?;
+        let resultlist = result.collect::<Result<Vec<(String, PageLinkEntry)>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Runs `f` inside a transaction.
+    /// If `f` returns an error, everything it did is rolled back.
+    pub fn in_transaction<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Database) -> Result<T, DatabaseError>,
+    {
+        let tx = self.connection.unchecked_transaction()?;
+        let result = f(self)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Updates a page entry, or creates one if no page with the url exists.
+    /// The page's site must already exist.
+    pub fn set_page(&self, page: PageEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO page
+                (url, siteurl, status, contenttype, size, fetchtime, hash, blocked, etag, lastmodified)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            ON CONFLICT(url) DO UPDATE SET
+                siteurl = excluded.siteurl,
+                status = excluded.status,
+                contenttype = excluded.contenttype,
+                size = excluded.size,
+                fetchtime = excluded.fetchtime,
+                hash = excluded.hash,
+                blocked = excluded.blocked,
+                etag = excluded.etag,
+                lastmodified = excluded.lastmodified",
+            (
+                page.url,
+                page.siteurl,
+                page.status,
+                page.contenttype,
+                page.size,
+                page.fetchtime,
+                page.hash,
+                page.blocked,
+                page.etag,
+                page.lastmodified,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Creates a page link entry if it doesn't exist yet.
+    /// The source page must already exist.
+    pub fn set_page_link(&self, link: PageLinkEntry) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO pagelink (srcurl, dsturl, kind, text, rel) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(srcurl, dsturl, kind) DO NOTHING",
+            (link.srcurl, link.dsturl, link.kind, link.text, link.rel),
+        )?;
+        Ok(())
+    }
+
+    /// Deletes every page of a site, along with the pages' links.
+    pub fn delete_pages_by_siteurl(&self, site: SiteEntry) -> Result<(), DatabaseError> {
+        self.connection
+            .execute("DELETE FROM page WHERE siteurl = ?1", (site.url,))?;
+        Ok(())
+    }
+
+    pub fn get_pages_by_siteurl(&self, site: SiteEntry) -> Result<Vec<PageEntry>, DatabaseError> {
+        self.query_pages("siteurl = ?1", site.url)
+    }
+
+    /// Returns the pages of a site that couldn't be fetched or returned an http error.
+    /// Pages that weren't fetched because of robots.txt aren't dead.
+    pub fn get_dead_pages_by_siteurl(
+        &self,
+        site: SiteEntry,
+    ) -> Result<Vec<PageEntry>, DatabaseError> {
+        self.query_pages(
+            "siteurl = ?1 AND ((status IS NULL AND blocked = 0) OR status >= 400)",
+            site.url,
+        )
+    }
+
+    /// Returns the pages of a site that weren't fetched because robots.txt disallows it.
+    pub fn get_blocked_pages_by_siteurl(
+        &self,
+        site: SiteEntry,
+    ) -> Result<Vec<PageEntry>, DatabaseError> {
+        self.query_pages("siteurl = ?1 AND blocked = 1", site.url)
+    }
+
+    /// Returns every page link on any page of `site`, ordered by page.
+    pub fn get_page_links_by_siteurl(
+        &self,
+        site: SiteEntry,
+    ) -> Result<Vec<PageLinkEntry>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT pagelink.srcurl, pagelink.dsturl, pagelink.kind, pagelink.text, pagelink.rel
+            FROM pagelink
+            JOIN page ON page.url = pagelink.srcurl
+            WHERE page.siteurl = ?1
+            ORDER BY pagelink.srcurl, pagelink.dsturl, pagelink.kind",
+        )?;
+        let result = statement.query_map((site.url,), PageLinkEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<PageLinkEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns every page link on a page of `srcsite` that points somewhere on `dstsite`,
+    /// i.e. which pages of site X link to site Y.
+    pub fn get_page_links_between_sites(
+        &self,
+        srcsite: SiteEntry,
+        dstsite: SiteEntry,
+    ) -> Result<Vec<PageLinkEntry>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT pagelink.srcurl, pagelink.dsturl, pagelink.kind, pagelink.text, pagelink.rel
+            FROM pagelink
+            JOIN page ON page.url = pagelink.srcurl
+            WHERE page.siteurl = ?1 AND substr(pagelink.dsturl, 1, length(?2)) = ?2
+            ORDER BY pagelink.srcurl, pagelink.dsturl, pagelink.kind",
+        )?;
+        let result = statement.query_map((srcsite.url, dstsite.url), PageLinkEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<PageLinkEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns every link between sites, with redirect-only sites collapsed into the site they
+    /// redirect to: a site whose front page redirects to another site (e.g. one that moved)
+    /// isn't a node of its own, links to it point to its target instead and its own links are left out.
+    pub fn get_site_graph(&self) -> Result<Vec<LinkEntry>, DatabaseError> {
+        let redirects = self.get_site_redirects()?;
+        let mut statement = self
+            .connection
+            .prepare("SELECT srcurl, dsturl, kind FROM link ORDER BY srcurl, dsturl, kind")?;
+        let result = statement.query_map((), LinkEntry::from_row)?;
+        let mut resultlist = Vec::new();
+        for link in result {
+            let mut link = link?;
+            if redirects.contains_key(&link.srcurl) {
+                continue;
+            }
+            if let Some(target) = redirects.get(&link.dsturl) {
+                link.dsturl = target.clone();
+            }
+            if link.dsturl != link.srcurl {
+                resultlist.push(link);
+            }
+        }
+        /* several sites can collapse into one, so the same link can come up more than once */
+        resultlist.sort_by(|a: &LinkEntry, b: &LinkEntry| {
+            (&a.srcurl, &a.dsturl, a.kind.as_str()).cmp(&(&b.srcurl, &b.dsturl, b.kind.as_str()))
+        });
+        resultlist.dedup();
+        Ok(resultlist)
+    }
+
+    /// Returns the url of every site whose front page redirects to another site,
+    /// along with the url of the site it ends up at after following any further moves.
+    pub fn get_site_redirects(&self) -> Result<HashMap<String, String>, DatabaseError> {
+        let sites = self.get_site_resolver()?;
+
+        /* find the sites whose front page redirects to another site */
+        let mut statement = self.connection.prepare(
+            "SELECT page.siteurl, pagelink.dsturl
+            FROM page
+            JOIN pagelink ON pagelink.srcurl = page.url
+            WHERE page.url = page.siteurl AND pagelink.kind = ?1
+            ORDER BY page.siteurl, pagelink.dsturl",
+        )?;
+        let result = statement.query_map((LinkKind::Redirect,), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut redirects: HashMap<String, String> = HashMap::new();
+        for r in result {
+            let (siteurl, dsturl) = r?;
+            let Some(target) = Url::parse(&dsturl).ok().and_then(|u| sites.resolve(&u)) else {
+                continue;
+            };
+            let target = target.url().to_string();
+            if target != siteurl {
+                redirects.entry(siteurl).or_insert(target);
+            }
+        }
+        /* follow chains of moved sites, giving up on loops */
+        let resolve = |url: &str| -> String {
+            let mut url = url;
+            for _ in 0..redirects.len() {
+                match redirects.get(url) {
+                    Some(target) => url = target,
+                    None => break,
+                }
+            }
+            url.to_string()
+        };
+        let resultlist = redirects
+            .keys()
+            .map(|siteurl| (siteurl.clone(), resolve(siteurl)))
+            .collect();
+        Ok(resultlist)
+    }
+
+    /// Fills the temporary tables that `for_each_site_node()` and `for_each_site_edge()` read,
+    /// for the site graph of `get_site_graph()` without the sites `crawledonly` and `mindegree` leave out.
+    ///
+    /// Page counts and degrees are sql aggregates and page links are counted per pair of sites,
+    /// so nothing here is held in memory but the redirects and one row at a time.
+    /// Degrees are counted over the whole graph, so they stay the same whatever is left out.
+    /// The tables only live as long as the connection and are replaced on every call.
+    pub fn set_site_graph_tables(
+        &self,
+        mindegree: usize,
+        crawledonly: bool,
+    ) -> Result<(), DatabaseError> {
+        let sites = self.get_site_resolver()?;
+        let redirects = self.get_site_redirects()?;
+        self.in_transaction(|db| {
+            db.connection.execute_batch(
+                "DROP TABLE IF EXISTS temp.siteredirect;
+                DROP TABLE IF EXISTS temp.siteweight;
+                DROP TABLE IF EXISTS temp.sitegraphnode;
+                CREATE TEMP TABLE siteredirect (
+                    siteurl TEXT NOT NULL PRIMARY KEY,
+                    target TEXT NOT NULL
+                );
+                CREATE TEMP TABLE siteweight (
+                    srcurl TEXT NOT NULL,
+                    dsturl TEXT NOT NULL,
+                    weight INTEGER NOT NULL,
+                    PRIMARY KEY (srcurl, dsturl)
+                );
+                CREATE TEMP TABLE sitegraphnode (
+                    url TEXT NOT NULL PRIMARY KEY,
+                    crawltime INTEGER NOT NULL,
+                    pages INTEGER NOT NULL,
+                    indegree INTEGER NOT NULL,
+                    outdegree INTEGER NOT NULL,
+                    community INTEGER,
+                    component INTEGER
+                );",
+            )?;
+            for (siteurl, target) in &redirects {
+                db.connection.execute(
+                    "INSERT INTO temp.siteredirect (siteurl, target) VALUES (?1, ?2)",
+                    (siteurl, target),
+                )?;
+            }
+
+            /* page links are counted per page url, then added up per pair of sites */
+            let mut statement = db.connection.prepare(
+                "SELECT page.siteurl, pagelink.dsturl, COUNT(*)
+                FROM pagelink
+                JOIN page ON page.url = pagelink.srcurl
+                GROUP BY page.siteurl, pagelink.dsturl",
+            )?;
+            let mut rows = statement.query(())?;
+            while let Some(row) = rows.next()? {
+                let srcurl: String = row.get(0)?;
+                if redirects.contains_key(&srcurl) {
+                    continue;
+                }
+                let dsturl: String = row.get(1)?;
+                let Some(dstsite) = Url::parse(&dsturl).ok().and_then(|u| sites.resolve(&u)) else {
+                    continue;
+                };
+                let mut dsturl = dstsite.url().to_string();
+                if let Some(target) = redirects.get(&dsturl) {
+                    dsturl = target.clone();
+                }
+                if dsturl != srcurl {
+                    db.connection.execute(
+                        "INSERT INTO temp.siteweight (srcurl, dsturl, weight) VALUES (?1, ?2, ?3)
+                        ON CONFLICT (srcurl, dsturl) DO UPDATE SET weight = weight + excluded.weight",
+                        (srcurl, dsturl, row.get::<_, i64>(2)?),
+                    )?;
+                }
+            }
+
+            db.connection.execute(
+                "WITH edge AS (
+                    SELECT DISTINCT link.srcurl AS srcurl,
+                        COALESCE(siteredirect.target, link.dsturl) AS dsturl
+                    FROM link
+                    LEFT JOIN temp.siteredirect ON siteredirect.siteurl = link.dsturl
+                    WHERE link.srcurl NOT IN (SELECT siteurl FROM temp.siteredirect)
+                        AND COALESCE(siteredirect.target, link.dsturl) != link.srcurl
+                ),
+                node AS (
+                    SELECT url FROM site WHERE url NOT IN (SELECT siteurl FROM temp.siteredirect)
+                    UNION SELECT srcurl FROM edge
+                    UNION SELECT dsturl FROM edge
+                )
+                INSERT INTO temp.sitegraphnode
+                    (url, crawltime, pages, indegree, outdegree, community, component)
+                SELECT * FROM (
+                    SELECT node.url,
+                        COALESCE(site.crawltime, 0) AS crawltime,
+                        COALESCE(pages.n, 0),
+                        COALESCE(indegree.n, 0) AS indegree,
+                        COALESCE(outdegree.n, 0) AS outdegree,
+                        sitecluster.community,
+                        sitecluster.component
+                    FROM node
+                    LEFT JOIN site ON site.url = node.url
+                    LEFT JOIN (SELECT siteurl, COUNT(*) AS n FROM page GROUP BY siteurl) AS pages
+                        ON pages.siteurl = node.url
+                    LEFT JOIN (SELECT dsturl, COUNT(*) AS n FROM edge GROUP BY dsturl) AS indegree
+                        ON indegree.dsturl = node.url
+                    LEFT JOIN (SELECT srcurl, COUNT(*) AS n FROM edge GROUP BY srcurl) AS outdegree
+                        ON outdegree.srcurl = node.url
+                    LEFT JOIN sitecluster ON sitecluster.siteurl = node.url
+                )
+                WHERE (NOT ?1 OR crawltime > 0) AND indegree + outdegree >= ?2",
+                (crawledonly, mindegree as i64),
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Calls `f` with every site left in the tables of `set_site_graph_tables()`, ordered by url.
+    pub fn for_each_site_node<E, F>(&self, mut f: F) -> Result<(), E>
+    where
+        E: From<DatabaseError>,
+        F: FnMut(SiteNodeEntry) -> Result<(), E>,
+    {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT url, crawltime, pages, indegree, outdegree, community, component
+                FROM temp.sitegraphnode ORDER BY url",
+            )
+            .map_err(DatabaseError::from)?;
+        let mut rows = statement.query(()).map_err(DatabaseError::from)?;
+        while let Some(row) = rows.next().map_err(DatabaseError::from)? {
+            f(SiteNodeEntry::from_row(row).map_err(DatabaseError::from)?)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every pair of linked sites whose both ends are left in the tables
+    /// of `set_site_graph_tables()`, ordered by source and destination.
+    /// Links of different kinds between the same sites make one edge.
+    pub fn for_each_site_edge<E, F>(&self, mut f: F) -> Result<(), E>
+    where
+        E: From<DatabaseError>,
+        F: FnMut(SiteEdgeEntry) -> Result<(), E>,
+    {
+        /* links from before pages were stored have no page links to count */
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT edge.srcurl, edge.dsturl, edge.kind, MAX(COALESCE(siteweight.weight, 0), 1)
+                FROM (
+                    SELECT link.srcurl AS srcurl,
+                        COALESCE(siteredirect.target, link.dsturl) AS dsturl,
+                        link.kind AS kind
+                    FROM link
+                    LEFT JOIN temp.siteredirect ON siteredirect.siteurl = link.dsturl
+                ) AS edge
+                JOIN temp.sitegraphnode AS src ON src.url = edge.srcurl
+                JOIN temp.sitegraphnode AS dst ON dst.url = edge.dsturl
+                LEFT JOIN temp.siteweight
+                    ON siteweight.srcurl = edge.srcurl AND siteweight.dsturl = edge.dsturl
+                WHERE edge.srcurl NOT IN (SELECT siteurl FROM temp.siteredirect)
+                    AND edge.dsturl != edge.srcurl
+                ORDER BY edge.srcurl, edge.dsturl",
+            )
+            .map_err(DatabaseError::from)?;
+        let mut rows = statement.query(()).map_err(DatabaseError::from)?;
+        let mut edge: Option<SiteEdgeEntry> = None;
+        while let Some(row) = rows.next().map_err(DatabaseError::from)? {
+            let next = SiteEdgeEntry::from_row(row).map_err(DatabaseError::from)?;
+            match &mut edge {
+                Some(e) if e.srcurl == next.srcurl && e.dsturl == next.dsturl => {
+                    e.kinds.extend(next.kinds);
+                }
+                _ => {
+                    if let Some(e) = edge.replace(next) {
+                        f(e.sorted())?;
+                    }
+                }
+            }
+        }
+        if let Some(e) = edge {
+            f(e.sorted())?;
+        }
+        Ok(())
+    }
+
+    /// Returns every site, ordered by url.
+    pub fn get_sites(&self) -> Result<Vec<SiteEntry>, DatabaseError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT url, crawltime FROM site ORDER BY url ASC")?;
+        let result = statement.query_map((), |row| {
+            Ok(SiteEntry {
+                url: row.get(0)?,
+                crawltime: row.get(1)?,
             })
-            .map_err(|e| e.to_string())?;
-        let resultlist: Vec<LinkEntry> = result.map(|r| r.unwrap()).collect();
+        })?;
+        let resultlist = result.collect::<Result<Vec<SiteEntry>, _>>()?;
+        Ok(resultlist)
+    }
+
+    /// Returns the pages matching `filter` (an sql condition with a single parameter), ordered by url.
+    fn query_pages(&self, filter: &str, param: String) -> Result<Vec<PageEntry>, DatabaseError> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT url, siteurl, status, contenttype, size, fetchtime, hash, blocked, etag, lastmodified
+            FROM page WHERE {filter} ORDER BY url"
+        ))?;
+        let result = statement.query_map((param,), PageEntry::from_row)?;
+        let resultlist = result.collect::<Result<Vec<PageEntry>, _>>()?;
         Ok(resultlist)
     }
 
+    /// Looks for rows that break the schema's guarantees.
+    ///
+    /// Foreign keys weren't always enforced, so older databases can contain
+    /// rows whose site (or for page links, whose page) has been deleted.
+    pub fn check_integrity(&self) -> Result<IntegrityReport, DatabaseError> {
+        fn orphans<T>(
+            con: &Connection,
+            sql: &str,
+            from_row: fn(&Row) -> rusqlite::Result<T>,
+        ) -> Result<Vec<T>, DatabaseError> {
+            let mut statement = con.prepare(sql)?;
+            let result = statement.query_map((), from_row)?;
+            let resultlist = result.collect::<Result<Vec<T>, _>>()?;
+            Ok(resultlist)
+        }
+        let con = &self.connection;
+        Ok(IntegrityReport {
+            orphanedlinks: orphans(
+                con,
+                "SELECT srcurl, dsturl, kind FROM link WHERE srcurl NOT IN (SELECT url FROM site)",
+                LinkEntry::from_row,
+            )?,
+            orphanedpages: orphans(
+                con,
+                "SELECT url, siteurl, status, contenttype, size, fetchtime, hash, blocked, etag, lastmodified
+                FROM page WHERE siteurl NOT IN (SELECT url FROM site)",
+                PageEntry::from_row,
+            )?,
+            orphanedpagelinks: orphans(
+                con,
+                "SELECT srcurl, dsturl, kind, text, rel
+                FROM pagelink WHERE srcurl NOT IN (SELECT url FROM page)",
+                PageLinkEntry::from_row,
+            )?,
+            orphanedsitedomains: orphans(
+                con,
+                "SELECT domain, siteurl FROM sitedomain WHERE siteurl NOT IN (SELECT url FROM site)",
+                SiteDomainEntry::from_row,
+            )?,
+            orphanedschedules: orphans(
+                con,
+                "SELECT siteurl, nextcrawl, interval, crawls, changes
+                FROM siteschedule WHERE siteurl NOT IN (SELECT url FROM site)",
+                ScheduleEntry::from_row,
+            )?,
+            orphanedscores: orphans(
+                con,
+                "SELECT siteurl, pagerank, hub, authority, indegree, outdegree, betweenness
+                FROM sitescore WHERE siteurl NOT IN (SELECT url FROM site)",
+                ScoreEntry::from_row,
+            )?,
+            orphanedclusters: orphans(
+                con,
+                "SELECT siteurl, community, component
+                FROM sitecluster WHERE siteurl NOT IN (SELECT url FROM site)",
+                ClusterEntry::from_row,
+            )?,
+        })
+    }
+
+    /// Deletes the rows that `check_integrity()` reports.
+    /// Returns the number of rows that were deleted.
+    pub fn repair_integrity(&self) -> Result<usize, DatabaseError> {
+        self.in_transaction(|db| {
+            let mut deleted = 0;
+            for sql in [
+                "DELETE FROM link WHERE srcurl NOT IN (SELECT url FROM site)",
+                "DELETE FROM pagelink WHERE srcurl NOT IN (SELECT url FROM page)",
+                "DELETE FROM page WHERE siteurl NOT IN (SELECT url FROM site)",
+                "DELETE FROM sitedomain WHERE siteurl NOT IN (SELECT url FROM site)",
+                "DELETE FROM siteschedule WHERE siteurl NOT IN (SELECT url FROM site)",
+                "DELETE FROM sitescore WHERE siteurl NOT IN (SELECT url FROM site)",
+                "DELETE FROM sitecluster WHERE siteurl NOT IN (SELECT url FROM site)",
+            ] {
+                deleted += db.connection.execute(sql, ())?;
+            }
+            Ok(deleted)
+        })
+    }
+
+    /// Counts what the database holds, with sites due at `now` counted as due.
+    pub fn get_stats(&self, now: i64) -> Result<DatabaseStats, DatabaseError> {
+        let count = |sql: &str| -> Result<u64, DatabaseError> {
+            let n: i64 = self.connection.query_row(sql, (), |row| row.get(0))?;
+            Ok(n as u64)
+        };
+        let duesites: i64 = self.connection.query_row(
+            "SELECT COUNT(*) FROM site
+            LEFT JOIN siteschedule ON siteschedule.siteurl = site.url
+            WHERE COALESCE(siteschedule.nextcrawl, 0) <= ?1",
+            (now,),
+            |row| row.get(0),
+        )?;
+        Ok(DatabaseStats {
+            sites: count("SELECT COUNT(*) FROM site")?,
+            crawledsites: count("SELECT COUNT(*) FROM site WHERE crawltime > 0")?,
+            duesites: duesites as u64,
+            links: count("SELECT COUNT(*) FROM link")?,
+            pages: count("SELECT COUNT(*) FROM page")?,
+            pagelinks: count("SELECT COUNT(*) FROM pagelink")?,
+            sitedomains: count("SELECT COUNT(*) FROM sitedomain")?,
+        })
+    }
+
     // pub fn get_site_by_
 }
 
+/// The result of `Database::get_stats()`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub sites: u64,
+    pub crawledsites: u64, // sites crawled at least once
+    pub duesites: u64,     // sites due to be crawled, including the ones never crawled
+    pub links: u64,        // links between sites
+    pub pages: u64,
+    pub pagelinks: u64,   // links on pages
+    pub sitedomains: u64, // custom domains
+}
+
+/// The result of `Database::check_integrity()`.
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    /// Links whose source site doesn't exist.
+    pub orphanedlinks: Vec<LinkEntry>,
+    /// Pages whose site doesn't exist.
+    pub orphanedpages: Vec<PageEntry>,
+    /// Page links whose source page doesn't exist.
+    pub orphanedpagelinks: Vec<PageLinkEntry>,
+    /// Custom domains whose site doesn't exist.
+    pub orphanedsitedomains: Vec<SiteDomainEntry>,
+    /// Schedules whose site doesn't exist.
+    pub orphanedschedules: Vec<ScheduleEntry>,
+    /// Scores whose site doesn't exist.
+    pub orphanedscores: Vec<ScoreEntry>,
+    /// Communities and components whose site doesn't exist.
+    pub orphanedclusters: Vec<ClusterEntry>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.orphanedlinks.is_empty()
+            && self.orphanedpages.is_empty()
+            && self.orphanedpagelinks.is_empty()
+            && self.orphanedsitedomains.is_empty()
+            && self.orphanedschedules.is_empty()
+            && self.orphanedscores.is_empty()
+            && self.orphanedclusters.is_empty()
+    }
+}
+
 /// A site entry in a `Database`.
 ///
 /// `url` must be properly formatted.
@@ -178,58 +1137,699 @@ impl Database {
 ///
 /// `crawltime` is the unix timestamp of when the site was last crawled.
 /// Sites that haven't been crawled yet should set this to 0.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SiteEntry {
     url: String, // primary key; base url of site (e.g. "https://kryptonaut.neocities.org/")
     crawltime: i64, // timestamp of last crawl, value is irrelevant if `iscrawled` is false
 }
 
 impl SiteEntry {
-    pub fn new(url: Url, lastcrawled: i64) -> Result<SiteEntry, String> {
+    pub fn new(url: Url, lastcrawled: i64) -> Result<SiteEntry, DatabaseError> {
         if url.domain().is_none() {
-            return Err(format!(r#"invalid url "{}""#, url.as_str()));
+            return Err(DatabaseError::InvalidUrl(url.to_string()));
         }
+        let url = canonicalize(&url);
         let s = SiteEntry {
             url: url.to_string(),
             crawltime: lastcrawled,
         };
         Ok(s)
     }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn crawltime(&self) -> i64 {
+        self.crawltime
+    }
+}
+
+/// A custom domain that a neocities site is served from.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SiteDomainEntry {
+    domain: String,  // primary key; lowercase, without "www."
+    siteurl: String, // site key of the site the domain belongs to
+}
+
+impl SiteDomainEntry {
+    pub fn new(domain: &str, site: &SiteId) -> Result<SiteDomainEntry, DatabaseError> {
+        let domain = site::normalize_host(domain);
+        if domain.is_empty() {
+            return Err(DatabaseError::InvalidUrl(domain));
+        }
+        let d = SiteDomainEntry {
+            domain,
+            siteurl: site.url().to_string(),
+        };
+        Ok(d)
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<SiteDomainEntry> {
+        Ok(SiteDomainEntry {
+            domain: row.get(0)?,
+            siteurl: row.get(1)?,
+        })
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn siteurl(&self) -> &str {
+        &self.siteurl
+    }
+}
+
+/// When a site is due to be crawled again, and how often its links changed so far.
+/// See `scheduler::Scheduler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleEntry {
+    siteurl: String, // site key
+    nextcrawl: i64,  // unix timestamp the site is due at, 0 for sites that were never scheduled
+    interval: i64,   // seconds between the last crawl and `nextcrawl`
+    crawls: i64,     // number of times the site was crawled while scheduled
+    changes: i64,    // number of those crawls that found different links than the one before
+}
+
+impl ScheduleEntry {
+    pub fn new(
+        siteurl: Url,
+        nextcrawl: i64,
+        interval: i64,
+    ) -> Result<ScheduleEntry, DatabaseError> {
+        if siteurl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(siteurl.to_string()));
+        }
+        let siteurl = canonicalize(&siteurl);
+        let s = ScheduleEntry {
+            siteurl: siteurl.to_string(),
+            nextcrawl,
+            interval,
+            crawls: 0,
+            changes: 0,
+        };
+        Ok(s)
+    }
+
+    /// Sets how many crawls the schedule is based on, and how many of them found changes.
+    pub fn with_history(mut self, crawls: i64, changes: i64) -> ScheduleEntry {
+        self.crawls = crawls;
+        self.changes = changes;
+        self
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<ScheduleEntry> {
+        Ok(ScheduleEntry {
+            siteurl: row.get(0)?,
+            nextcrawl: row.get(1)?,
+            interval: row.get(2)?,
+            crawls: row.get(3)?,
+            changes: row.get(4)?,
+        })
+    }
+
+    pub fn siteurl(&self) -> &str {
+        &self.siteurl
+    }
+
+    pub fn nextcrawl(&self) -> i64 {
+        self.nextcrawl
+    }
+
+    pub fn interval(&self) -> i64 {
+        self.interval
+    }
+
+    pub fn crawls(&self) -> i64 {
+        self.crawls
+    }
+
+    pub fn changes(&self) -> i64 {
+        self.changes
+    }
+
+    /// Whether the site was never crawled while scheduled.
+    pub fn is_new(&self) -> bool {
+        self.crawls == 0
+    }
+}
+
+/// A column of the `sitescore` table that sites can be ordered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreColumn {
+    PageRank,
+    Hub,
+    Authority,
+    InDegree,
+    OutDegree,
+    Betweenness,
+}
+
+impl ScoreColumn {
+    fn name(&self) -> &'static str {
+        match self {
+            ScoreColumn::PageRank => "pagerank",
+            ScoreColumn::Hub => "hub",
+            ScoreColumn::Authority => "authority",
+            ScoreColumn::InDegree => "indegree",
+            ScoreColumn::OutDegree => "outdegree",
+            ScoreColumn::Betweenness => "betweenness",
+        }
+    }
+}
+
+/// How a site ranks in the site graph. See `analysis::analyze()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoreEntry {
+    siteurl: String, // site key
+    pagerank: f64,
+    hub: f64,
+    authority: f64,
+    indegree: i64,  // sites linking to this one
+    outdegree: i64, // sites this one links to
+    betweenness: f64,
+}
+
+impl ScoreEntry {
+    pub fn new(siteurl: Url, pagerank: f64) -> Result<ScoreEntry, DatabaseError> {
+        if siteurl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(siteurl.to_string()));
+        }
+        let siteurl = canonicalize(&siteurl);
+        let s = ScoreEntry {
+            siteurl: siteurl.to_string(),
+            pagerank,
+            hub: 0.0,
+            authority: 0.0,
+            indegree: 0,
+            outdegree: 0,
+            betweenness: 0.0,
+        };
+        Ok(s)
+    }
+
+    pub fn with_hits(mut self, hub: f64, authority: f64) -> ScoreEntry {
+        self.hub = hub;
+        self.authority = authority;
+        self
+    }
+
+    pub fn with_degree(mut self, indegree: i64, outdegree: i64) -> ScoreEntry {
+        self.indegree = indegree;
+        self.outdegree = outdegree;
+        self
+    }
+
+    pub fn with_betweenness(mut self, betweenness: f64) -> ScoreEntry {
+        self.betweenness = betweenness;
+        self
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<ScoreEntry> {
+        Ok(ScoreEntry {
+            siteurl: row.get(0)?,
+            pagerank: row.get(1)?,
+            hub: row.get(2)?,
+            authority: row.get(3)?,
+            indegree: row.get(4)?,
+            outdegree: row.get(5)?,
+            betweenness: row.get(6)?,
+        })
+    }
+
+    pub fn siteurl(&self) -> &str {
+        &self.siteurl
+    }
+
+    pub fn pagerank(&self) -> f64 {
+        self.pagerank
+    }
+
+    pub fn hub(&self) -> f64 {
+        self.hub
+    }
+
+    pub fn authority(&self) -> f64 {
+        self.authority
+    }
+
+    pub fn indegree(&self) -> i64 {
+        self.indegree
+    }
+
+    pub fn outdegree(&self) -> i64 {
+        self.outdegree
+    }
+
+    pub fn betweenness(&self) -> f64 {
+        self.betweenness
+    }
+
+    /// Returns the value in `column`.
+    pub fn get(&self, column: ScoreColumn) -> f64 {
+        match column {
+            ScoreColumn::PageRank => self.pagerank,
+            ScoreColumn::Hub => self.hub,
+            ScoreColumn::Authority => self.authority,
+            ScoreColumn::InDegree => self.indegree as f64,
+            ScoreColumn::OutDegree => self.outdegree as f64,
+            ScoreColumn::Betweenness => self.betweenness,
+        }
+    }
+}
+
+/// Which groups of sites a site belongs to in the site graph. See `community::find_clusters()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterEntry {
+    siteurl: String, // site key
+    community: i64,  // sites that link to each other more than to the rest
+    component: i64,  // sites that can all reach each other through links
+}
+
+impl ClusterEntry {
+    pub fn new(
+        siteurl: Url,
+        community: i64,
+        component: i64,
+    ) -> Result<ClusterEntry, DatabaseError> {
+        if siteurl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(siteurl.to_string()));
+        }
+        let siteurl = canonicalize(&siteurl);
+        let c = ClusterEntry {
+            siteurl: siteurl.to_string(),
+            community,
+            component,
+        };
+        Ok(c)
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<ClusterEntry> {
+        Ok(ClusterEntry {
+            siteurl: row.get(0)?,
+            community: row.get(1)?,
+            component: row.get(2)?,
+        })
+    }
+
+    pub fn siteurl(&self) -> &str {
+        &self.siteurl
+    }
+
+    pub fn community(&self) -> i64 {
+        self.community
+    }
+
+    pub fn component(&self) -> i64 {
+        self.component
+    }
 }
 
 /// A link entry in a `Database`.
 ///
 /// `srcurl` and `dsturl` must be properly formatted.
 /// Ideally, `LinkEntry::new()` should guarantee this.
+///
+/// A site can link to another site in several ways (e.g. a hyperlink and a hotlinked image),
+/// each of which is its own entry.
 #[derive(Debug, PartialEq, Eq)]
 pub struct LinkEntry {
     srcurl: String, // source site key (this is the site that has the link)
     dsturl: String, // destination site key
+    kind: LinkKind,
 }
 
-impl LinkEntry {
-    pub fn new(srcurl: Url, dsturl: Url) -> Result<LinkEntry, String> {
-        if srcurl.domain().is_none() {
-            return Err(format!(r#"invalid source url "{}""#, srcurl.as_ref()));
-        }
-        if dsturl.domain().is_none() {
-            return Err(format!(r#"invalid destination url "{}""#, dsturl.as_ref()));
-        }
-        let l = LinkEntry {
-            srcurl: String::from(srcurl.as_str()),
-            dsturl: String::from(dsturl.as_str()),
-        };
-        Ok(l)
+impl LinkEntry {
+    pub fn new(srcurl: Url, dsturl: Url) -> Result<LinkEntry, DatabaseError> {
+        if srcurl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(srcurl.to_string()));
+        }
+        if dsturl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(dsturl.to_string()));
+        }
+        let srcurl = canonicalize(&srcurl);
+        let dsturl = canonicalize(&dsturl);
+        let l = LinkEntry {
+            srcurl: String::from(srcurl.as_str()),
+            dsturl: String::from(dsturl.as_str()),
+            kind: LinkKind::Hyperlink,
+        };
+        Ok(l)
+    }
+
+    /// Sets the kind of the link, which is `LinkKind::Hyperlink` by default.
+    pub fn with_kind(mut self, kind: LinkKind) -> LinkEntry {
+        self.kind = kind;
+        self
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<LinkEntry> {
+        Ok(LinkEntry {
+            srcurl: row.get(0)?,
+            dsturl: row.get(1)?,
+            kind: row.get(2)?,
+        })
+    }
+
+    pub fn srcurl(&self) -> &str {
+        &self.srcurl
+    }
+
+    pub fn dsturl(&self) -> &str {
+        &self.dsturl
+    }
+
+    pub fn kind(&self) -> LinkKind {
+        self.kind
+    }
+}
+
+/// A site of the exported site graph. See `Database::for_each_site_node()`.
+///
+/// `crawltime` is 0 for sites that were never crawled (or are only known from links to them),
+/// `community` and `component` are `None` for sites that weren't clustered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteNodeEntry {
+    url: String,
+    crawltime: i64,
+    pages: u64,       // pages stored for the site
+    indegree: usize,  // sites linking to this one
+    outdegree: usize, // sites this one links to
+    community: Option<i64>,
+    component: Option<i64>,
+}
+
+impl SiteNodeEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<SiteNodeEntry> {
+        Ok(SiteNodeEntry {
+            url: row.get(0)?,
+            crawltime: row.get(1)?,
+            pages: row.get::<_, i64>(2)? as u64,
+            indegree: row.get::<_, i64>(3)? as usize,
+            outdegree: row.get::<_, i64>(4)? as usize,
+            community: row.get(5)?,
+            component: row.get(6)?,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn crawltime(&self) -> i64 {
+        self.crawltime
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    pub fn indegree(&self) -> usize {
+        self.indegree
+    }
+
+    pub fn outdegree(&self) -> usize {
+        self.outdegree
+    }
+
+    pub fn community(&self) -> Option<i64> {
+        self.community
+    }
+
+    pub fn component(&self) -> Option<i64> {
+        self.component
+    }
+}
+
+/// Links from one site to another in the exported site graph, of every kind.
+/// See `Database::for_each_site_edge()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteEdgeEntry {
+    srcurl: String,
+    dsturl: String,
+    kinds: Vec<LinkKind>, // sorted
+    weight: u64,          // number of page links, at least 1
+}
+
+impl SiteEdgeEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<SiteEdgeEntry> {
+        Ok(SiteEdgeEntry {
+            srcurl: row.get(0)?,
+            dsturl: row.get(1)?,
+            kinds: vec![row.get(2)?],
+            weight: row.get::<_, i64>(3)? as u64,
+        })
+    }
+
+    /// Sorts the kinds, dropping the repeats that come up when several sites collapse into one.
+    fn sorted(mut self) -> SiteEdgeEntry {
+        self.kinds.sort_unstable();
+        self.kinds.dedup();
+        self
+    }
+
+    pub fn srcurl(&self) -> &str {
+        &self.srcurl
+    }
+
+    pub fn dsturl(&self) -> &str {
+        &self.dsturl
+    }
+
+    pub fn kinds(&self) -> &[LinkKind] {
+        &self.kinds
+    }
+
+    pub fn weight(&self) -> u64 {
+        self.weight
+    }
+}
+
+/// A page entry in a `Database`.
+///
+/// `url` is the full url of the page, `siteurl` is the key of the site it belongs to.
+/// `status` is `None` if the page couldn't be fetched at all.
+/// `fetchtime` is the unix timestamp of when the page was fetched.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PageEntry {
+    url: String,     // primary key
+    siteurl: String, // site key
+    status: Option<u16>,
+    contenttype: Option<String>,
+    size: i64, // body size in bytes
+    fetchtime: i64,
+    hash: Option<String>, // see `page::content_hash()`
+    blocked: bool,        // not fetched because robots.txt disallows it
+    etag: Option<String>, // validators from the last fetch, see `fetcher::Validators`
+    lastmodified: Option<String>,
+}
+
+impl PageEntry {
+    pub fn new(
+        url: Url,
+        siteurl: Url,
+        status: Option<u16>,
+        contenttype: Option<String>,
+        size: usize,
+        fetchtime: i64,
+        hash: Option<String>,
+    ) -> Result<PageEntry, DatabaseError> {
+        if url.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(url.to_string()));
+        }
+        let url = canonicalize(&url);
+        if siteurl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(siteurl.to_string()));
+        }
+        let siteurl = canonicalize(&siteurl);
+        let p = PageEntry {
+            url: url.to_string(),
+            siteurl: siteurl.to_string(),
+            status,
+            contenttype,
+            size: size as i64,
+            fetchtime,
+            hash,
+            blocked: false,
+            etag: None,
+            lastmodified: None,
+        };
+        Ok(p)
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<PageEntry> {
+        Ok(PageEntry {
+            url: row.get(0)?,
+            siteurl: row.get(1)?,
+            status: row.get(2)?,
+            contenttype: row.get(3)?,
+            size: row.get(4)?,
+            fetchtime: row.get(5)?,
+            hash: row.get(6)?,
+            blocked: row.get(7)?,
+            etag: row.get(8)?,
+            lastmodified: row.get(9)?,
+        })
+    }
+
+    /// Marks the page as not fetched because robots.txt disallows it.
+    pub fn with_blocked(mut self, blocked: bool) -> PageEntry {
+        self.blocked = blocked;
+        self
+    }
+
+    /// Sets the ETag and Last-Modified headers the page was sent with.
+    pub fn with_validators(
+        mut self,
+        etag: Option<String>,
+        lastmodified: Option<String>,
+    ) -> PageEntry {
+        self.etag = etag;
+        self.lastmodified = lastmodified;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn siteurl(&self) -> &str {
+        &self.siteurl
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    pub fn contenttype(&self) -> Option<&str> {
+        self.contenttype.as_deref()
+    }
+
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+
+    pub fn fetchtime(&self) -> i64 {
+        self.fetchtime
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    pub fn lastmodified(&self) -> Option<&str> {
+        self.lastmodified.as_deref()
+    }
+}
+
+/// A page link entry in a `Database`.
+///
+/// Unlike `LinkEntry`, this links a page to any url,
+/// which doesn't have to be a page or site that is in the database.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PageLinkEntry {
+    srcurl: String, // source page key
+    dsturl: String, // full destination url
+    kind: LinkKind,
+    text: Option<String>, // anchor or alt text
+    rel: String,          // space separated rel values
+}
+
+impl PageLinkEntry {
+    pub fn new(srcurl: Url, dsturl: Url) -> Result<PageLinkEntry, DatabaseError> {
+        if srcurl.domain().is_none() {
+            return Err(DatabaseError::InvalidUrl(srcurl.to_string()));
+        }
+        let srcurl = canonicalize(&srcurl);
+        let dsturl = canonicalize(&dsturl);
+        let l = PageLinkEntry {
+            srcurl: srcurl.to_string(),
+            dsturl: dsturl.to_string(),
+            kind: LinkKind::Hyperlink,
+            text: None,
+            rel: String::new(),
+        };
+        Ok(l)
+    }
+
+    /// Creates an entry for a link found on the page at `srcurl`.
+    pub fn from_link(srcurl: Url, link: &Link) -> Result<PageLinkEntry, DatabaseError> {
+        let l = PageLinkEntry::new(srcurl, link.url.clone())?
+            .with_kind(link.kind)
+            .with_text(link.text.clone())
+            .with_rel(&link.rel);
+        Ok(l)
+    }
+
+    /// Sets the kind of the link, which is `LinkKind::Hyperlink` by default.
+    pub fn with_kind(mut self, kind: LinkKind) -> PageLinkEntry {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_text(mut self, text: Option<String>) -> PageLinkEntry {
+        self.text = text;
+        self
+    }
+
+    pub fn with_rel(mut self, rel: &[String]) -> PageLinkEntry {
+        self.rel = rel.join(" ");
+        self
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<PageLinkEntry> {
+        Ok(PageLinkEntry {
+            srcurl: row.get(0)?,
+            dsturl: row.get(1)?,
+            kind: row.get(2)?,
+            text: row.get(3)?,
+            rel: row.get(4)?,
+        })
+    }
+
+    pub fn kind(&self) -> LinkKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    pub fn rel(&self) -> Vec<&str> {
+        self.rel.split_whitespace().collect()
+    }
+
+    pub fn srcurl(&self) -> &str {
+        &self.srcurl
+    }
+
+    pub fn dsturl(&self) -> &str {
+        &self.dsturl
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Database, LinkEntry, SiteEntry};
+    use super::{
+        Database, DatabaseError, DatabaseStats, LinkEntry, PageEntry, PageLinkEntry, ScheduleEntry,
+        SiteDomainEntry, SiteEntry,
+    };
+    use crate::page::LinkKind;
+    use crate::site::SiteId;
     use rusqlite::OptionalExtension;
     use url::Url;
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn helloworld() {
         assert!(true);
     }
@@ -411,4 +2011,699 @@ mod tests {
         .unwrap();
         assert!(db.set_link(link).is_err());
     }
+
+    #[test]
+    fn add_site_keeps_crawltime() {
+        let db = Database::connect_virtual().unwrap();
+
+        db.set_site(create_site("https://dawnvoid.neocities.org/", 999).unwrap())
+            .unwrap();
+        db.add_site(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        db.add_site(create_site("https://koyo.neocities.org/", 0).unwrap())
+            .unwrap();
+
+        // existing site should be unchanged
+        let result = get_site_by_url(&db, "https://dawnvoid.neocities.org/").unwrap();
+        assert_eq!(result.unwrap().crawltime(), 999);
+
+        // new site should exist
+        let result = get_site_by_url(&db, "https://koyo.neocities.org/").unwrap();
+        assert_eq!(result.unwrap().crawltime(), 0);
+    }
+
+    #[test]
+    fn errors_are_typed() {
+        let db = Database::connect_virtual().unwrap();
+
+        // link without a source site
+        let link = create_link(
+            "https://errormine.neocities.org/",
+            "https://scarbyte.neocities.org/",
+        )
+        .unwrap();
+        assert!(matches!(
+            db.set_link(link),
+            Err(DatabaseError::ConstraintViolation(_))
+        ));
+
+        // updating a site that doesn't exist
+        let site = create_site("https://errormine.neocities.org/", 999).unwrap();
+        assert!(matches!(
+            db.update_site_crawltime(site),
+            Err(DatabaseError::UnexpectedRowCount {
+                expected: 1,
+                actual: 0
+            })
+        ));
+
+        // url without a domain
+        let url = Url::parse("mailto:someone@example.org").unwrap();
+        assert!(matches!(
+            SiteEntry::new(url, 0),
+            Err(DatabaseError::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn entries_are_canonical() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("http://Koyo.neocities.org/index.html", 0).unwrap())
+            .unwrap();
+        db.set_site(create_site("https://koyo.neocities.org/#top", 5).unwrap())
+            .unwrap();
+        let sites = db.get_sites_with_oldest_crawltime(10).unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].url(), "https://koyo.neocities.org/");
+
+        let link = create_page_link(
+            "https://koyo.neocities.org/about.html",
+            "http://dawnvoid.neocities.org/blog/index.html?utm_source=koyo",
+        );
+        assert_eq!(link.srcurl(), "https://koyo.neocities.org/about");
+        assert_eq!(link.dsturl(), "https://dawnvoid.neocities.org/blog/");
+    }
+
+    #[test]
+    fn oldest_crawltime_empty() {
+        let db = Database::connect_virtual().unwrap();
+        assert!(db.get_site_with_oldest_crawltime().unwrap().is_none());
+    }
+
+    #[test]
+    fn oldest_crawltime_many() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("https://koyo.neocities.org/", 20).unwrap())
+            .unwrap();
+        db.set_site(create_site("https://scarbyte.neocities.org/", 0).unwrap())
+            .unwrap();
+        db.set_site(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        db.set_site(create_site("https://errormine.neocities.org/", 10).unwrap())
+            .unwrap();
+
+        let sites = db.get_sites_with_oldest_crawltime(3).unwrap();
+        let urls: Vec<&str> = sites.iter().map(|s| s.url()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://dawnvoid.neocities.org/",
+                "https://scarbyte.neocities.org/",
+                "https://errormine.neocities.org/",
+            ]
+        );
+    }
+
+    #[test]
+    fn foreign_keys_enabled() {
+        let db = Database::connect_virtual().unwrap();
+        let enabled: bool = db
+            .connection
+            .pragma_query_value(None, "foreign_keys", |row| row.get(0))
+            .unwrap();
+        assert!(enabled);
+    }
+
+    #[test]
+    fn connect_file_uses_wal() {
+        let path = std::env::temp_dir().join(format!("neomap-wal-{}.db", std::process::id()));
+        let db = Database::connect(&path).unwrap();
+        let mode: String = db
+            .connection
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        db.disconnect().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(mode.to_lowercase(), "wal");
+    }
+
+    #[test]
+    fn site_domains() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("https://koyo.neocities.org/", 0).unwrap())
+            .unwrap();
+        let koyo = SiteId::new("koyo").unwrap();
+        db.set_site_domain(SiteDomainEntry::new("WWW.Koyo.example", &koyo).unwrap())
+            .unwrap();
+
+        let domains = db.get_site_domains().unwrap();
+        assert_eq!(domains.len(), 1);
+        assert_eq!(domains[0].domain(), "koyo.example");
+        assert_eq!(domains[0].siteurl(), "https://koyo.neocities.org/");
+
+        let sites = db.get_site_resolver().unwrap();
+        let url = Url::parse("https://koyo.example/art/").unwrap();
+        assert_eq!(sites.resolve(&url), Some(koyo.clone()));
+
+        // the domain has to belong to a known site
+        let dawnvoid = SiteId::new("dawnvoid").unwrap();
+        assert!(matches!(
+            db.set_site_domain(SiteDomainEntry::new("dawn.example", &dawnvoid).unwrap()),
+            Err(DatabaseError::ConstraintViolation(_))
+        ));
+
+        // and goes away with it
+        db.delete_site_by_url(create_site("https://koyo.neocities.org/", 0).unwrap())
+            .unwrap();
+        assert!(db.get_site_domains().unwrap().is_empty());
+    }
+
+    #[test]
+    fn site_graph_collapses_redirects() {
+        let db = Database::connect_virtual().unwrap();
+        for site in ["alice", "oldalice", "bob", "carol"] {
+            let url = format!("https://{site}.neocities.org/");
+            db.set_site(create_site(&url, 0).unwrap()).unwrap();
+        }
+        // oldalice moved to alice, and her front page says so with a meta refresh
+        let old = Url::parse("https://oldalice.neocities.org/").unwrap();
+        db.set_page(PageEntry::new(old.clone(), old.clone(), Some(200), None, 0, 0, None).unwrap())
+            .unwrap();
+        let refresh = Url::parse("https://alice.neocities.org/").unwrap();
+        db.set_page_link(
+            PageLinkEntry::new(old, refresh)
+                .unwrap()
+                .with_kind(LinkKind::Redirect),
+        )
+        .unwrap();
+
+        let links = [
+            (
+                "https://bob.neocities.org/",
+                "https://oldalice.neocities.org/",
+                LinkKind::Hyperlink,
+            ),
+            (
+                "https://bob.neocities.org/",
+                "https://alice.neocities.org/",
+                LinkKind::Hyperlink,
+            ),
+            (
+                "https://carol.neocities.org/",
+                "https://oldalice.neocities.org/",
+                LinkKind::Image,
+            ),
+            (
+                "https://alice.neocities.org/",
+                "https://oldalice.neocities.org/",
+                LinkKind::Hyperlink,
+            ),
+            (
+                "https://oldalice.neocities.org/",
+                "https://alice.neocities.org/",
+                LinkKind::Redirect,
+            ),
+            (
+                "https://oldalice.neocities.org/",
+                "https://carol.neocities.org/",
+                LinkKind::Hyperlink,
+            ),
+        ];
+        for (src, dst, kind) in links {
+            db.set_link(create_link(src, dst).unwrap().with_kind(kind))
+                .unwrap();
+        }
+
+        // links to the old site go to the new one, and the old site's own links are gone
+        let graph = db.get_site_graph().unwrap();
+        let graph: Vec<(&str, &str, LinkKind)> = graph
+            .iter()
+            .map(|l| (l.srcurl(), l.dsturl(), l.kind()))
+            .collect();
+        assert_eq!(
+            graph,
+            vec![
+                (
+                    "https://bob.neocities.org/",
+                    "https://alice.neocities.org/",
+                    LinkKind::Hyperlink
+                ),
+                (
+                    "https://carol.neocities.org/",
+                    "https://alice.neocities.org/",
+                    LinkKind::Image
+                ),
+            ]
+        );
+        // and so do backlinks
+        let backlinks = db
+            .get_site_backlinks(create_site("https://alice.neocities.org/", 0).unwrap())
+            .unwrap();
+        let backlinks: Vec<(&str, &str, LinkKind)> = backlinks
+            .iter()
+            .map(|l| (l.srcurl(), l.dsturl(), l.kind()))
+            .collect();
+        assert_eq!(backlinks, graph);
+    }
+
+    #[test]
+    fn schedule() {
+        let db = Database::connect_virtual().unwrap();
+        for url in [
+            "https://alice.neocities.org/",
+            "https://bob.neocities.org/",
+            "https://carol.neocities.org/",
+            "https://dave.neocities.org/",
+        ] {
+            db.set_site(create_site(url, 0).unwrap()).unwrap();
+        }
+        let schedule = |url: &str, nextcrawl: i64| {
+            let url = Url::parse(url).unwrap();
+            ScheduleEntry::new(url, nextcrawl, 60)
+                .unwrap()
+                .with_history(2, 1)
+        };
+        db.set_schedule(schedule("https://bob.neocities.org/", 100))
+            .unwrap();
+        db.set_schedule(schedule("https://carol.neocities.org/", 50))
+            .unwrap();
+        db.set_schedule(schedule("https://carol.neocities.org/", 70))
+            .unwrap();
+
+        let urls = |entries: Vec<ScheduleEntry>| -> Vec<String> {
+            entries.iter().map(|e| e.siteurl().to_string()).collect()
+        };
+        // new sites first, then by due time
+        assert_eq!(
+            urls(db.get_due_schedule(75, 10).unwrap()),
+            vec![
+                "https://alice.neocities.org/",
+                "https://dave.neocities.org/",
+                "https://carol.neocities.org/"
+            ]
+        );
+        let upcoming = db.get_schedule(10).unwrap();
+        assert_eq!(upcoming.len(), 4);
+        assert!(upcoming[0].is_new());
+        assert_eq!(upcoming[3], schedule("https://bob.neocities.org/", 100));
+
+        let bob = create_site("https://bob.neocities.org/", 0).unwrap();
+        assert_eq!(
+            db.get_schedule_by_siteurl(bob)
+                .unwrap()
+                .unwrap()
+                .nextcrawl(),
+            100
+        );
+        let alice = create_site("https://alice.neocities.org/", 0).unwrap();
+        assert!(db.get_schedule_by_siteurl(alice).unwrap().is_none());
+    }
+
+    #[test]
+    fn backlinks() {
+        let db = Database::connect_virtual().unwrap();
+        let koyo = "https://koyo.neocities.org/";
+        for (src, dst, kind) in [
+            ("https://alice.neocities.org/", koyo, LinkKind::Hyperlink),
+            ("https://alice.neocities.org/", koyo, LinkKind::Image),
+            ("https://bob.neocities.org/", koyo, LinkKind::Hyperlink),
+            (
+                "https://bob.neocities.org/",
+                "https://alice.neocities.org/",
+                LinkKind::Hyperlink,
+            ),
+        ] {
+            db.set_site(create_site(src, 0).unwrap()).unwrap();
+            db.set_link(create_link(src, dst).unwrap().with_kind(kind))
+                .unwrap();
+        }
+
+        let links = db
+            .get_links_by_dsturl(create_link(koyo, koyo).unwrap())
+            .unwrap();
+        let links: Vec<(&str, LinkKind)> = links.iter().map(|l| (l.srcurl(), l.kind())).collect();
+        assert_eq!(
+            links,
+            vec![
+                ("https://alice.neocities.org/", LinkKind::Hyperlink),
+                ("https://alice.neocities.org/", LinkKind::Image),
+                ("https://bob.neocities.org/", LinkKind::Hyperlink),
+            ]
+        );
+        // alice links to koyo twice, but she is only one site
+        assert_eq!(
+            db.get_inbound_link_count(create_site(koyo, 0).unwrap())
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            db.get_inbound_link_counts(10).unwrap(),
+            vec![
+                (String::from(koyo), 2),
+                (String::from("https://alice.neocities.org/"), 1)
+            ]
+        );
+
+        // and backlinks don't scan the whole table
+        let plan: String = db
+            .connection
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT srcurl FROM link WHERE dsturl = ?1",
+                (koyo,),
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("link_dsturl"), "{plan}");
+
+        let alice = create_site("https://alice.neocities.org/", 0).unwrap();
+        assert_eq!(
+            db.get_linking_sites(create_site(koyo, 0).unwrap()).unwrap(),
+            vec!["https://alice.neocities.org/", "https://bob.neocities.org/"]
+        );
+        assert_eq!(db.get_linked_sites(alice).unwrap(), vec![koyo.to_string()]);
+    }
+
+    #[test]
+    fn stats() {
+        let db = Database::connect_virtual().unwrap();
+        assert_eq!(db.get_stats(0).unwrap(), DatabaseStats::default());
+
+        db.set_site(create_site("https://alice.neocities.org/", 100).unwrap())
+            .unwrap();
+        db.set_site(create_site("https://bob.neocities.org/", 0).unwrap())
+            .unwrap();
+        db.set_link(
+            create_link("https://alice.neocities.org/", "https://bob.neocities.org/").unwrap(),
+        )
+        .unwrap();
+        let alice = Url::parse("https://alice.neocities.org/").unwrap();
+        db.set_schedule(ScheduleEntry::new(alice, 500, 400).unwrap())
+            .unwrap();
+
+        let stats = db.get_stats(200).unwrap();
+        assert_eq!((stats.sites, stats.crawledsites, stats.links), (2, 1, 1));
+        // only bob, who was never crawled, is due
+        assert_eq!(stats.duesites, 1);
+        assert_eq!(db.get_stats(500).unwrap().duesites, 2);
+    }
+
+    #[test]
+    fn integrity_orphaned_links() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("https://koyo.neocities.org/", 0).unwrap())
+            .unwrap();
+        db.set_link(
+            create_link(
+                "https://koyo.neocities.org/",
+                "https://jackomix.neocities.org/",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(db.check_integrity().unwrap().is_ok());
+
+        // simulate an old database that didn't enforce foreign keys
+        db.connection
+            .pragma_update(None, "foreign_keys", false)
+            .unwrap();
+        db.set_link(
+            create_link(
+                "https://jackomix.neocities.org/",
+                "https://koyo.neocities.org/",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        db.connection
+            .pragma_update(None, "foreign_keys", true)
+            .unwrap();
+
+        let report = db.check_integrity().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.orphanedlinks.len(), 1);
+        assert_eq!(
+            report.orphanedlinks[0].srcurl(),
+            "https://jackomix.neocities.org/"
+        );
+
+        // repairing should only remove the orphan
+        assert_eq!(db.repair_integrity().unwrap(), 1);
+        assert!(db.check_integrity().unwrap().is_ok());
+        let dblinks = db
+            .get_links_by_srcurl(
+                create_link("https://koyo.neocities.org/", "https://example.org/").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(dblinks.len(), 1);
+    }
+
+    #[test]
+    fn integrity_orphaned_rows() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("https://koyo.neocities.org/", 0).unwrap())
+            .unwrap();
+
+        // simulate an old database that didn't enforce foreign keys
+        db.connection
+            .pragma_update(None, "foreign_keys", false)
+            .unwrap();
+        db.connection
+            .execute_batch(
+                "INSERT INTO page (url, siteurl, size, fetchtime)
+                    VALUES ('https://gone.neocities.org/', 'https://gone.neocities.org/', 0, 0);
+                INSERT INTO pagelink (srcurl, dsturl)
+                    VALUES ('https://koyo.neocities.org/lost', 'https://koyo.neocities.org/');
+                INSERT INTO sitedomain (domain, siteurl) VALUES ('gone.net', 'https://gone.neocities.org/');
+                INSERT INTO siteschedule (siteurl, nextcrawl, interval, crawls, changes)
+                    VALUES ('https://gone.neocities.org/', 0, 0, 0, 0);
+                INSERT INTO sitescore (siteurl, pagerank, hub, authority, indegree, outdegree, betweenness)
+                    VALUES ('https://gone.neocities.org/', 1, 0, 0, 0, 0, 0);
+                INSERT INTO sitecluster (siteurl, community, component)
+                    VALUES ('https://gone.neocities.org/', 0, 0);",
+            )
+            .unwrap();
+        db.connection
+            .pragma_update(None, "foreign_keys", true)
+            .unwrap();
+
+        let report = db.check_integrity().unwrap();
+        assert!(!report.is_ok());
+        assert!(report.orphanedlinks.is_empty());
+        assert_eq!(report.orphanedpages[0].url(), "https://gone.neocities.org/");
+        assert_eq!(
+            report.orphanedpagelinks[0].srcurl(),
+            "https://koyo.neocities.org/lost"
+        );
+        assert_eq!(report.orphanedsitedomains[0].domain(), "gone.net");
+        assert_eq!(report.orphanedschedules.len(), 1);
+        assert_eq!(report.orphanedscores.len(), 1);
+        assert_eq!(report.orphanedclusters.len(), 1);
+
+        assert_eq!(db.repair_integrity().unwrap(), 6);
+        assert!(db.check_integrity().unwrap().is_ok());
+        assert_eq!(db.get_sites().unwrap().len(), 1);
+    }
+
+    fn create_page(url: &str, siteurl: &str, status: Option<u16>) -> PageEntry {
+        PageEntry::new(
+            Url::parse(url).unwrap(),
+            Url::parse(siteurl).unwrap(),
+            status,
+            Some(String::from("text/html")),
+            100,
+            1000,
+            Some(String::from("0123456789abcdef")),
+        )
+        .unwrap()
+    }
+
+    fn create_page_link(srcurl: &str, dsturl: &str) -> PageLinkEntry {
+        PageLinkEntry::new(Url::parse(srcurl).unwrap(), Url::parse(dsturl).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn pages() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        db.set_site(create_site("https://koyo.neocities.org/", 0).unwrap())
+            .unwrap();
+
+        let pages = vec![
+            ("https://dawnvoid.neocities.org/", Some(200)),
+            ("https://dawnvoid.neocities.org/links.html", Some(200)),
+            ("https://dawnvoid.neocities.org/old.html", Some(404)),
+            ("https://dawnvoid.neocities.org/timeout.html", None),
+        ];
+        for &(url, status) in &pages {
+            db.set_page(create_page(url, "https://dawnvoid.neocities.org/", status))
+                .unwrap();
+        }
+
+        let links = vec![
+            (
+                "https://dawnvoid.neocities.org/",
+                "https://dawnvoid.neocities.org/links.html",
+            ),
+            (
+                "https://dawnvoid.neocities.org/links.html",
+                "https://koyo.neocities.org/",
+            ),
+            (
+                "https://dawnvoid.neocities.org/links.html",
+                "https://koyo.neocities.org/art/",
+            ),
+            (
+                "https://dawnvoid.neocities.org/links.html",
+                "https://errormine.neocities.org/",
+            ),
+        ];
+        for &(src, dst) in &links {
+            db.set_page_link(create_page_link(src, dst)).unwrap();
+        }
+
+        let result = db
+            .get_pages_by_siteurl(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        assert_eq!(result.len(), 4);
+        assert!(result.iter().all(|p| !p.is_blocked()));
+
+        // which page on dawnvoid links to koyo?
+        let result = db
+            .get_page_links_between_sites(
+                create_site("https://dawnvoid.neocities.org/", 0).unwrap(),
+                create_site("https://koyo.neocities.org/", 0).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                create_page_link(
+                    "https://dawnvoid.neocities.org/links.html",
+                    "https://koyo.neocities.org/"
+                ),
+                create_page_link(
+                    "https://dawnvoid.neocities.org/links.html",
+                    "https://koyo.neocities.org/art/"
+                ),
+            ]
+        );
+
+        // blocked pages aren't dead
+        db.set_page(
+            create_page(
+                "https://dawnvoid.neocities.org/private/",
+                "https://dawnvoid.neocities.org/",
+                None,
+            )
+            .with_blocked(true),
+        )
+        .unwrap();
+        let result = db
+            .get_blocked_pages_by_siteurl(
+                create_site("https://dawnvoid.neocities.org/", 0).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(result.len(), 1);
+        assert!(result[0].is_blocked());
+
+        // dead pages
+        let result = db
+            .get_dead_pages_by_siteurl(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        let urls: Vec<&str> = result.iter().map(|p| p.url()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://dawnvoid.neocities.org/old",
+                "https://dawnvoid.neocities.org/timeout"
+            ]
+        );
+
+        // deleting the site should delete its pages and their links
+        db.delete_site_by_url(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        let result = db
+            .get_pages_by_siteurl(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+        assert!(result.is_empty());
+        let n: i64 = db
+            .connection
+            .query_row("SELECT COUNT(*) FROM pagelink", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn set_page_no_site() {
+        let db = Database::connect_virtual().unwrap();
+        let page = create_page(
+            "https://errormine.neocities.org/",
+            "https://errormine.neocities.org/",
+            Some(200),
+        );
+        assert!(matches!(
+            db.set_page(page),
+            Err(DatabaseError::ConstraintViolation(_))
+        ));
+    }
+
+    #[test]
+    fn transaction_rolls_back() {
+        let db = Database::connect_virtual().unwrap();
+        let result: Result<(), DatabaseError> = db.in_transaction(|db| {
+            db.set_site(create_site("https://koyo.neocities.org/", 0).unwrap())?;
+            db.set_link(
+                create_link(
+                    "https://errormine.neocities.org/",
+                    "https://koyo.neocities.org/",
+                )
+                .unwrap(),
+            )?;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert!(get_site_by_url(&db, "https://koyo.neocities.org/")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn link_kinds() {
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(create_site("https://dawnvoid.neocities.org/", 0).unwrap())
+            .unwrap();
+
+        // the same pair of sites can be linked in different ways
+        let src = "https://dawnvoid.neocities.org/";
+        let dst = "https://koyo.neocities.org/";
+        db.set_link(create_link(src, dst).unwrap()).unwrap();
+        db.set_link(create_link(src, dst).unwrap().with_kind(LinkKind::Image))
+            .unwrap();
+        db.set_link(create_link(src, dst).unwrap()).unwrap();
+
+        let mut kinds: Vec<LinkKind> = db
+            .get_links_by_srcurl(create_link(src, dst).unwrap())
+            .unwrap()
+            .iter()
+            .map(|l| l.kind())
+            .collect();
+        kinds.sort();
+        assert_eq!(kinds, vec![LinkKind::Hyperlink, LinkKind::Image]);
+
+        let result = db
+            .get_links_by_srcurl_and_kind(create_link(src, dst).unwrap().with_kind(LinkKind::Image))
+            .unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].kind(), LinkKind::Image);
+
+        // page links keep their kind, text and rel
+        db.set_page(create_page(src, src, Some(200))).unwrap();
+        db.set_page_link(
+            create_page_link(src, dst)
+                .with_text(Some(String::from("koyo")))
+                .with_rel(&[String::from("me"), String::from("nofollow")]),
+        )
+        .unwrap();
+        let result = db
+            .get_page_links_between_sites(
+                create_site(src, 0).unwrap(),
+                create_site(dst, 0).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].kind(), LinkKind::Hyperlink);
+        assert_eq!(result[0].text(), Some("koyo"));
+        assert_eq!(result[0].rel(), vec!["me", "nofollow"]);
+    }
 }
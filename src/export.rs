@@ -0,0 +1,591 @@
+//! Writing the site graph out for other tools.
+//!
+//! The graph can be written as GraphML, GEXF (for Gephi), Graphviz DOT,
+//! or as a pair of CSV files with one row per site and one row per link.
+//! Every site is a node and every pair of linked sites is an edge,
+//! weighted by how many links the pages of one site have to the other.
+//! Sites carry their community and component once `community::find_clusters()` has been saved,
+//! sites without them leave those attributes out.
+//!
+//! Exports are streamed: degrees, page counts and weights are aggregated by the database
+//! (see `Database::set_site_graph_tables()`) and every site and edge is written as it is read,
+//! so neither the graph nor the page links are held in memory.
+
+use crate::database::{Database, DatabaseError, SiteEdgeEntry, SiteNodeEntry};
+use crate::page::LinkKind;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A file format the site graph can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    GraphMl,
+    Gexf,
+    Dot,
+    Csv, // a nodes file and an edges file
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::GraphMl, Format::Gexf, Format::Dot, Format::Csv];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::GraphMl => "graphml",
+            Format::Gexf => "gexf",
+            Format::Dot => "dot",
+            Format::Csv => "csv",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Format> {
+        Format::ALL.into_iter().find(|f| f.as_str() == s)
+    }
+
+    /// Guesses the format from the extension of `path` (e.g. "map.gexf").
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gv" => Some(Format::Dot),
+            "xml" => Some(Format::GraphMl),
+            e => Format::parse(e),
+        }
+    }
+}
+
+/// Which sites to leave out of an export.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportFilter {
+    pub mindegree: usize,  // leave out sites with fewer links in and out than this
+    pub crawledonly: bool, // leave out sites that were never crawled
+}
+
+/// Everything that can go wrong while writing the site graph.
+#[derive(Debug)]
+pub enum ExportError {
+    Database(DatabaseError),
+    Io(io::Error),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Database(e) => write!(f, "{e}"),
+            ExportError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExportError::Database(e) => Some(e),
+            ExportError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<DatabaseError> for ExportError {
+    fn from(e: DatabaseError) -> Self {
+        ExportError::Database(e)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
+
+/// How many sites and links a write produced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportCounts {
+    pub nodes: usize,
+    pub edges: usize,
+}
+
+/// The site graph as it is exported, sorted by url.
+///
+/// Sites and edges are read from the database while they are written,
+/// so every write goes over the database again.
+/// Degrees are counted over the whole graph, so they stay the same whatever is filtered out.
+pub struct SiteGraph<'a> {
+    db: &'a Database,
+}
+
+impl SiteGraph<'_> {
+    /// Prepares the site graph of `db` for writing, with redirect-only sites collapsed
+    /// (see `Database::get_site_graph()`), leaving out what `filter` asks for.
+    pub fn load<'a>(
+        db: &'a Database,
+        filter: &ExportFilter,
+    ) -> Result<SiteGraph<'a>, DatabaseError> {
+        db.set_site_graph_tables(filter.mindegree, filter.crawledonly)?;
+        Ok(SiteGraph { db })
+    }
+
+    /// Writes the graph as GraphML, with the node and edge attributes declared as keys.
+    pub fn write_graphml(&self, w: &mut impl Write) -> Result<ExportCounts, ExportError> {
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            w,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        for (key, domain, kind) in [
+            ("crawltime", "node", "long"),
+            ("pages", "node", "long"),
+            ("indegree", "node", "int"),
+            ("outdegree", "node", "int"),
+            ("community", "node", "long"),
+            ("component", "node", "long"),
+            ("weight", "edge", "long"),
+            ("kinds", "edge", "string"),
+        ] {
+            writeln!(
+                w,
+                r#"  <key id="{key}" for="{domain}" attr.name="{key}" attr.type="{kind}"/>"#
+            )?;
+        }
+        writeln!(w, r#"  <graph id="neomap" edgedefault="directed">"#)?;
+        let mut counts = ExportCounts::default();
+        self.db.for_each_site_node(|n| -> Result<(), ExportError> {
+            counts.nodes += 1;
+            writeln!(w, r#"    <node id="{}">"#, escape_xml(n.url()))?;
+            writeln!(w, r#"      <data key="crawltime">{}</data>"#, n.crawltime())?;
+            writeln!(w, r#"      <data key="pages">{}</data>"#, n.pages())?;
+            writeln!(w, r#"      <data key="indegree">{}</data>"#, n.indegree())?;
+            writeln!(w, r#"      <data key="outdegree">{}</data>"#, n.outdegree())?;
+            for (key, value) in clusters(&n) {
+                writeln!(w, r#"      <data key="{key}">{value}</data>"#)?;
+            }
+            writeln!(w, "    </node>")?;
+            Ok(())
+        })?;
+        self.db.for_each_site_edge(|e| -> Result<(), ExportError> {
+            counts.edges += 1;
+            writeln!(
+                w,
+                r#"    <edge source="{}" target="{}">"#,
+                escape_xml(e.srcurl()),
+                escape_xml(e.dsturl())
+            )?;
+            writeln!(w, r#"      <data key="weight">{}</data>"#, e.weight())?;
+            writeln!(w, r#"      <data key="kinds">{}</data>"#, kinds(&e))?;
+            writeln!(w, "    </edge>")?;
+            Ok(())
+        })?;
+        writeln!(w, "  </graph>")?;
+        writeln!(w, "</graphml>")?;
+        Ok(counts)
+    }
+
+    /// Writes the graph as GEXF 1.3, which Gephi opens directly.
+    /// Edge weights use GEXF's own `weight`, everything else is an attribute.
+    pub fn write_gexf(&self, w: &mut impl Write) -> Result<ExportCounts, ExportError> {
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(w, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+        writeln!(w, r#"  <graph mode="static" defaultedgetype="directed">"#)?;
+        writeln!(w, r#"    <attributes class="node">"#)?;
+        for (id, kind) in [
+            ("crawltime", "long"),
+            ("pages", "long"),
+            ("indegree", "integer"),
+            ("outdegree", "integer"),
+            ("community", "long"),
+            ("component", "long"),
+        ] {
+            writeln!(
+                w,
+                r#"      <attribute id="{id}" title="{id}" type="{kind}"/>"#
+            )?;
+        }
+        writeln!(w, "    </attributes>")?;
+        writeln!(w, r#"    <attributes class="edge">"#)?;
+        writeln!(
+            w,
+            r#"      <attribute id="kinds" title="kinds" type="string"/>"#
+        )?;
+        writeln!(w, "    </attributes>")?;
+
+        let mut counts = ExportCounts::default();
+        writeln!(w, "    <nodes>")?;
+        self.db.for_each_site_node(|n| -> Result<(), ExportError> {
+            counts.nodes += 1;
+            let url = escape_xml(n.url());
+            writeln!(w, r#"      <node id="{url}" label="{url}">"#)?;
+            writeln!(w, "        <attvalues>")?;
+            let attributes = [
+                ("crawltime", n.crawltime()),
+                ("pages", n.pages() as i64),
+                ("indegree", n.indegree() as i64),
+                ("outdegree", n.outdegree() as i64),
+            ];
+            for (id, value) in attributes.into_iter().chain(clusters(&n)) {
+                writeln!(w, r#"          <attvalue for="{id}" value="{value}"/>"#)?;
+            }
+            writeln!(w, "        </attvalues>")?;
+            writeln!(w, "      </node>")?;
+            Ok(())
+        })?;
+        writeln!(w, "    </nodes>")?;
+
+        writeln!(w, "    <edges>")?;
+        self.db.for_each_site_edge(|e| -> Result<(), ExportError> {
+            writeln!(
+                w,
+                r#"      <edge id="{}" source="{}" target="{}" weight="{}">"#,
+                counts.edges,
+                escape_xml(e.srcurl()),
+                escape_xml(e.dsturl()),
+                e.weight()
+            )?;
+            counts.edges += 1;
+            writeln!(w, "        <attvalues>")?;
+            writeln!(
+                w,
+                r#"          <attvalue for="kinds" value="{}"/>"#,
+                kinds(&e)
+            )?;
+            writeln!(w, "        </attvalues>")?;
+            writeln!(w, "      </edge>")?;
+            Ok(())
+        })?;
+        writeln!(w, "    </edges>")?;
+        writeln!(w, "  </graph>")?;
+        writeln!(w, "</gexf>")?;
+        Ok(counts)
+    }
+
+    /// Writes the graph as a Graphviz digraph, with the attributes as node and edge attributes.
+    pub fn write_dot(&self, w: &mut impl Write) -> Result<ExportCounts, ExportError> {
+        writeln!(w, "digraph neomap {{")?;
+        let mut counts = ExportCounts::default();
+        self.db.for_each_site_node(|n| -> Result<(), ExportError> {
+            counts.nodes += 1;
+            let mut attributes = format!(
+                "crawltime={}, pages={}, indegree={}, outdegree={}",
+                n.crawltime(),
+                n.pages(),
+                n.indegree(),
+                n.outdegree()
+            );
+            for (key, value) in clusters(&n) {
+                attributes.push_str(&format!(", {key}={value}"));
+            }
+            writeln!(w, "  {} [{attributes}];", quote_dot(n.url()))?;
+            Ok(())
+        })?;
+        self.db.for_each_site_edge(|e| -> Result<(), ExportError> {
+            counts.edges += 1;
+            writeln!(
+                w,
+                "  {} -> {} [weight={}, kinds={}];",
+                quote_dot(e.srcurl()),
+                quote_dot(e.dsturl()),
+                e.weight(),
+                quote_dot(&kinds(&e))
+            )?;
+            Ok(())
+        })?;
+        writeln!(w, "}}")?;
+        Ok(counts)
+    }
+
+    /// Writes the nodes as CSV, one site per row.
+    /// The community and component of sites without them are left empty.
+    pub fn write_csv_nodes(&self, w: &mut impl Write) -> Result<ExportCounts, ExportError> {
+        writeln!(
+            w,
+            "url,crawltime,pages,indegree,outdegree,community,component"
+        )?;
+        let optional = |v: Option<i64>| v.map(|v| v.to_string()).unwrap_or_default();
+        let mut counts = ExportCounts::default();
+        self.db.for_each_site_node(|n| -> Result<(), ExportError> {
+            counts.nodes += 1;
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{}",
+                quote_csv(n.url()),
+                n.crawltime(),
+                n.pages(),
+                n.indegree(),
+                n.outdegree(),
+                optional(n.community()),
+                optional(n.component())
+            )?;
+            Ok(())
+        })?;
+        Ok(counts)
+    }
+
+    /// Writes the edges as CSV, one pair of linked sites per row.
+    pub fn write_csv_edges(&self, w: &mut impl Write) -> Result<ExportCounts, ExportError> {
+        writeln!(w, "source,target,weight,kinds")?;
+        let mut counts = ExportCounts::default();
+        self.db.for_each_site_edge(|e| -> Result<(), ExportError> {
+            counts.edges += 1;
+            writeln!(
+                w,
+                "{},{},{},{}",
+                quote_csv(e.srcurl()),
+                quote_csv(e.dsturl()),
+                e.weight(),
+                quote_csv(&kinds(&e))
+            )?;
+            Ok(())
+        })?;
+        Ok(counts)
+    }
+}
+
+/// The community and component of a site, as far as it has them.
+fn clusters(n: &SiteNodeEntry) -> impl Iterator<Item = (&'static str, i64)> {
+    let community = n.community().map(|c| ("community", c));
+    let component = n.component().map(|c| ("component", c));
+    community.into_iter().chain(component)
+}
+
+/// The kinds of an edge's links, separated by spaces (e.g. "hyperlink image").
+fn kinds(e: &SiteEdgeEntry) -> String {
+    let kinds: Vec<&str> = e.kinds().iter().map(LinkKind::as_str).collect();
+    kinds.join(" ")
+}
+
+/// Escapes `s` for use in xml text and double-quoted attributes.
+fn escape_xml(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Returns `s` as a double-quoted Graphviz id.
+fn quote_dot(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quotes `s` if it can't be a CSV field as it is.
+fn quote_csv(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        escape_xml, quote_csv, quote_dot, ExportCounts, ExportError, ExportFilter, Format,
+        SiteGraph,
+    };
+    use crate::database::{
+        ClusterEntry, Database, DatabaseError, LinkEntry, PageEntry, PageLinkEntry, SiteEdgeEntry,
+        SiteEntry, SiteNodeEntry,
+    };
+    use crate::page::LinkKind;
+    use std::path::Path;
+    use url::Url;
+
+    const GRAPHML: &str = include_str!("../tests/fixtures/export/graph.graphml");
+    const GEXF: &str = include_str!("../tests/fixtures/export/graph.gexf");
+    const DOT: &str = include_str!("../tests/fixtures/export/graph.dot");
+    const NODES_CSV: &str = include_str!("../tests/fixtures/export/nodes.csv");
+    const EDGES_CSV: &str = include_str!("../tests/fixtures/export/edges.csv");
+
+    fn url(name: &str, path: &str) -> Url {
+        Url::parse(&format!("https://{name}.neocities.org{path}")).unwrap()
+    }
+
+    /// alice and bob link to each other, alice also links to carol (never crawled),
+    /// and dave links to oldalice, who moved to alice.
+    fn database() -> Database {
+        let db = Database::connect_virtual().unwrap();
+        for (name, crawltime) in [
+            ("alice", 100),
+            ("bob", 200),
+            ("carol", 0),
+            ("dave", 300),
+            ("oldalice", 50),
+        ] {
+            db.set_site(SiteEntry::new(url(name, "/"), crawltime).unwrap())
+                .unwrap();
+        }
+        let pages = [
+            ("alice", "/", vec![("bob", "/"), ("carol", "/")]),
+            ("alice", "/about", vec![("bob", "/cat.png"), ("bob", "/x")]),
+            ("bob", "/", vec![("alice", "/")]),
+            ("dave", "/", vec![("oldalice", "/")]),
+            ("oldalice", "/", vec![("alice", "/")]),
+        ];
+        for (name, path, links) in pages {
+            let page = url(name, path);
+            db.set_page(
+                PageEntry::new(page.clone(), url(name, "/"), Some(200), None, 0, 0, None).unwrap(),
+            )
+            .unwrap();
+            for (dst, dstpath) in links {
+                let kind = if dstpath.ends_with(".png") {
+                    LinkKind::Image
+                } else if name == "oldalice" {
+                    LinkKind::Redirect
+                } else {
+                    LinkKind::Hyperlink
+                };
+                db.set_page_link(
+                    PageLinkEntry::new(page.clone(), url(dst, dstpath))
+                        .unwrap()
+                        .with_kind(kind),
+                )
+                .unwrap();
+                db.set_link(
+                    LinkEntry::new(url(name, "/"), url(dst, "/"))
+                        .unwrap()
+                        .with_kind(kind),
+                )
+                .unwrap();
+            }
+        }
+        // carol has no cluster, as if she was found after the last time they were computed
+        for (name, community, component) in [("alice", 0, 0), ("bob", 0, 0), ("dave", 1, 2)] {
+            db.set_cluster(ClusterEntry::new(url(name, "/"), community, component).unwrap())
+                .unwrap();
+        }
+        db
+    }
+
+    /// The sites and edges an export with `filter` writes.
+    fn graph(filter: ExportFilter) -> (Vec<SiteNodeEntry>, Vec<SiteEdgeEntry>) {
+        let db = database();
+        SiteGraph::load(&db, &filter).unwrap();
+        let mut nodes = Vec::new();
+        db.for_each_site_node(|n| -> Result<(), DatabaseError> {
+            nodes.push(n);
+            Ok(())
+        })
+        .unwrap();
+        let mut edges = Vec::new();
+        db.for_each_site_edge(|e| -> Result<(), DatabaseError> {
+            edges.push(e);
+            Ok(())
+        })
+        .unwrap();
+        (nodes, edges)
+    }
+
+    #[test]
+    fn load() {
+        let (nodes, edges) = graph(ExportFilter::default());
+        let summary: Vec<(&str, i64, u64, usize, usize)> = nodes
+            .iter()
+            .map(|n| {
+                (
+                    n.url(),
+                    n.crawltime(),
+                    n.pages(),
+                    n.indegree(),
+                    n.outdegree(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("https://alice.neocities.org/", 100, 2, 2, 2),
+                ("https://bob.neocities.org/", 200, 1, 1, 1),
+                ("https://carol.neocities.org/", 0, 0, 1, 0),
+                ("https://dave.neocities.org/", 300, 1, 0, 1),
+            ]
+        );
+        assert_eq!(
+            (nodes[0].community(), nodes[3].component()),
+            (Some(0), Some(2))
+        );
+        assert_eq!(nodes[2].community(), None);
+        let edges: Vec<(&str, &str, u64, Vec<LinkKind>)> = edges
+            .iter()
+            .map(|e| (e.srcurl(), e.dsturl(), e.weight(), e.kinds().to_vec()))
+            .collect();
+        assert_eq!(
+            edges[0],
+            (
+                "https://alice.neocities.org/",
+                "https://bob.neocities.org/",
+                3,
+                vec![LinkKind::Hyperlink, LinkKind::Image]
+            )
+        );
+        // dave's link to oldalice counts as a link to alice
+        assert_eq!(
+            edges[3],
+            (
+                "https://dave.neocities.org/",
+                "https://alice.neocities.org/",
+                1,
+                vec![LinkKind::Hyperlink]
+            )
+        );
+        assert_eq!(edges.len(), 4);
+    }
+
+    #[test]
+    fn filters() {
+        let (nodes, edges) = graph(ExportFilter {
+            mindegree: 0,
+            crawledonly: true,
+        });
+        assert!(nodes.iter().all(|n| n.crawltime() > 0));
+        assert!(edges.iter().all(|e| !e.dsturl().contains("carol")));
+        // degrees still count the links to carol
+        assert_eq!(nodes[0].outdegree(), 2);
+
+        let (nodes, edges) = graph(ExportFilter {
+            mindegree: 2,
+            crawledonly: false,
+        });
+        let urls: Vec<&str> = nodes.iter().map(|n| n.url()).collect();
+        assert_eq!(
+            urls,
+            vec!["https://alice.neocities.org/", "https://bob.neocities.org/"]
+        );
+        assert_eq!(edges.len(), 2);
+    }
+
+    #[test]
+    fn formats() {
+        let db = database();
+        let g = SiteGraph::load(&db, &ExportFilter::default()).unwrap();
+        let write = |f: &dyn Fn(&mut Vec<u8>) -> Result<ExportCounts, ExportError>| {
+            let mut out = Vec::new();
+            f(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(write(&|w| g.write_graphml(w)), GRAPHML);
+        assert_eq!(write(&|w| g.write_gexf(w)), GEXF);
+        assert_eq!(write(&|w| g.write_dot(w)), DOT);
+        assert_eq!(write(&|w| g.write_csv_nodes(w)), NODES_CSV);
+        assert_eq!(write(&|w| g.write_csv_edges(w)), EDGES_CSV);
+        let counts = g.write_graphml(&mut Vec::new()).unwrap();
+        assert_eq!(counts, ExportCounts { nodes: 4, edges: 4 });
+
+        assert_eq!(Format::parse("gexf"), Some(Format::Gexf));
+        assert_eq!(Format::from_path(Path::new("map.GV")), Some(Format::Dot));
+        assert_eq!(Format::from_path(Path::new("map")), None);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_xml(r#"a&b<c>"d'"#), "a&amp;b&lt;c&gt;&quot;d&apos;");
+        assert_eq!(quote_dot(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+        assert_eq!(quote_csv("plain"), "plain");
+        assert_eq!(quote_csv(r#"a,"b""#), r#""a,""b""""#);
+    }
+}
@@ -0,0 +1,570 @@
+use crate::politeness::{parse_retry_after, Politeness, PolitenessConfig};
+use chrono::Utc;
+use reqwest::blocking::Client;
+use reqwest::header::{self, HeaderMap};
+use reqwest::redirect;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// The user agent that neomap identifies itself with by default.
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "neomap/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/dawnvoid/neomap)"
+);
+
+/// Settings for a `Fetcher`.
+#[derive(Debug, Clone)]
+pub struct FetcherConfig {
+    pub useragent: String,
+    pub connecttimeout: Duration, // time allowed to establish a connection
+    pub timeout: Duration,        // time allowed for the whole request, including the body
+    pub maxredirects: usize,
+    pub politeness: PolitenessConfig, // delays between requests and backoff
+}
+
+impl Default for FetcherConfig {
+    fn default() -> Self {
+        FetcherConfig {
+            useragent: String::from(DEFAULT_USER_AGENT),
+            connecttimeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            maxredirects: 10,
+            politeness: PolitenessConfig::default(),
+        }
+    }
+}
+
+/// Fetches pages over http.
+///
+/// All clones share the same underlying client (and its connection pool),
+/// so a single `Fetcher` should be created and handed to whatever needs one.
+/// They also share the same `Politeness`, so the rate limits hold across all of them.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client,
+    useragent: String,
+    maxredirects: usize,
+    politeness: Arc<Politeness>,
+    overrides: Arc<HashMap<String, Url>>, // host -> where its requests actually go
+}
+
+impl Fetcher {
+    pub fn new(config: FetcherConfig) -> Result<Fetcher, FetchError> {
+        let client = Client::builder()
+            .user_agent(&config.useragent)
+            .connect_timeout(config.connecttimeout)
+            .timeout(config.timeout)
+            .redirect(redirect::Policy::none())
+            .build()
+            .map_err(|e| FetchError::Client(e.to_string()))?;
+        Ok(Fetcher {
+            client,
+            useragent: config.useragent,
+            maxredirects: config.maxredirects,
+            politeness: Arc::new(Politeness::new(config.politeness)),
+            overrides: Arc::new(HashMap::new()),
+        })
+    }
+
+    /// Sends requests for `host` to the scheme, host and port of `target` instead
+    /// (e.g. a local mirror, or a test server standing in for a neocities site).
+    /// Urls in the response are mapped back, so nothing else notices.
+    pub fn with_host_override(mut self, host: &str, target: Url) -> Fetcher {
+        Arc::make_mut(&mut self.overrides).insert(host.to_string(), target);
+        self
+    }
+
+    /// Uses `politeness` instead of the one built from the config
+    /// (e.g. one with a mock clock).
+    pub fn with_politeness(mut self, politeness: Politeness) -> Fetcher {
+        self.politeness = Arc::new(politeness);
+        self
+    }
+
+    pub fn politeness(&self) -> &Politeness {
+        &self.politeness
+    }
+
+    /// The user agent sent with every request, also used for robots.txt matching.
+    pub fn useragent(&self) -> &str {
+        &self.useragent
+    }
+
+    /// Fetches `url`, following redirects.
+    ///
+    /// Every hop waits as long as `Politeness` says before sending its request,
+    /// and 429 and 503 responses are retried after backing off.
+    ///
+    /// Any response is `Ok`, including http errors like 404,
+    /// only failing to get a response at all is an `Err`.
+    pub fn fetch(&self, url: &Url) -> Result<FetchedPage, FetchError> {
+        self.fetch_conditional(url, &Validators::default())
+    }
+
+    /// Like `fetch()`, but sends `validators` from an earlier fetch of `url` along,
+    /// so the server can answer with a bodyless 304 if the page hasn't changed since.
+    /// They are only sent for `url` itself, not for where it redirects to.
+    pub fn fetch_conditional(
+        &self,
+        url: &Url,
+        validators: &Validators,
+    ) -> Result<FetchedPage, FetchError> {
+        let mut redirects = Vec::new();
+        let mut current = url.clone();
+        loop {
+            let conditional = if redirects.is_empty() {
+                validators
+            } else {
+                &Validators::default()
+            };
+            let mut page = self.fetch_politely(&current, conditional)?;
+            if let Some(next) = self.redirect_location(&page) {
+                if redirects.len() >= self.maxredirects {
+                    return Err(FetchError::TooManyRedirects(url.clone()));
+                }
+                redirects.push(Redirect {
+                    from: current,
+                    to: next.clone(),
+                    status: page.status,
+                });
+                current = next;
+                continue;
+            }
+            page.url = url.clone();
+            page.redirects = redirects;
+            return Ok(page);
+        }
+    }
+
+    /// Fetches `url` without following redirects, retrying if the server asks to slow down.
+    fn fetch_politely(
+        &self,
+        url: &Url,
+        validators: &Validators,
+    ) -> Result<FetchedPage, FetchError> {
+        let host = url.host_str().unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            self.politeness.wait(host);
+            let page = self.fetch_once(url, validators)?;
+            if page.status == 429 || page.status == 503 {
+                let retryafter = page
+                    .headers
+                    .get(header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| parse_retry_after(v, Utc::now()));
+                if self.politeness.backoff(host, retryafter, attempt).is_some() {
+                    attempt += 1;
+                    continue;
+                }
+            }
+            return Ok(page);
+        }
+    }
+
+    fn fetch_once(&self, url: &Url, validators: &Validators) -> Result<FetchedPage, FetchError> {
+        let target = url.host_str().and_then(|h| self.overrides.get(h));
+        let requesturl = match target {
+            Some(t) => move_url(url, t, None),
+            None => url.clone(),
+        };
+        let mut request = self.client.get(requesturl);
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(lastmodified) = &validators.lastmodified {
+            request = request.header(header::IF_MODIFIED_SINCE, lastmodified);
+        }
+        let response = request
+            .send()
+            .map_err(|e| FetchError::from_reqwest(url, e))?;
+        let fetchtime = Utc::now().timestamp();
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .map_err(|e| FetchError::from_reqwest(url, e))?
+            .to_vec();
+        Ok(FetchedPage {
+            url: url.clone(),
+            finalurl: url.clone(),
+            status,
+            headers,
+            body,
+            fetchtime,
+            redirects: Vec::new(),
+        })
+    }
+
+    /// Returns where `page` redirects to, if it is a redirect.
+    fn redirect_location(&self, page: &FetchedPage) -> Option<Url> {
+        if !matches!(page.status, 301 | 302 | 303 | 307 | 308) {
+            return None;
+        }
+        let location = page.headers.get(header::LOCATION)?.to_str().ok()?;
+        let next = page.finalurl.join(location.trim()).ok()?;
+        /* a server standing in for another host redirects to itself, not to that host */
+        let target = page.finalurl.host_str().and_then(|h| self.overrides.get(h));
+        match target {
+            Some(t) if same_origin(&next, t) => {
+                Some(move_url(&next, &page.finalurl, page.finalurl.port()))
+            }
+            _ => Some(next),
+        }
+    }
+}
+
+/// Returns `url` with the scheme and host of `to`, and `port`
+/// (or the port of `to` when `port` is `None`).
+fn move_url(url: &Url, to: &Url, port: Option<u16>) -> Url {
+    let mut moved = url.clone();
+    /* these only fail for urls that can't be a base, which `to` never is */
+    let _ = moved.set_scheme(to.scheme());
+    let _ = moved.set_host(to.host_str());
+    let _ = moved.set_port(port.or(to.port()));
+    moved
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme() && a.host_str() == b.host_str() && a.port() == b.port()
+}
+
+/// A response to a `Fetcher::fetch()`.
+#[derive(Debug, Clone)]
+pub struct FetchedPage {
+    pub url: Url,      // url that was requested
+    pub finalurl: Url, // url after following redirects
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub fetchtime: i64,           // unix timestamp of the response
+    pub redirects: Vec<Redirect>, // every redirect that was followed, in order
+}
+
+/// What a server said about the version of a page it sent,
+/// which can be sent back to ask for the page only if it changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,         // ETag header, sent back as If-None-Match
+    pub lastmodified: Option<String>, // Last-Modified header, sent back as If-Modified-Since
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.lastmodified.is_none()
+    }
+}
+
+/// One hop of a redirect chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub from: Url,
+    pub to: Url,
+    pub status: u16, // e.g. 301 or 302
+}
+
+impl FetchedPage {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn contenttype(&self) -> Option<&str> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+    }
+
+    /// Whether the server said the page hasn't changed since the validators that were sent.
+    pub fn is_not_modified(&self) -> bool {
+        self.status == 304
+    }
+
+    /// Returns the validators to send along the next time this page is fetched.
+    pub fn validators(&self) -> Validators {
+        let get = |name| {
+            self.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        Validators {
+            etag: get(header::ETAG),
+            lastmodified: get(header::LAST_MODIFIED),
+        }
+    }
+
+    /// Returns the body as text, replacing invalid utf-8.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Reasons a `Fetcher` didn't get a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    /// The http client couldn't be built (e.g. an invalid user agent).
+    Client(String),
+    /// The server took too long to connect or respond.
+    Timeout(Url),
+    /// More redirects than `FetcherConfig::maxredirects`.
+    TooManyRedirects(Url),
+    /// The server couldn't be reached.
+    Connect(Url, String),
+    /// Anything else that went wrong with the request or the body.
+    Request(Url, String),
+}
+
+impl FetchError {
+    fn from_reqwest(url: &Url, e: reqwest::Error) -> FetchError {
+        if e.is_timeout() {
+            FetchError::Timeout(url.clone())
+        } else if e.is_redirect() {
+            FetchError::TooManyRedirects(url.clone())
+        } else if e.is_connect() {
+            FetchError::Connect(url.clone(), e.to_string())
+        } else {
+            FetchError::Request(url.clone(), e.to_string())
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Client(m) => write!(f, "failed to create http client: {m}"),
+            FetchError::Timeout(u) => write!(f, r#"timed out fetching "{u}""#),
+            FetchError::TooManyRedirects(u) => write!(f, r#"too many redirects fetching "{u}""#),
+            FetchError::Connect(u, m) => write!(f, r#"failed to connect to "{u}": {m}"#),
+            FetchError::Request(u, m) => write!(f, r#"failed to fetch "{u}": {m}"#),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{FetchError, Fetcher, FetcherConfig, Redirect, Validators, DEFAULT_USER_AGENT};
+    use crate::politeness::{MockClock, Politeness, PolitenessConfig};
+    use crate::testserver::{self, Response, TestServer};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn fetch_ok() {
+        let server = TestServer::start(vec![("/", Response::html("<p>hello</p>"))]);
+        let fetcher = Fetcher::new(FetcherConfig::default()).unwrap();
+
+        let page = fetcher.fetch(&server.url("/")).unwrap();
+        assert_eq!(page.status, 200);
+        assert!(page.is_success());
+        assert_eq!(page.text(), "<p>hello</p>");
+        assert_eq!(page.contenttype(), Some("text/html; charset=utf-8"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].headers.get("user-agent").map(String::as_str),
+            Some(DEFAULT_USER_AGENT)
+        );
+    }
+
+    #[test]
+    fn fetch_not_found_is_ok() {
+        let server = TestServer::start(vec![]);
+        let fetcher = Fetcher::new(FetcherConfig::default()).unwrap();
+
+        let page = fetcher.fetch(&server.url("/missing.html")).unwrap();
+        assert_eq!(page.status, 404);
+        assert!(!page.is_success());
+    }
+
+    #[test]
+    fn fetch_follows_redirects() {
+        let server = TestServer::start(vec![
+            ("/old", Response::new(301, "").header("Location", "/new")),
+            ("/new", Response::html("new")),
+        ]);
+        let fetcher = testserver::fetcher();
+
+        let page = fetcher.fetch(&server.url("/old")).unwrap();
+        assert_eq!(page.status, 200);
+        assert_eq!(page.url, server.url("/old"));
+        assert_eq!(page.finalurl, server.url("/new"));
+        assert_eq!(
+            page.redirects,
+            vec![Redirect {
+                from: server.url("/old"),
+                to: server.url("/new"),
+                status: 301
+            }]
+        );
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec!["/old", "/new"]);
+    }
+
+    #[test]
+    fn fetch_too_many_redirects() {
+        let server = TestServer::start(vec![
+            ("/a", Response::new(302, "").header("Location", "/b")),
+            ("/b", Response::new(302, "").header("Location", "/a")),
+        ]);
+        let config = FetcherConfig {
+            maxredirects: 3,
+            politeness: PolitenessConfig::none(),
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(config).unwrap();
+
+        let result = fetcher.fetch(&server.url("/a"));
+        assert_eq!(
+            result.unwrap_err(),
+            FetchError::TooManyRedirects(server.url("/a"))
+        );
+    }
+
+    #[test]
+    fn fetch_timeout() {
+        let server = TestServer::start(vec![(
+            "/slow",
+            Response::html("zzz").delay(Duration::from_secs(2)),
+        )]);
+        let config = FetcherConfig {
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(config).unwrap();
+
+        let result = fetcher.fetch(&server.url("/slow"));
+        assert_eq!(
+            result.unwrap_err(),
+            FetchError::Timeout(server.url("/slow"))
+        );
+    }
+
+    #[test]
+    fn fetch_connection_refused() {
+        // grab a free port and close it again, so nothing is listening there
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = Url::parse(&format!("http://127.0.0.1:{port}/")).unwrap();
+        let fetcher = Fetcher::new(FetcherConfig::default()).unwrap();
+
+        assert!(matches!(
+            fetcher.fetch(&url),
+            Err(FetchError::Connect(_, _))
+        ));
+    }
+
+    fn mock_fetcher(maxretries: u32) -> (Fetcher, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new());
+        let config = PolitenessConfig {
+            hostdelay: Duration::ZERO,
+            maxrps: None,
+            maxretries,
+            backoff: Duration::from_secs(1),
+            maxbackoff: Duration::from_secs(60),
+        };
+        let fetcher = Fetcher::new(FetcherConfig::default())
+            .unwrap()
+            .with_politeness(Politeness::with_clock(config, clock.clone()));
+        (fetcher, clock)
+    }
+
+    #[test]
+    fn fetch_retries_after_429() {
+        let server = TestServer::start(vec![
+            (
+                "/",
+                Response::new(429, "slow down").header("Retry-After", "2"),
+            ),
+            ("/", Response::html("ok")),
+        ]);
+        let (fetcher, clock) = mock_fetcher(3);
+
+        let page = fetcher.fetch(&server.url("/")).unwrap();
+        assert_eq!(page.status, 200);
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(clock.elapsed(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn fetch_gives_up_on_503() {
+        let server = TestServer::start(vec![("/", Response::new(503, "down"))]);
+        let (fetcher, clock) = mock_fetcher(2);
+
+        // retried twice, backing off 1s and then 2s
+        let page = fetcher.fetch(&server.url("/")).unwrap();
+        assert_eq!(page.status, 503);
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(clock.elapsed(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn fetch_host_override() {
+        let server = TestServer::start(vec![
+            ("/old", Response::new(301, "").header("Location", "/older")),
+            ("/older", Response::new(302, "").header("Location", "new")),
+            ("/new", Response::html("new")),
+        ]);
+        let fetcher =
+            testserver::fetcher().with_host_override("dawnvoid.neocities.org", server.url("/"));
+
+        let url = Url::parse("https://dawnvoid.neocities.org/old").unwrap();
+        let page = fetcher.fetch(&url).unwrap();
+        assert_eq!(page.status, 200);
+        assert_eq!(page.url, url);
+        assert_eq!(page.finalurl.as_str(), "https://dawnvoid.neocities.org/new");
+        assert_eq!(page.redirects.len(), 2);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn fetch_conditional() {
+        let server = TestServer::start(vec![
+            (
+                "/",
+                Response::html("hello")
+                    .header("ETag", "\"v1\"")
+                    .header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ),
+            ("/", Response::new(304, "")),
+        ]);
+        let fetcher = testserver::fetcher();
+
+        let first = fetcher.fetch(&server.url("/")).unwrap();
+        assert!(!first.is_not_modified());
+        let validators = first.validators();
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+
+        let second = fetcher
+            .fetch_conditional(&server.url("/"), &validators)
+            .unwrap();
+        assert!(second.is_not_modified());
+        assert!(second.body.is_empty());
+
+        let requests = server.requests();
+        assert_eq!(requests[0].headers.get("if-none-match"), None);
+        assert_eq!(
+            requests[1].headers.get("if-none-match").map(String::as_str),
+            Some("\"v1\"")
+        );
+        assert_eq!(
+            requests[1]
+                .headers
+                .get("if-modified-since")
+                .map(String::as_str),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        assert!(Validators::default().is_empty());
+    }
+}
@@ -0,0 +1,811 @@
+//! A small, forgiving html tokenizer, and link extraction on top of it.
+//!
+//! This isn't a full html5 parser, it only understands as much html as is
+//! needed to find links: tags and their attributes, comments, raw text
+//! elements like `<script>` and `<style>`, and character references.
+
+/// A piece of an html document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    StartTag {
+        name: String,                 // lowercase
+        attrs: Vec<(String, String)>, // lowercase names, decoded values
+        selfclosing: bool,
+    },
+    EndTag {
+        name: String, // lowercase
+    },
+    /// Text outside of tags, with character references decoded.
+    Text(String),
+    /// The contents of a raw text element like `<script>` or `<style>`, as is.
+    RawText { name: String, text: String },
+}
+
+/// Elements whose contents aren't html.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title", "xmp"];
+
+/// Splits `html` into tokens. Comments, doctypes and processing instructions are skipped.
+pub fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let bytes = html.as_bytes();
+    let mut pos = 0;
+    let mut textstart = 0;
+
+    while pos < bytes.len() {
+        if bytes[pos] != b'<' {
+            pos += 1;
+            continue;
+        }
+        let next = bytes.get(pos + 1).copied().unwrap_or(b' ');
+
+        if html[pos..].starts_with("<!--") {
+            push_text(&mut tokens, &html[textstart..pos]);
+            pos = match html[pos + 4..].find("-->") {
+                Some(end) => pos + 4 + end + 3,
+                None => bytes.len(),
+            };
+            textstart = pos;
+        } else if next == b'!' || next == b'?' {
+            /* doctype, cdata or processing instruction */
+            push_text(&mut tokens, &html[textstart..pos]);
+            pos = match html[pos..].find('>') {
+                Some(end) => pos + end + 1,
+                None => bytes.len(),
+            };
+            textstart = pos;
+        } else if next == b'/' && bytes.get(pos + 2).is_some_and(|b| b.is_ascii_alphabetic()) {
+            push_text(&mut tokens, &html[textstart..pos]);
+            let (name, after) = read_tag_name(html, pos + 2);
+            pos = match html[after..].find('>') {
+                Some(end) => after + end + 1,
+                None => bytes.len(),
+            };
+            textstart = pos;
+            tokens.push(Token::EndTag { name });
+        } else if next.is_ascii_alphabetic() {
+            push_text(&mut tokens, &html[textstart..pos]);
+            let (name, after) = read_tag_name(html, pos + 1);
+            let (attrs, selfclosing, end) = read_attributes(html, after);
+            pos = end;
+            textstart = pos;
+
+            let israw = RAW_TEXT_ELEMENTS.contains(&name.as_str());
+            tokens.push(Token::StartTag {
+                name: name.clone(),
+                attrs,
+                selfclosing,
+            });
+
+            if israw && !selfclosing {
+                /* everything up to the matching end tag is plain text */
+                let close = format!("</{name}");
+                let rawend = find_ascii_case_insensitive(html, pos, &close).unwrap_or(bytes.len());
+                if rawend > pos {
+                    tokens.push(Token::RawText {
+                        name,
+                        text: html[pos..rawend].to_string(),
+                    });
+                }
+                pos = rawend;
+                textstart = pos;
+            }
+        } else {
+            /* a lone '<' is just text */
+            pos += 1;
+        }
+    }
+    push_text(&mut tokens, &html[textstart..]);
+    tokens
+}
+
+fn push_text(tokens: &mut Vec<Token>, text: &str) {
+    if !text.is_empty() {
+        tokens.push(Token::Text(decode_entities(text)));
+    }
+}
+
+/// Reads a tag name starting at `pos`, returns it lowercased along with the position after it.
+fn read_tag_name(html: &str, pos: usize) -> (String, usize) {
+    let bytes = html.as_bytes();
+    let mut end = pos;
+    while end < bytes.len() && !is_space(bytes[end]) && bytes[end] != b'/' && bytes[end] != b'>' {
+        end += 1;
+    }
+    (html[pos..end].to_ascii_lowercase(), end)
+}
+
+/// Reads attributes up to and including the closing '>'.
+/// Returns the attributes, whether the tag was self-closing, and the position after the tag.
+fn read_attributes(html: &str, mut pos: usize) -> (Vec<(String, String)>, bool, usize) {
+    let bytes = html.as_bytes();
+    let mut attrs = Vec::new();
+    let mut selfclosing = false;
+
+    loop {
+        while pos < bytes.len() && is_space(bytes[pos]) {
+            pos += 1;
+        }
+        if pos >= bytes.len() {
+            return (attrs, selfclosing, pos);
+        }
+        match bytes[pos] {
+            b'>' => return (attrs, selfclosing, pos + 1),
+            b'/' => {
+                selfclosing = true;
+                pos += 1;
+                continue;
+            }
+            _ => selfclosing = false,
+        }
+
+        /* attribute name */
+        let namestart = pos;
+        while pos < bytes.len()
+            && !is_space(bytes[pos])
+            && !matches!(bytes[pos], b'=' | b'>' | b'/')
+        {
+            pos += 1;
+        }
+        /* a name can't be empty, so a stray '=' becomes part of the name */
+        if pos == namestart {
+            pos += 1;
+        }
+        let name = html[namestart..pos].to_ascii_lowercase();
+
+        while pos < bytes.len() && is_space(bytes[pos]) {
+            pos += 1;
+        }
+        if bytes.get(pos) != Some(&b'=') {
+            attrs.push((name, String::new()));
+            continue;
+        }
+        pos += 1;
+        while pos < bytes.len() && is_space(bytes[pos]) {
+            pos += 1;
+        }
+
+        /* attribute value: double quoted, single quoted or unquoted */
+        let value = match bytes.get(pos) {
+            Some(&q) if q == b'"' || q == b'\'' => {
+                let start = pos + 1;
+                let end = html[start..]
+                    .find(q as char)
+                    .map_or(bytes.len(), |e| start + e);
+                pos = (end + 1).min(bytes.len());
+                &html[start..end]
+            }
+            _ => {
+                let start = pos;
+                while pos < bytes.len() && !is_space(bytes[pos]) && bytes[pos] != b'>' {
+                    pos += 1;
+                }
+                &html[start..pos]
+            }
+        };
+        attrs.push((name, decode_entities(value)));
+    }
+}
+
+fn is_space(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r' | b'\x0c')
+}
+
+fn find_ascii_case_insensitive(haystack: &str, from: usize, needle: &str) -> Option<usize> {
+    let h = haystack.as_bytes();
+    let n = needle.as_bytes();
+    (from..h.len().saturating_sub(n.len() - 1)).find(|&i| h[i..i + n.len()].eq_ignore_ascii_case(n))
+}
+
+/// Replaces character references like `&amp;`, `&#39;` and `&#x2F;` with the characters they stand for.
+/// Unknown references are left alone.
+pub fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let (decoded, len) = decode_entity(rest);
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decodes the reference at the start of `text` (which starts with '&').
+/// Returns the character and the length of the reference.
+fn decode_entity(text: &str) -> (Option<char>, usize) {
+    let bytes = text.as_bytes();
+
+    if bytes.get(1) == Some(&b'#') {
+        let (radix, start) = match bytes.get(2) {
+            Some(b'x') | Some(b'X') => (16, 3),
+            _ => (10, 2),
+        };
+        let mut end = start;
+        while end < bytes.len() && (bytes[end] as char).is_digit(radix) {
+            end += 1;
+        }
+        if end == start {
+            return (None, 0);
+        }
+        let c = u32::from_str_radix(&text[start..end], radix)
+            .ok()
+            .and_then(char::from_u32)
+            .unwrap_or('\u{fffd}');
+        let len = if bytes.get(end) == Some(&b';') {
+            end + 1
+        } else {
+            end
+        };
+        return (Some(c), len);
+    }
+
+    let mut end = 1;
+    while end < bytes.len() && bytes[end].is_ascii_alphanumeric() {
+        end += 1;
+    }
+    let c = match &text[1..end] {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "sol" => '/',
+        "colon" => ':',
+        "equals" => '=',
+        "quest" => '?',
+        "num" => '#',
+        "percnt" => '%',
+        "period" => '.',
+        "lowbar" => '_',
+        "commat" => '@',
+        _ => return (None, 0),
+    };
+    let len = if bytes.get(end) == Some(&b';') {
+        end + 1
+    } else {
+        end
+    };
+    (Some(c), len)
+}
+
+/// A link found in an html document, before it is resolved against a base url.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtmlLink {
+    pub element: String,      // e.g. "a", or "style" for links in a <style> element
+    pub attribute: String,    // e.g. "href", or "" for links in a <style> element
+    pub value: String,        // the url as written in the document, decoded
+    pub text: Option<String>, // anchor text of <a>, or alt text of <img> and <area>
+    pub rel: Vec<String>,     // lowercase values of the rel attribute
+}
+
+/// Element and attribute pairs whose value is a single url.
+const URL_ATTRIBUTES: &[(&str, &str)] = &[
+    ("a", "href"),
+    ("area", "href"),
+    ("link", "href"),
+    ("img", "src"),
+    ("iframe", "src"),
+    ("frame", "src"),
+    ("embed", "src"),
+    ("script", "src"),
+    ("source", "src"),
+    ("track", "src"),
+    ("video", "src"),
+    ("video", "poster"),
+    ("audio", "src"),
+    ("input", "src"),
+    ("object", "data"),
+    ("body", "background"),
+    ("table", "background"),
+    ("td", "background"),
+];
+
+/// Finds every link in `html`.
+///
+/// Returns the value of the document's `<base href>` (if there is one),
+/// which is what relative links should be resolved against,
+/// along with the links in the order they appear.
+pub fn extract_links(html: &str) -> (Option<String>, Vec<HtmlLink>) {
+    let mut base: Option<String> = None;
+    let mut links = Vec::new();
+
+    /* the <a> whose anchor text is being collected: (index of its first link, text so far) */
+    let mut anchor: Option<(usize, String)> = None;
+
+    for token in tokenize(html) {
+        match token {
+            Token::StartTag { name, attrs, .. } => {
+                if name == "a" {
+                    finish_anchor(&mut links, anchor.take());
+                    anchor = Some((links.len(), String::new()));
+                }
+                if name == "base" && base.is_none() {
+                    base = get_attr(&attrs, "href").map(|h| h.trim().to_string());
+                }
+
+                let alt = get_attr(&attrs, "alt").map(collapse_whitespace);
+                if name == "img" {
+                    /* an image inside a link stands in for the link's text */
+                    if let (Some((_, t)), Some(a)) = (anchor.as_mut(), alt.as_ref()) {
+                        t.push(' ');
+                        t.push_str(a);
+                    }
+                }
+                let text = match name.as_str() {
+                    "img" | "area" => alt.filter(|a| !a.is_empty()),
+                    _ => None,
+                };
+                let rel: Vec<String> = get_attr(&attrs, "rel")
+                    .map(|r| r.split_whitespace().map(str::to_ascii_lowercase).collect())
+                    .unwrap_or_default();
+
+                let mut push = |attr: &str, value: &str| {
+                    push_link(&mut links, &name, attr, value, text.clone(), rel.clone())
+                };
+                for (attr, value) in &attrs {
+                    if URL_ATTRIBUTES.contains(&(name.as_str(), attr.as_str())) {
+                        push(attr, value);
+                    } else if attr == "srcset" {
+                        for u in parse_srcset(value) {
+                            push(attr, &u);
+                        }
+                    } else if attr == "style" {
+                        for u in parse_css_urls(value) {
+                            push(attr, &u);
+                        }
+                    }
+                }
+
+                if name == "meta"
+                    && get_attr(&attrs, "http-equiv")
+                        .is_some_and(|h| h.eq_ignore_ascii_case("refresh"))
+                {
+                    if let Some(u) = get_attr(&attrs, "content").and_then(parse_meta_refresh) {
+                        push("content", &u);
+                    }
+                }
+            }
+            Token::EndTag { name } if name == "a" => {
+                finish_anchor(&mut links, anchor.take());
+            }
+            Token::Text(text) => {
+                if let Some((_, t)) = anchor.as_mut() {
+                    t.push_str(&text);
+                }
+            }
+            Token::RawText { name, text } if name == "style" => {
+                for u in parse_css_urls(&text) {
+                    push_link(&mut links, &name, "", &u, None, Vec::new());
+                }
+            }
+            Token::RawText { name, text } if name == "script" => {
+                for u in parse_script_redirects(&text) {
+                    push_link(&mut links, &name, "location", &u, None, Vec::new());
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_anchor(&mut links, anchor);
+    (base, links)
+}
+
+/// Gives the collected anchor text to the links of an <a> element.
+fn finish_anchor(links: &mut [HtmlLink], anchor: Option<(usize, String)>) {
+    let Some((start, text)) = anchor else { return };
+    let text = collapse_whitespace(&text);
+    if text.is_empty() {
+        return;
+    }
+    for l in links[start..].iter_mut().filter(|l| l.element == "a") {
+        l.text = Some(text.clone());
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn push_link(
+    links: &mut Vec<HtmlLink>,
+    element: &str,
+    attribute: &str,
+    value: &str,
+    text: Option<String>,
+    rel: Vec<String>,
+) {
+    let value = value.trim();
+    if value.is_empty() {
+        return;
+    }
+    links.push(HtmlLink {
+        element: element.to_string(),
+        attribute: attribute.to_string(),
+        value: value.to_string(),
+        text,
+        rel,
+    });
+}
+
+pub fn get_attr<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Returns the urls of a `srcset` attribute, e.g. "a.png 1x, b.png 2x" -> ["a.png", "b.png"].
+pub fn parse_srcset(srcset: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = srcset.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    while !rest.is_empty() {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let mut url = &rest[..end];
+        rest = &rest[end..];
+        if url.ends_with(',') {
+            /* no descriptor */
+            url = url.trim_end_matches(',');
+        } else {
+            /* skip the descriptor */
+            rest = match rest.find(',') {
+                Some(c) => &rest[c..],
+                None => "",
+            };
+        }
+        if !url.is_empty() {
+            urls.push(url.to_string());
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    }
+    urls
+}
+
+/// Returns the target of a `<meta http-equiv="refresh">` content attribute,
+/// e.g. "0; url=https://example.org/" -> "https://example.org/".
+pub fn parse_meta_refresh(content: &str) -> Option<String> {
+    let (_, target) = content.split_once([';', ','])?;
+    let target = target.trim();
+    let target = match target.get(..4) {
+        Some(p) if p.eq_ignore_ascii_case("url=") => target[4..].trim(),
+        _ => target,
+    };
+    let target = target.trim_matches(|c| c == '"' || c == '\'').trim();
+    if target.is_empty() {
+        return None;
+    }
+    Some(target.to_string())
+}
+
+/// Returns the urls that a script sends the browser to, from stubs like
+/// `window.location.href = "new.html"` or `location.replace('new.html')`.
+/// Only string literals are understood, anything computed is ignored.
+pub fn parse_script_redirects(js: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = js[pos..].find("location") {
+        let start = pos + start;
+        pos = start + 8;
+        /* skip things like `mylocation` */
+        if js[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$')
+        {
+            continue;
+        }
+
+        let mut rest = js[pos..].trim_start();
+        if let Some(r) = rest.strip_prefix(".href") {
+            rest = r.trim_start();
+        }
+        let rest = if let Some(r) = rest.strip_prefix('=') {
+            if r.starts_with('=') {
+                /* a comparison */
+                continue;
+            }
+            r
+        } else if let Some(r) = rest
+            .strip_prefix(".replace(")
+            .or_else(|| rest.strip_prefix(".assign("))
+        {
+            r
+        } else {
+            continue;
+        };
+
+        let rest = rest.trim_start();
+        if let Some(q) = rest
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'' || c == '`')
+        {
+            if let Some(end) = rest[1..].find(q) {
+                let u = rest[1..1 + end].trim();
+                if !u.is_empty() && !u.contains("${") {
+                    urls.push(u.to_string());
+                }
+            }
+        }
+    }
+    urls
+}
+
+/// Returns the urls in a piece of css, from `url(...)` and `@import "..."`.
+pub fn parse_css_urls(css: &str) -> Vec<String> {
+    /* comments could hide anything, get rid of them first */
+    let mut clean = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        clean.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    clean.push_str(rest);
+
+    let mut urls = Vec::new();
+    let lower = clean.to_ascii_lowercase();
+
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("url(") {
+        let start = pos + start + 4;
+        let end = match clean[start..].find(')') {
+            Some(e) => start + e,
+            None => break,
+        };
+        let u = clean[start..end]
+            .trim()
+            .trim_matches(|c| c == '"' || c == '\'')
+            .trim();
+        if !u.is_empty() {
+            urls.push(u.to_string());
+        }
+        pos = end;
+    }
+
+    /* @import url(...) was already handled above */
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("@import") {
+        let start = pos + start + 7;
+        let rest = clean[start..].trim_start();
+        if let Some(q) = rest.chars().next().filter(|&c| c == '"' || c == '\'') {
+            if let Some(end) = rest[1..].find(q) {
+                urls.push(rest[1..1 + end].to_string());
+            }
+        }
+        pos = start;
+    }
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_entities, extract_links, parse_meta_refresh, parse_script_redirects, parse_srcset,
+        tokenize, Token,
+    };
+
+    fn values(html: &str) -> Vec<String> {
+        extract_links(html).1.into_iter().map(|l| l.value).collect()
+    }
+
+    #[test]
+    fn tokenize_attributes() {
+        let tokens = tokenize(r#"<A HREF='a.html' data-x=1 hidden title="x &amp; y">hi</a>"#);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::StartTag {
+                    name: String::from("a"),
+                    attrs: vec![
+                        (String::from("href"), String::from("a.html")),
+                        (String::from("data-x"), String::from("1")),
+                        (String::from("hidden"), String::new()),
+                        (String::from("title"), String::from("x & y")),
+                    ],
+                    selfclosing: false,
+                },
+                Token::Text(String::from("hi")),
+                Token::EndTag {
+                    name: String::from("a")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        let html = r#"<a href="double.html">1</a> <a href='single.html'>2</a> <a href=unquoted.html>3</a>
+            <a href = "spaced.html">4</a>"#;
+        assert_eq!(
+            values(html),
+            vec!["double.html", "single.html", "unquoted.html", "spaced.html"]
+        );
+    }
+
+    #[test]
+    fn ignores_comments_and_scripts() {
+        let html = r#"
+            <!-- <a href="commented.html">old</a> -->
+            <script>document.write('<a href="scripted.html">x</a>');</script>
+            <script src="real.js"></script>
+            <p>href="text.html"</p>
+            <a href="real.html">real</a>"#;
+        assert_eq!(values(html), vec!["real.js", "real.html"]);
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(decode_entities("a&amp;b&#38;c&#x26;d&lt;"), "a&b&c&d<");
+        assert_eq!(decode_entities("&unknown; & &"), "&unknown; & &");
+        assert_eq!(
+            values(r#"<a href="page?a=1&amp;b=2">x</a>"#),
+            vec!["page?a=1&b=2"]
+        );
+    }
+
+    #[test]
+    fn base_href() {
+        let (base, links) = extract_links(
+            r#"<head><base href="https://example.org/dir/"></head><a href="x.html">"#,
+        );
+        assert_eq!(base.as_deref(), Some("https://example.org/dir/"));
+        assert_eq!(links.len(), 1);
+    }
+
+    #[test]
+    fn element_and_attribute() {
+        let html = r#"
+            <link rel="stylesheet" href="style.css">
+            <img src="cat.png" srcset="cat-2x.png 2x, cat-3x.png 3x">
+            <iframe src="frame.html"></iframe>
+            <map><area href="area.html"></map>
+            <picture><source srcset="a.webp, b.webp 800w"></picture>
+            <meta http-equiv="Refresh" content="0; URL='moved.html'">
+            <div style="background: url(&quot;bg.gif&quot;)"></div>
+            <style>body { background: url('/tile.png'); } /* url(nope.png) */ @import "more.css";</style>"#;
+        let links: Vec<(String, String, String)> = extract_links(html)
+            .1
+            .into_iter()
+            .map(|l| (l.element, l.attribute, l.value))
+            .collect();
+        let expected = vec![
+            ("link", "href", "style.css"),
+            ("img", "src", "cat.png"),
+            ("img", "srcset", "cat-2x.png"),
+            ("img", "srcset", "cat-3x.png"),
+            ("iframe", "src", "frame.html"),
+            ("area", "href", "area.html"),
+            ("source", "srcset", "a.webp"),
+            ("source", "srcset", "b.webp"),
+            ("meta", "content", "moved.html"),
+            ("div", "style", "bg.gif"),
+            ("style", "", "/tile.png"),
+            ("style", "", "more.css"),
+        ];
+        let expected: Vec<(String, String, String)> = expected
+            .into_iter()
+            .map(|(e, a, v)| (e.to_string(), a.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(links, expected);
+    }
+
+    #[test]
+    fn text_and_rel() {
+        let html = r#"
+            <a href="a.html" rel="me NoFollow">my   <b>other</b>
+                site</a>
+            <a href="b.html"><img src="button.gif" alt="cool button"></a>
+            <map><area href="c.html" alt="door"></map>
+            <a href="d.html"></a>"#;
+        let links: Vec<(String, Option<String>, Vec<String>)> = extract_links(html)
+            .1
+            .into_iter()
+            .map(|l| (l.value, l.text, l.rel))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (
+                    String::from("a.html"),
+                    Some(String::from("my other site")),
+                    vec![String::from("me"), String::from("nofollow")]
+                ),
+                (
+                    String::from("b.html"),
+                    Some(String::from("cool button")),
+                    vec![]
+                ),
+                (
+                    String::from("button.gif"),
+                    Some(String::from("cool button")),
+                    vec![]
+                ),
+                (String::from("c.html"), Some(String::from("door")), vec![]),
+                (String::from("d.html"), None, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn srcset() {
+        assert_eq!(parse_srcset("a.png"), vec!["a.png"]);
+        assert_eq!(parse_srcset(" a.png 1x ,b.png 2x"), vec!["a.png", "b.png"]);
+        assert_eq!(parse_srcset("a.png,b.png"), vec!["a.png,b.png"]);
+    }
+
+    #[test]
+    fn meta_refresh() {
+        assert_eq!(
+            parse_meta_refresh("0; url=https://example.org/").as_deref(),
+            Some("https://example.org/")
+        );
+        assert_eq!(
+            parse_meta_refresh("5;URL=\"new.html\"").as_deref(),
+            Some("new.html")
+        );
+        assert_eq!(
+            parse_meta_refresh("3, other.html").as_deref(),
+            Some("other.html")
+        );
+        assert_eq!(parse_meta_refresh("30"), None);
+    }
+
+    #[test]
+    fn unterminated() {
+        // broken html shouldn't panic
+        tokenize("<a href=\"x");
+        tokenize("<a href='x' <b");
+        tokenize("<!-- forever");
+        tokenize("<script>no end");
+        tokenize("a < b > c </");
+        tokenize("&#xffffffff; &#");
+        tokenize("<é>ü</é>");
+    }
+
+    #[test]
+    fn script_redirects() {
+        assert_eq!(
+            parse_script_redirects(r#"window.location.href = "https://koyo.neocities.org/";"#),
+            vec!["https://koyo.neocities.org/"]
+        );
+        assert_eq!(
+            parse_script_redirects(
+                "document.location='new.html'; top.location.replace(`other.html`)"
+            ),
+            vec!["new.html", "other.html"]
+        );
+        assert_eq!(
+            parse_script_redirects("location.assign( 'a.html' )"),
+            vec!["a.html"]
+        );
+        // not redirects
+        assert!(parse_script_redirects(r#"if (location == "x") {}"#).is_empty());
+        assert!(parse_script_redirects(r#"mylocation = "x.html""#).is_empty());
+        assert!(parse_script_redirects("location.href = base + '/x.html'").is_empty());
+        assert!(parse_script_redirects("location.href = `${base}/x.html`").is_empty());
+
+        let (_, links) = extract_links(r#"<script>window.location = "moved.html";</script>"#);
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].element, "script");
+        assert_eq!(links[0].attribute, "location");
+        assert_eq!(links[0].value, "moved.html");
+    }
+}
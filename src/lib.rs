@@ -1,15 +1,44 @@
+pub mod analysis;
+pub mod canonical;
+pub mod cli;
+pub mod community;
+pub mod database;
+pub mod export;
+pub mod fetcher;
+pub mod html;
+mod migration;
 pub mod neocrawler;
 pub mod page;
 pub mod pagecrawler;
+pub mod path;
+pub mod politeness;
+pub mod robots;
+pub mod scheduler;
+pub mod seed;
+pub mod site;
+#[cfg(test)]
+mod testgraph;
+#[cfg(test)]
+mod testserver;
 
+use site::{SiteId, SiteResolver};
 use url::Url;
 
-// url must have a domain!
+/// Returns whether `url` is on a neocities.org subdomain.
 pub fn is_in_domain(url: &Url) -> bool {
-    url.domain().unwrap().ends_with(".neocities.org")
+    url.domain()
+        .is_some_and(|d| d.to_ascii_lowercase().ends_with(".neocities.org"))
 }
 
-// url and siteurl must have a domain!
+/// Returns whether `url` is on the same site as `siteurl`
+/// (see `SiteResolver::is_in_site()`, this one doesn't know about custom domains).
 pub fn is_in_site(url: &Url, siteurl: &Url) -> bool {
-    url.domain() == siteurl.domain()
+    SiteResolver::new().is_in_site(url, siteurl)
+}
+
+/// Returns the root url of the neocities site that `url` belongs to
+/// (e.g. "https://kryptonaut.neocities.org/about.html" -> "https://kryptonaut.neocities.org/"),
+/// or `None` if `url` isn't on a neocities site.
+pub fn get_site_url(url: &Url) -> Option<Url> {
+    SiteId::from_url(url).map(|s| s.url())
 }
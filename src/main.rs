@@ -1,76 +1,1114 @@
-mod database;
 use chrono::Utc;
-use database::{Database, LinkEntry, SiteEntry};
-use std::path::Path;
+use neomap::analysis::{self, AnalysisConfig, Dangling, LinkGraph, Metric};
+use neomap::cli::{parse_rate, parse_wait, OptionArg};
+use neomap::community;
+use neomap::database::{Database, LinkEntry, SiteEntry};
+use neomap::export::{ExportCounts, ExportError, ExportFilter, Format, SiteGraph};
+use neomap::fetcher::{Fetcher, FetcherConfig};
+use neomap::neocrawler::NeoCrawler;
+use neomap::path::PathFinder;
+use neomap::seed;
+use neomap::site::SiteId;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 use url::Url;
 
-fn add_site(db: &Database, url: &str, crawltime: i64) {
-    let url = Url::parse(url).unwrap();
-    let s = SiteEntry::new(url, crawltime).unwrap();
-    db.set_site(s).unwrap();
+const HELP: &str = "\
+neomap - map the links between neocities sites
+
+Usage:
+  neomap [--db <path>] <command> [args]
+
+Commands:
+  init                   create the database, or bring an existing one up to date
+  seed <site|file>...    add sites to crawl from a site (and its sitemaps) or a file listing sites
+  crawl [options]        crawl the sites that are due
+  schedule [count]       print the next sites to be crawled (default 20)
+  status                 print what the database holds
+  links <site>           print the sites a site links to
+  backlinks <site>       print the sites that link to a site
+  export [options]       write the site graph for other tools (csv edges on stdout by default)
+  analyze [options]      score every site by its place in the site graph and save the scores
+  rank [count]           print the sites with the highest scores (default 20)
+  cluster [count]        group sites into communities and components, save them and print
+                         the largest communities (default 20)
+  webrings               print the webrings found in the links between sites
+  path <from> <to>       print how one site reaches another through links, and the pages
+                         holding each link
+  help                   print this help
+
+Crawl options:
+  -n, --sites <count>    stop after crawling <count> sites
+  -t, --duration <time>  stop starting on new sites after <time> (e.g. 90s, 30m, 2h, 1d)
+  -j, --workers <count>  crawl <count> sites at the same time (default 4)
+  -w, --wait <seconds>   seconds to wait between requests to the same host (default 1)
+  -q, --rate <rps>       requests per second over all hosts, 0 for no limit (default 5)
+
+Export options:
+  -f, --format <format>  graphml, gexf, dot or csv (default from the output's extension, or csv)
+  -o, --output <path>    write to <path> instead of stdout; csv writes <path>.nodes.csv and
+                         <path>.edges.csv
+      --min-degree <n>   leave out sites with fewer than <n> links in and out
+      --crawled          leave out sites that were never crawled
+
+Analyze options:
+      --damping <d>      pagerank damping factor between 0 and 1 (default 0.85)
+      --dangling <how>   what sites without links do with their pagerank: distribute it
+                         over every site or give it back to the sites linking to them
+                         (default distribute)
+      --samples <count>  sites to sample betweenness from (default 200)
+
+Rank options:
+      --by <score>       pagerank, hub, authority, indegree, outdegree or betweenness
+                         (default pagerank)
+
+Path options:
+  -k, --paths <count>    print the <count> shortest paths instead of just one
+      --max-hops <n>     only look for paths of up to <n> links
+
+Options:
+      --db <path>        database file (default neomap.db)
+  -h, --help             print this help
+
+Sites can be given as a url, a domain or just a name (e.g. dawnvoid).
+Exit status is 0 on success, 1 if something went wrong, and 2 for invalid arguments.";
+
+/// What the arguments ask for.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Init,
+    Seed(Vec<String>),
+    Crawl(CrawlOptions),
+    Schedule(usize),
+    Status,
+    Links(String),
+    Backlinks(String),
+    Export(ExportOptions),
+    Analyze(AnalysisConfig),
+    Rank(Metric, usize),
+    Cluster(usize),
+    Webrings,
+    Path(PathOptions),
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct CrawlOptions {
+    maxsites: Option<usize>,
+    duration: Option<Duration>,
+    workers: Option<usize>,
+    wait: Option<Duration>,    // between requests to the same host
+    rate: Option<Option<f64>>, // requests per second, `Some(None)` for no limit
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ExportOptions {
+    format: Option<Format>,
+    output: Option<PathBuf>,
+    filter: ExportFilter,
 }
 
-fn add_link(db: &Database, srcurl: &str, dsturl: &str) {
-    let src = Url::parse(srcurl).unwrap();
-    let dst = Url::parse(dsturl).unwrap();
-    let l = LinkEntry::new(src, dst).unwrap();
-    db.set_link(l).unwrap();
+#[derive(Debug, PartialEq, Eq)]
+struct PathOptions {
+    from: String,
+    to: String,
+    paths: usize,
+    maxhops: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Options {
+    dbpath: PathBuf,
+    command: Command,
 }
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(o) => o,
+        Err(e) => fail(&e),
+    };
+    if options.command == Command::Help {
+        let _ = writeln!(io::stdout(), "{HELP}");
+        return;
+    }
+
+    /* only init creates a database, a mistyped --db shouldn't leave an empty one behind */
+    if options.command != Command::Init && !options.dbpath.exists() {
+        eprintln!(
+            "neomap: {} doesn't exist, create it with \"neomap init\"",
+            options.dbpath.display()
+        );
+        process::exit(1);
+    }
+    let db = match Database::connect(&options.dbpath) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("neomap: {}: {e}", options.dbpath.display());
+            process::exit(1);
+        }
+    };
+
+    let result = match options.command {
+        Command::Help => Ok(()),
+        Command::Init => init(&db, &options.dbpath),
+        Command::Seed(sources) => add_seeds(&db, &sources),
+        Command::Crawl(c) => crawl(db, &c),
+        Command::Schedule(count) => schedule(&db, count),
+        Command::Status => status(&db, &options.dbpath),
+        Command::Links(site) => links(&db, &site),
+        Command::Backlinks(site) => backlinks(&db, &site),
+        Command::Export(e) => export(&db, &e),
+        Command::Analyze(config) => analyze(&db, &config),
+        Command::Rank(metric, count) => rank(&db, metric, count),
+        Command::Cluster(count) => cluster(&db, count),
+        Command::Webrings => webrings(&db),
+        Command::Path(p) => path(&db, &p),
+    };
+    if let Err(e) = result {
+        eprintln!("neomap: {e}");
+        process::exit(1);
+    }
+}
+
+/// Prints `message` and the usage hint, then exits with status 2.
+fn fail(message: &str) -> ! {
+    eprintln!("neomap: {message}");
+    eprintln!("try \"neomap --help\" for more information");
+    process::exit(2);
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut dbpath = PathBuf::from("neomap.db");
+    let mut words: Vec<String> = Vec::new();
+    let mut crawloptions = CrawlOptions::default();
+    let mut exportoptions = ExportOptions::default();
+    let mut analysisconfig = AnalysisConfig::default();
+    let mut metric = Metric::PageRank;
+    let mut paths = 1;
+    let mut maxhops = None;
+    /* the first option that only works with one command, for complaining about */
+    let mut commandflag: Option<(String, &str)> = None;
+
+    let mut args = args.iter();
+    let mut only_words = false; /* after "--", everything is an argument */
+    while let Some(arg) = args.next() {
+        if only_words || arg == "-" || !arg.starts_with('-') {
+            words.push(arg.clone());
+            continue;
+        }
+        if arg == "--" {
+            only_words = true;
+            continue;
+        }
+
+        /* options can have their value in the next argument, after "=" or glued on (-n5) */
+        let option = OptionArg::parse(arg);
+        let name = option.name;
+        let mut value = || option.value(&mut args);
+
+        match name {
+            "-h" | "--help" => {
+                return Ok(Options {
+                    dbpath,
+                    command: Command::Help,
+                })
+            }
+            "--db" => dbpath = PathBuf::from(value()?),
+            "-n" | "--sites" => {
+                let v = value()?;
+                let n = v
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid number of sites \"{v}\""))?;
+                crawloptions.maxsites = Some(n);
+            }
+            "-t" | "--duration" => {
+                let v = value()?;
+                let d = parse_duration(&v).ok_or_else(|| format!("invalid duration \"{v}\""))?;
+                crawloptions.duration = Some(d);
+            }
+            "-j" | "--workers" => {
+                let v = value()?;
+                match v.parse::<usize>() {
+                    Ok(n) if n > 0 => crawloptions.workers = Some(n),
+                    _ => return Err(format!("invalid number of workers \"{v}\"")),
+                }
+            }
+            "-w" | "--wait" => crawloptions.wait = Some(parse_wait(&value()?)?),
+            "-q" | "--rate" => crawloptions.rate = Some(parse_rate(&value()?)?),
+            "-f" | "--format" => {
+                let v = value()?;
+                let f = Format::parse(&v).ok_or_else(|| format!("unknown format \"{v}\""))?;
+                exportoptions.format = Some(f);
+            }
+            "-o" | "--output" => exportoptions.output = Some(PathBuf::from(value()?)),
+            "--min-degree" => {
+                let v = value()?;
+                let n = v
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid degree \"{v}\""))?;
+                exportoptions.filter.mindegree = n;
+            }
+            "--crawled" => exportoptions.filter.crawledonly = true,
+            "--damping" => {
+                let v = value()?;
+                match v.parse::<f64>() {
+                    Ok(d) if (0.0..1.0).contains(&d) => analysisconfig.pagerank.damping = d,
+                    _ => return Err(format!("invalid damping \"{v}\"")),
+                }
+            }
+            "--dangling" => {
+                let v = value()?;
+                analysisconfig.pagerank.dangling = match v.as_str() {
+                    "distribute" => Dangling::Distribute,
+                    "back" => Dangling::Back,
+                    _ => return Err(format!("invalid dangling \"{v}\"")),
+                };
+            }
+            "--samples" => {
+                let v = value()?;
+                analysisconfig.samples = v
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid number of samples \"{v}\""))?;
+            }
+            "--by" => {
+                let v = value()?;
+                metric = Metric::parse(&v).ok_or_else(|| format!("unknown score \"{v}\""))?;
+            }
+            "-k" | "--paths" => {
+                let v = value()?;
+                match v.parse::<usize>() {
+                    Ok(n) if n > 0 => paths = n,
+                    _ => return Err(format!("invalid number of paths \"{v}\"")),
+                }
+            }
+            "--max-hops" => {
+                let v = value()?;
+                let n = v
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid number of hops \"{v}\""))?;
+                maxhops = Some(n);
+            }
+            _ => return Err(format!("unknown option \"{arg}\"")),
+        }
+        let owner = match name {
+            "-n" | "--sites" | "-t" | "--duration" | "-j" | "--workers" | "-w" | "--wait"
+            | "-q" | "--rate" => Some("crawl"),
+            "-f" | "--format" | "-o" | "--output" | "--min-degree" | "--crawled" => Some("export"),
+            "--damping" | "--dangling" | "--samples" => Some("analyze"),
+            "--by" => Some("rank"),
+            "-k" | "--paths" | "--max-hops" => Some("path"),
+            _ => None,
+        };
+        if let (Some(owner), None) = (owner, &commandflag) {
+            commandflag = Some((name.to_string(), owner));
+        }
+    }
+
+    let mut words = words.into_iter();
+    let name = words.next();
+    let rest: Vec<String> = words.collect();
+    let site = |rest: &[String]| match rest {
+        [site] => Ok(site.clone()),
+        [] => Err(String::from("no site given")),
+        _ => Err(String::from("only one site can be given")),
+    };
+    let count = |rest: &[String]| match rest.first() {
+        None => Ok(20),
+        Some(c) => c
+            .parse::<usize>()
+            .map_err(|_| format!("invalid count \"{c}\"")),
+    };
+    let command = match name.as_deref() {
+        None | Some("help") => Command::Help,
+        Some("init") => Command::Init,
+        Some("seed") if rest.is_empty() => return Err(String::from("nothing to seed from")),
+        Some("seed") => Command::Seed(rest.clone()),
+        Some("crawl") => Command::Crawl(crawloptions),
+        Some("schedule") => Command::Schedule(count(&rest)?),
+        Some("status") => Command::Status,
+        Some("links") => Command::Links(site(&rest)?),
+        Some("backlinks") => Command::Backlinks(site(&rest)?),
+        Some("export") => Command::Export(exportoptions),
+        Some("analyze") => Command::Analyze(analysisconfig),
+        Some("rank") => Command::Rank(metric, count(&rest)?),
+        Some("cluster") => Command::Cluster(count(&rest)?),
+        Some("webrings") => Command::Webrings,
+        Some("path") => match &rest[..] {
+            [from, to, ..] => Command::Path(PathOptions {
+                from: from.clone(),
+                to: to.clone(),
+                paths,
+                maxhops,
+            }),
+            _ => {
+                return Err(String::from(
+                    "path needs a site to start from and one to reach",
+                ))
+            }
+        },
+        Some(other) => return Err(format!("unknown command \"{other}\"")),
+    };
+    if let Some((flag, owner)) = commandflag {
+        if name.as_deref() != Some(owner) {
+            return Err(format!("{flag} only works with {owner}"));
+        }
+    }
+    let maxargs = match command {
+        Command::Help | Command::Seed(_) => usize::MAX,
+        Command::Schedule(_)
+        | Command::Rank(..)
+        | Command::Cluster(_)
+        | Command::Links(_)
+        | Command::Backlinks(_) => 1,
+        Command::Path(_) => 2,
+        _ => 0,
+    };
+    if let Some(extra) = rest.get(maxargs) {
+        return Err(format!("unexpected argument \"{extra}\""));
+    }
+    Ok(Options { dbpath, command })
+}
+
+/// Parses a duration like "90s", "30m", "2h" or "1d". Plain numbers are seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "s"),
+    };
+    let number = number.parse::<u64>().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+/// Formats `seconds` in the largest whole unit that fits (e.g. 5400 is "1h").
+fn format_duration(seconds: i64) -> String {
+    match seconds {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Returns the site `s` names, as a url, a domain or just a name.
+/// Custom domains the database knows about are resolved to their site.
+fn parse_site(db: &Database, s: &str) -> Result<SiteId, Box<dyn Error>> {
+    if !s.contains('.') && !s.contains('/') {
+        return SiteId::new(s).ok_or_else(|| format!("invalid site name \"{s}\"").into());
+    }
+    let url = match Url::parse(s) {
+        Ok(u) if u.has_host() => u,
+        _ => {
+            Url::parse(&format!("https://{s}")).map_err(|e| format!("invalid url \"{s}\": {e}"))?
+        }
+    };
+    let resolver = db.get_site_resolver()?;
+    resolver
+        .resolve(&url)
+        .ok_or_else(|| format!("\"{s}\" isn't a neocities site").into())
+}
+
+/// Reports rows left behind by older databases and offers to delete them.
+fn check_integrity(db: &Database) -> Result<(), Box<dyn Error>> {
+    let report = db.check_integrity()?;
+    if report.is_ok() {
+        return Ok(());
+    }
+
+    let orphans: [(&str, Vec<String>); 7] = [
+        (
+            "link(s) whose source site doesn't exist",
+            report
+                .orphanedlinks
+                .iter()
+                .map(|l| format!("{} -> {}", l.srcurl(), l.dsturl()))
+                .collect(),
+        ),
+        (
+            "page(s) whose site doesn't exist",
+            report
+                .orphanedpages
+                .iter()
+                .map(|p| p.url().to_string())
+                .collect(),
+        ),
+        (
+            "page link(s) whose source page doesn't exist",
+            report
+                .orphanedpagelinks
+                .iter()
+                .map(|l| format!("{} -> {}", l.srcurl(), l.dsturl()))
+                .collect(),
+        ),
+        (
+            "custom domain(s) whose site doesn't exist",
+            report
+                .orphanedsitedomains
+                .iter()
+                .map(|d| format!("{} -> {}", d.domain(), d.siteurl()))
+                .collect(),
+        ),
+        (
+            "schedule(s) whose site doesn't exist",
+            report
+                .orphanedschedules
+                .iter()
+                .map(|s| s.siteurl().to_string())
+                .collect(),
+        ),
+        (
+            "score(s) whose site doesn't exist",
+            report
+                .orphanedscores
+                .iter()
+                .map(|s| s.siteurl().to_string())
+                .collect(),
+        ),
+        (
+            "cluster(s) whose site doesn't exist",
+            report
+                .orphanedclusters
+                .iter()
+                .map(|c| c.siteurl().to_string())
+                .collect(),
+        ),
+    ];
+    for (what, rows) in orphans.iter().filter(|(_, rows)| !rows.is_empty()) {
+        println!("found {} {what}:", rows.len());
+        for r in rows {
+            println!("  {r}");
+        }
+    }
+    print!("delete them? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        let deleted = db.repair_integrity()?;
+        println!("deleted {deleted} row(s)");
+    }
+    Ok(())
+}
+
+/// Connecting already created and migrated the database, so all that is left is checking it.
+fn init(db: &Database, dbpath: &Path) -> Result<(), Box<dyn Error>> {
+    println!(
+        "{}: schema version {}",
+        dbpath.display(),
+        db.schema_version()?
+    );
+    check_integrity(db)
+}
+
+/// Adds the sites from each of `sources` to the database as seeds.
+/// A source is either a file listing sites, or a site whose sitemaps are used as well.
+fn add_seeds(db: &Database, sources: &[String]) -> Result<(), Box<dyn Error>> {
+    let fetcher = Fetcher::new(FetcherConfig::default())?;
+    let resolver = db.get_site_resolver()?;
+    let mut seeds: Vec<SiteId> = Vec::new();
+    for source in sources {
+        let path = Path::new(source);
+        let found = if path.exists() {
+            seed::read_list_file(path).map_err(|e| format!("{source}: {e}"))?
+        } else {
+            let url = seed_url(source)
+                .ok_or_else(|| format!("{source}: not a file, a site's url or a site's name"))?;
+            let (sitemapurls, errors) = seed::fetch_sitemaps(&fetcher, &url);
+            for e in errors {
+                eprintln!("neomap: {e}");
+            }
+            let mut urls = vec![url];
+            urls.extend(sitemapurls);
+            seed::sites_of(&urls, &resolver)
+        };
+        println!("{source}: {} site(s)", found.len());
+        seeds.extend(found);
+    }
+    seed::insert_seeds(db, &seeds)?;
+    Ok(())
+}
+
+/// Returns the url of the site `source` names: a url, a domain (e.g. "dawnvoid.neocities.org")
+/// or just a name (e.g. "dawnvoid").
+fn seed_url(source: &str) -> Option<Url> {
+    match Url::parse(source) {
+        Ok(url) if url.has_host() => Some(url),
+        _ => seed::parse_site(source, true).map(|s| s.url()),
+    }
+}
+
+/// Crawls due sites until none are left or one of the `options` limits is reached.
+fn crawl(db: Database, options: &CrawlOptions) -> Result<(), Box<dyn Error>> {
+    let before = db.get_stats(Utc::now().timestamp())?;
+    let mut config = FetcherConfig::default();
+    if let Some(w) = options.wait {
+        config.politeness.hostdelay = w;
+    }
+    if let Some(r) = options.rate {
+        config.politeness.maxrps = r;
+    }
+    let fetcher = Fetcher::new(config)?;
+    let mut crawler = NeoCrawler::new(db, fetcher);
+    if let Some(n) = options.maxsites {
+        crawler = crawler.with_max_sites(n);
+    }
+    if let Some(d) = options.duration {
+        crawler = crawler.with_time_limit(d);
+    }
+    if let Some(w) = options.workers {
+        crawler = crawler.with_workers(w);
+    }
+
+    let result = crawler.crawl_due();
+    println!("{}", crawler.report());
+    for siteurl in &crawler.report().panicked {
+        eprintln!(r#"neomap: crawling "{siteurl}" panicked"#);
+    }
+    result?;
 
-    let dbpath = Path::new("neomap.db");
-    let db = Database::connect(dbpath).unwrap();
+    let after = crawler.into_database().get_stats(Utc::now().timestamp())?;
+    println!(
+        "{} site(s) known, {} new, {} still due",
+        after.sites,
+        after.sites.saturating_sub(before.sites),
+        after.duesites
+    );
+    Ok(())
+}
 
-    add_site(&db, "https://dawnvoid.neocities.org/", 100);
-    add_site(&db, "https://errormine.neocities.org/", 100);
-    add_site(&db, "https://koyo.neocities.org/", 100);
-    add_site(&db, "https://undoified.neocities.org/", 100);
-    add_site(&db, "https://kryptonaut.neocities.org/", 100);
+/// Prints the next `count` sites to be crawled, and when.
+fn schedule(db: &Database, count: usize) -> Result<(), Box<dyn Error>> {
+    let now = Utc::now().timestamp();
+    println!("{:<12} {:>8} {:>8}  site", "due", "every", "changed");
+    for s in db.get_schedule(count)? {
+        if s.is_new() {
+            println!("{:<12} {:>8} {:>8}  {}", "new", "-", "-", s.siteurl());
+            continue;
+        }
+        let due = if s.nextcrawl() <= now {
+            format!("{} ago", format_duration(now - s.nextcrawl()))
+        } else {
+            format!("in {}", format_duration(s.nextcrawl() - now))
+        };
+        println!(
+            "{:<12} {:>8} {:>8}  {}",
+            due,
+            format_duration(s.interval()),
+            format!("{}/{}", s.changes(), s.crawls()),
+            s.siteurl()
+        );
+    }
+    Ok(())
+}
 
-    add_link(
-        &db,
-        "https://dawnvoid.neocities.org/",
-        "https://errormine.neocities.org/",
+/// Prints how much the database holds and what is due next.
+fn status(db: &Database, dbpath: &Path) -> Result<(), Box<dyn Error>> {
+    let now = Utc::now().timestamp();
+    let stats = db.get_stats(now)?;
+    println!(
+        "database  {} (schema version {})",
+        dbpath.display(),
+        db.schema_version()?
     );
-    add_link(
-        &db,
-        "https://dawnvoid.neocities.org/",
-        "https://koyo.neocities.org/",
+    println!(
+        "sites     {} ({} crawled, {} never crawled, {} due)",
+        stats.sites,
+        stats.crawledsites,
+        stats.sites - stats.crawledsites,
+        stats.duesites
+    );
+    println!("domains   {} custom", stats.sitedomains);
+    println!("pages     {}", stats.pages);
+    println!(
+        "links     {} between sites, {} on pages",
+        stats.links, stats.pagelinks
+    );
+    for (i, (url, count)) in db.get_inbound_link_counts(5)?.iter().enumerate() {
+        let label = if i == 0 { "linked" } else { "" };
+        println!("{label:<9} {url} ({count} site(s))");
+    }
+    if let Some(next) = db.get_schedule(1)?.first() {
+        let when = if next.nextcrawl() <= now {
+            String::from("now")
+        } else {
+            format!("in {}", format_duration(next.nextcrawl() - now))
+        };
+        println!("next      {} {when}", next.siteurl());
+    }
+    Ok(())
+}
+
+/// Prints the sites that `site` links to, with the kind of each link.
+fn links(db: &Database, site: &str) -> Result<(), Box<dyn Error>> {
+    let site = parse_site(db, site)?;
+    let mut links = db.get_links_by_srcurl(LinkEntry::new(site.url(), site.url())?)?;
+    links.sort_by(|a, b| a.dsturl().cmp(b.dsturl()));
+    print_links(links.iter().map(|l| (l.kind().as_str(), l.dsturl())));
+    Ok(())
+}
+
+/// Prints the sites that link to `site`, with the kind of each link.
+fn backlinks(db: &Database, site: &str) -> Result<(), Box<dyn Error>> {
+    let site = parse_site(db, site)?;
+    let links = db.get_site_backlinks(SiteEntry::new(site.url(), 0)?)?;
+    print_links(links.iter().map(|l| (l.kind().as_str(), l.srcurl())));
+    Ok(())
+}
+
+fn print_links<'a>(links: impl Iterator<Item = (&'a str, &'a str)>) {
+    let mut stdout = io::stdout().lock();
+    for (kind, url) in links {
+        /* stop quietly when whatever reads the output goes away (e.g. `| head`) */
+        if writeln!(stdout, "{kind:<10} {url}").is_err() {
+            return;
+        }
+    }
+}
+
+/// Writes the site graph in the format `options` ask for, to stdout or a file.
+fn export(db: &Database, options: &ExportOptions) -> Result<(), Box<dyn Error>> {
+    let format = options
+        .format
+        .or_else(|| options.output.as_deref().and_then(Format::from_path))
+        .unwrap_or(Format::Csv);
+    let graph = SiteGraph::load(db, &options.filter)?;
+
+    let Some(output) = &options.output else {
+        let result = write_graph(&graph, format, &mut io::stdout().lock());
+        /* stop quietly when whatever reads the output goes away (e.g. `| head`) */
+        return match result {
+            Err(ExportError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            r => r.map(|_| ()).map_err(|e| e.into()),
+        };
+    };
+
+    let create = |path: &Path| -> Result<BufWriter<File>, Box<dyn Error>> {
+        let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(BufWriter::new(file))
+    };
+    let mut written = Vec::new();
+    let counts = if format == Format::Csv {
+        /* "map" and "map.csv" both become map.nodes.csv and map.edges.csv */
+        let stem = output.with_extension("");
+        let nodes = stem.with_extension("nodes.csv");
+        let edges = stem.with_extension("edges.csv");
+        let mut w = create(&nodes)?;
+        let nodecounts = graph.write_csv_nodes(&mut w)?;
+        w.flush()?;
+        let mut w = create(&edges)?;
+        let edgecounts = graph.write_csv_edges(&mut w)?;
+        w.flush()?;
+        written.extend([nodes, edges]);
+        ExportCounts {
+            nodes: nodecounts.nodes,
+            edges: edgecounts.edges,
+        }
+    } else {
+        let mut w = create(output)?;
+        let counts = write_graph(&graph, format, &mut w)?;
+        w.flush()?;
+        written.push(output.clone());
+        counts
+    };
+    for path in written {
+        eprintln!(
+            "{}: {} site(s), {} link(s)",
+            path.display(),
+            counts.nodes,
+            counts.edges
+        );
+    }
+    Ok(())
+}
+
+/// Scores every site in the database and prints the top sites by pagerank.
+fn analyze(db: &Database, config: &AnalysisConfig) -> Result<(), Box<dyn Error>> {
+    let graph = LinkGraph::load(db)?;
+    let scores = analysis::analyze(&graph, config)?;
+    analysis::save_scores(db, scores)?;
+    println!(
+        "scored {} site(s) with {} link(s) between them",
+        graph.len(),
+        graph.link_count()
     );
-    add_link(
-        &db,
-        "https://undoified.neocities.org/",
-        "https://kryptonaut.neocities.org/",
+    rank(db, Metric::PageRank, 10)
+}
+
+/// Prints the `count` sites with the highest `metric`, with all their scores.
+fn rank(db: &Database, metric: Metric, count: usize) -> Result<(), Box<dyn Error>> {
+    let scores = db.get_scores_by(metric.column(), count)?;
+    if scores.is_empty() {
+        return Err("no scores yet, compute them with \"neomap analyze\"".into());
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(
+        stdout,
+        "{:>4} {:>9} {:>7} {:>9} {:>5} {:>5} {:>11}  site",
+        "#", "pagerank", "hub", "authority", "in", "out", "betweenness"
     );
+    for (i, s) in scores.iter().enumerate() {
+        let line = writeln!(
+            stdout,
+            "{:>4} {:>9.6} {:>7.4} {:>9.4} {:>5} {:>5} {:>11.1}  {}",
+            i + 1,
+            s.pagerank(),
+            s.hub(),
+            s.authority(),
+            s.indegree(),
+            s.outdegree(),
+            s.betweenness(),
+            s.siteurl()
+        );
+        /* stop quietly when whatever reads the output goes away (e.g. `| head`) */
+        if line.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the communities and components of every site, saves them
+/// and prints the `count` largest communities.
+fn cluster(db: &Database, count: usize) -> Result<(), Box<dyn Error>> {
+    let graph = LinkGraph::load(db)?;
+    let clusters = community::find_clusters(&graph)?;
+    let communities = clusters
+        .iter()
+        .map(|c| c.community() + 1)
+        .max()
+        .unwrap_or(0);
+    let components = clusters
+        .iter()
+        .map(|c| c.component() + 1)
+        .max()
+        .unwrap_or(0);
+    println!(
+        "grouped {} site(s) into {communities} communities and {components} components",
+        graph.len()
+    );
+
+    let mut members: Vec<Vec<&str>> = vec![Vec::new(); communities as usize];
+    for c in &clusters {
+        members[c.community() as usize].push(c.siteurl());
+    }
+    let mut stdout = io::stdout().lock();
+    for (i, m) in members.iter().take(count).enumerate() {
+        let mut sample = m[..m.len().min(3)].join(", ");
+        if m.len() > 3 {
+            sample.push_str(&format!(" and {} more", m.len() - 3));
+        }
+        /* stop quietly when whatever reads the output goes away (e.g. `| head`) */
+        if writeln!(stdout, "{i:>4} {:>6}  {sample}", m.len()).is_err() {
+            break;
+        }
+    }
+    drop(stdout);
+    community::save_clusters(db, clusters)?;
+    Ok(())
+}
+
+/// Prints every webring, with its hub if it has one and its members.
+fn webrings(db: &Database) -> Result<(), Box<dyn Error>> {
+    let webrings = community::find_webrings(db)?;
+    if webrings.is_empty() {
+        println!("no webrings found");
+        return Ok(());
+    }
+    let mut stdout = io::stdout().lock();
+    for w in &webrings {
+        let hub = w.hub.as_deref().unwrap_or("(no hub)");
+        let mut result = writeln!(stdout, "{hub} ({} sites)", w.members.len());
+        for m in &w.members {
+            result = result.and_then(|_| writeln!(stdout, "  {m}"));
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the shortest paths between two sites, with the pages that hold every link on them.
+fn path(db: &Database, options: &PathOptions) -> Result<(), Box<dyn Error>> {
+    let from = parse_site(db, &options.from)?;
+    let to = parse_site(db, &options.to)?;
+    let mut finder = PathFinder::new(db);
+    if let Some(m) = options.maxhops {
+        finder = finder.with_max_hops(m);
+    }
+    let paths = finder.shortest_paths(from.url().as_str(), to.url().as_str(), options.paths)?;
+    if paths.is_empty() {
+        let within = match options.maxhops {
+            Some(m) => format!(" within {m} link(s)"),
+            None => String::new(),
+        };
+        return Err(format!("no path from {} to {}{within}", from.url(), to.url()).into());
+    }
+
+    /* paths are short, so put it all together before printing any of it */
+    let mut out = String::new();
+    for (i, p) in paths.iter().enumerate() {
+        if paths.len() > 1 {
+            if i > 0 {
+                out.push('\n');
+            }
+            out.push_str(&format!("{}. {} link(s)\n", i + 1, p.len() - 1));
+        }
+        out.push_str(&format!("{}\n", p[0]));
+        for pair in p.windows(2) {
+            let src = SiteEntry::new(Url::parse(&pair[0])?, 0)?;
+            let dst = SiteEntry::new(Url::parse(&pair[1])?, 0)?;
+            let pagelinks = db.get_page_links_between_sites(src, dst)?;
+            for l in pagelinks.iter().take(3) {
+                out.push_str(&format!(
+                    "  {} -> {} ({})\n",
+                    l.srcurl(),
+                    l.dsturl(),
+                    l.kind().as_str()
+                ));
+            }
+            if pagelinks.len() > 3 {
+                out.push_str(&format!("  and {} more\n", pagelinks.len() - 3));
+            }
+            /* links from before pages were stored, or to a custom domain, have no pages to show */
+            if pagelinks.is_empty() {
+                out.push_str("  (no pages stored for this link)\n");
+            }
+            out.push_str(&format!("{}\n", pair[1]));
+        }
+    }
+    /* stop quietly when whatever reads the output goes away (e.g. `| head`) */
+    let _ = io::stdout().lock().write_all(out.as_bytes());
+    Ok(())
+}
+
+/// Writes `graph` in `format`, which for csv is just the edges.
+fn write_graph(
+    graph: &SiteGraph,
+    format: Format,
+    w: &mut impl Write,
+) -> Result<ExportCounts, ExportError> {
+    match format {
+        Format::GraphMl => graph.write_graphml(w),
+        Format::Gexf => graph.write_gexf(w),
+        Format::Dot => graph.write_dot(w),
+        Format::Csv => graph.write_csv_edges(w),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_args, parse_duration, seed_url, Command, CrawlOptions, ExportOptions, PathOptions,
+    };
+    use neomap::analysis::{Dangling, Metric};
+    use neomap::export::{ExportFilter, Format};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use url::Url;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_args(&args).map(|o| o.command)
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse(&[]).unwrap(), Command::Help);
+        assert_eq!(parse(&["status", "--help"]).unwrap(), Command::Help);
+        assert_eq!(parse(&["init"]).unwrap(), Command::Init);
+        assert_eq!(parse(&["schedule"]).unwrap(), Command::Schedule(20));
+        assert_eq!(parse(&["schedule", "5"]).unwrap(), Command::Schedule(5));
+        assert_eq!(parse(&["cluster"]).unwrap(), Command::Cluster(20));
+        assert_eq!(parse(&["webrings"]).unwrap(), Command::Webrings);
+        assert_eq!(
+            parse(&["path", "dawnvoid", "koyo", "-k3", "--max-hops=6"]).unwrap(),
+            Command::Path(PathOptions {
+                from: String::from("dawnvoid"),
+                to: String::from("koyo"),
+                paths: 3,
+                maxhops: Some(6),
+            })
+        );
+        assert_eq!(
+            parse(&["seed", "sites.txt", "https://dawnvoid.neocities.org/"]).unwrap(),
+            Command::Seed(vec![
+                String::from("sites.txt"),
+                String::from("https://dawnvoid.neocities.org/")
+            ])
+        );
+        assert_eq!(
+            parse(&["backlinks", "dawnvoid"]).unwrap(),
+            Command::Backlinks(String::from("dawnvoid"))
+        );
+
+        let args: Vec<String> = ["--db=/tmp/x.db", "links", "koyo"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let o = parse_args(&args).unwrap();
+        assert_eq!(o.dbpath, Path::new("/tmp/x.db"));
+        assert_eq!(o.command, Command::Links(String::from("koyo")));
+    }
+
+    #[test]
+    fn crawl_options() {
+        assert_eq!(
+            parse(&["crawl"]).unwrap(),
+            Command::Crawl(CrawlOptions::default())
+        );
+        assert_eq!(
+            parse(&[
+                "crawl",
+                "--sites",
+                "10",
+                "-t2h",
+                "--db",
+                "a.db",
+                "--workers=8",
+                "-w0.5",
+                "--rate",
+                "0"
+            ])
+            .unwrap(),
+            Command::Crawl(CrawlOptions {
+                maxsites: Some(10),
+                duration: Some(Duration::from_secs(2 * 60 * 60)),
+                workers: Some(8),
+                wait: Some(Duration::from_millis(500)),
+                rate: Some(None),
+            })
+        );
+        assert_eq!(
+            parse(&["crawl", "-q", "2"]).unwrap(),
+            Command::Crawl(CrawlOptions {
+                rate: Some(Some(2.0)),
+                ..CrawlOptions::default()
+            })
+        );
+        assert!(parse(&["crawl", "-w", "1e30"]).is_err());
+        assert!(parse(&["crawl", "--rate=inf"]).is_err());
+        // politeness options only make sense for crawling
+        assert!(parse(&["status", "-w", "2"]).is_err());
+    }
+
+    #[test]
+    fn export_options() {
+        assert_eq!(
+            parse(&["export"]).unwrap(),
+            Command::Export(ExportOptions::default())
+        );
+        assert_eq!(
+            parse(&[
+                "export",
+                "-f",
+                "gexf",
+                "-o",
+                "map.gexf",
+                "--min-degree=2",
+                "--crawled"
+            ])
+            .unwrap(),
+            Command::Export(ExportOptions {
+                format: Some(Format::Gexf),
+                output: Some(PathBuf::from("map.gexf")),
+                filter: ExportFilter {
+                    mindegree: 2,
+                    crawledonly: true,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn analysis_options() {
+        let Command::Analyze(config) = parse(&[
+            "analyze",
+            "--damping",
+            "0.5",
+            "--dangling=back",
+            "--samples",
+            "10",
+        ])
+        .unwrap() else {
+            panic!("not analyze");
+        };
+        assert_eq!(config.pagerank.damping, 0.5);
+        assert_eq!(config.pagerank.dangling, Dangling::Back);
+        assert_eq!(config.samples, 10);
+        assert_eq!(
+            parse(&["rank", "5", "--by", "authority"]).unwrap(),
+            Command::Rank(Metric::Authority, 5)
+        );
+        assert_eq!(
+            parse(&["rank"]).unwrap(),
+            Command::Rank(Metric::PageRank, 20)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&["map"]).is_err());
+        assert!(parse(&["seed"]).is_err());
+        assert!(parse(&["links"]).is_err());
+        assert!(parse(&["links", "a", "b"]).is_err());
+        assert!(parse(&["status", "now"]).is_err());
+        assert!(parse(&["schedule", "lots"]).is_err());
+        assert!(parse(&["status", "--sites", "3"]).is_err());
+        assert!(parse(&["crawl", "--duration", "soon"]).is_err());
+        assert!(parse(&["crawl", "-j", "0"]).is_err());
+        assert!(parse(&["crawl", "--db"]).is_err());
+        assert!(parse(&["crawl", "--crawled"]).is_err());
+        assert!(parse(&["export", "--format", "png"]).is_err());
+        assert!(parse(&["analyze", "--damping", "1.5"]).is_err());
+        assert!(parse(&["rank", "--by", "vibes"]).is_err());
+        assert!(parse(&["rank", "--damping", "0.5"]).is_err());
+        assert!(parse(&["webrings", "10"]).is_err());
+        assert!(parse(&["path", "dawnvoid"]).is_err());
+        assert!(parse(&["path", "a", "b", "c"]).is_err());
+        assert!(parse(&["path", "a", "b", "-k0"]).is_err());
+        assert!(parse(&["links", "a", "--max-hops", "2"]).is_err());
+        assert!(parse(&["-é"]).is_err());
+        assert!(parse(&["crawl", "-é5"]).is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(
+            parse_duration("1d"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("2w"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("-5s"), None);
+    }
 
-    // db.delete_link_by_srcurl(
-    //     LinkEntry::new(
-    //         Url::parse("https://dawnvoid.neocities.org/").unwrap(),
-    //         Url::parse("https://example.org/").unwrap(),
-    //     )
-    //     .unwrap(),
-    // )
-    // .unwrap();
-
-    db.delete_site_by_url(
-        SiteEntry::new(Url::parse("https://dawnvoid.neocities.org/").unwrap(), 0).unwrap(),
-    )
-    .unwrap();
-
-    let r = db.get_site_with_oldest_crawltime().unwrap().unwrap();
-    println!("site with oldest crawltime: {r:?}");
-
-    let s = SiteEntry::new(
-        Url::parse("https://errormine.neocities.org/").unwrap(),
-        Utc::now().timestamp(),
-    )
-    .unwrap();
-    db.update_site_crawltime(s).unwrap();
-
-    let r = db.get_site_with_oldest_crawltime().unwrap().unwrap();
-    println!("site with oldest crawltime: {r:?}");
-
-    db.disconnect().unwrap();
+    #[test]
+    fn seed_urls() {
+        let dawnvoid = Url::parse("https://dawnvoid.neocities.org/").ok();
+        assert_eq!(seed_url("dawnvoid"), dawnvoid);
+        assert_eq!(seed_url("dawnvoid.neocities.org"), dawnvoid);
+        assert_eq!(seed_url("https://dawnvoid.neocities.org/"), dawnvoid);
+        assert_eq!(
+            seed_url("https://example.org/sites"),
+            Url::parse("https://example.org/sites").ok()
+        );
+        assert_eq!(seed_url("my sites.txt"), None);
+        assert_eq!(seed_url(""), None);
+    }
 }
@@ -0,0 +1,505 @@
+use crate::canonical::canonicalize;
+use crate::database::DatabaseError;
+use rusqlite::Connection;
+use url::Url;
+
+/// A single schema upgrade.
+///
+/// `MIGRATIONS[i]` upgrades a database from version `i` to version `i + 1`.
+/// Migrations must never be edited or reordered once released,
+/// schema changes always go into a new migration at the end of the list.
+pub(crate) struct Migration {
+    pub description: &'static str,
+    pub sql: &'static str,
+    // runs after `sql`, for changes to the data that sql can't express
+    pub update: Option<fn(&Connection) -> rusqlite::Result<()>>,
+}
+
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    // version 1
+    // databases created before schema versioning existed already have these tables,
+    // so this has to be a no-op for them
+    Migration {
+        description: "create site and link tables",
+        sql: "CREATE TABLE IF NOT EXISTS site (
+                url TEXT NOT NULL PRIMARY KEY,
+                crawltime INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS link (
+                srcurl TEXT NOT NULL,
+                dsturl TEXT NOT NULL,
+                PRIMARY KEY (srcurl, dsturl),
+                FOREIGN KEY (srcurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );",
+        update: None,
+    },
+    // version 2
+    Migration {
+        description: "create page and pagelink tables",
+        sql: "CREATE TABLE page (
+                url TEXT NOT NULL PRIMARY KEY,
+                siteurl TEXT NOT NULL,
+                status INTEGER,
+                contenttype TEXT,
+                size INTEGER NOT NULL,
+                fetchtime INTEGER NOT NULL,
+                hash TEXT,
+                FOREIGN KEY (siteurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );
+            CREATE INDEX page_siteurl ON page (siteurl);
+            CREATE TABLE pagelink (
+                srcurl TEXT NOT NULL,
+                dsturl TEXT NOT NULL,
+                PRIMARY KEY (srcurl, dsturl),
+                FOREIGN KEY (srcurl) REFERENCES page (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );",
+        update: None,
+    },
+    // version 3
+    // rows from before link kinds existed are assumed to be hyperlinks,
+    // they are replaced the next time their site is crawled anyway
+    Migration {
+        description: "add link kinds, anchor text and rel",
+        sql: "CREATE TABLE link_new (
+                srcurl TEXT NOT NULL,
+                dsturl TEXT NOT NULL,
+                kind TEXT NOT NULL DEFAULT 'hyperlink',
+                PRIMARY KEY (srcurl, dsturl, kind),
+                FOREIGN KEY (srcurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );
+            INSERT INTO link_new (srcurl, dsturl) SELECT srcurl, dsturl FROM link;
+            DROP TABLE link;
+            ALTER TABLE link_new RENAME TO link;
+
+            CREATE TABLE pagelink_new (
+                srcurl TEXT NOT NULL,
+                dsturl TEXT NOT NULL,
+                kind TEXT NOT NULL DEFAULT 'hyperlink',
+                text TEXT,
+                rel TEXT NOT NULL DEFAULT '',
+                PRIMARY KEY (srcurl, dsturl, kind),
+                FOREIGN KEY (srcurl) REFERENCES page (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );
+            INSERT INTO pagelink_new (srcurl, dsturl) SELECT srcurl, dsturl FROM pagelink;
+            DROP TABLE pagelink;
+            ALTER TABLE pagelink_new RENAME TO pagelink;",
+        update: None,
+    },
+    // version 4
+    Migration {
+        description: "remember pages blocked by robots.txt",
+        sql: "ALTER TABLE page ADD COLUMN blocked INTEGER NOT NULL DEFAULT 0;",
+        update: None,
+    },
+    // version 5
+    Migration {
+        description: "map custom domains to sites",
+        sql: "CREATE TABLE sitedomain (
+                domain TEXT NOT NULL PRIMARY KEY,
+                siteurl TEXT NOT NULL,
+                FOREIGN KEY (siteurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );",
+        update: None,
+    },
+    // version 6
+    Migration {
+        description: "remember page validators for conditional requests",
+        sql: "ALTER TABLE page ADD COLUMN etag TEXT;
+            ALTER TABLE page ADD COLUMN lastmodified TEXT;",
+        update: None,
+    },
+    // version 7
+    // sites without a schedule are new and due right away
+    Migration {
+        description: "schedule site recrawls",
+        sql: "CREATE TABLE siteschedule (
+                siteurl TEXT NOT NULL PRIMARY KEY,
+                nextcrawl INTEGER NOT NULL,
+                interval INTEGER NOT NULL,
+                crawls INTEGER NOT NULL,
+                changes INTEGER NOT NULL,
+                FOREIGN KEY (siteurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );
+            CREATE INDEX siteschedule_nextcrawl ON siteschedule (nextcrawl);",
+        update: None,
+    },
+    // version 8
+    // the primary key only covers lookups by srcurl
+    Migration {
+        description: "index links by destination for backlinks",
+        sql: "CREATE INDEX link_dsturl ON link (dsturl);",
+        update: None,
+    },
+    // version 9
+    Migration {
+        description: "store site scores from graph analysis",
+        sql: "CREATE TABLE sitescore (
+                siteurl TEXT NOT NULL PRIMARY KEY,
+                pagerank REAL NOT NULL,
+                hub REAL NOT NULL,
+                authority REAL NOT NULL,
+                indegree INTEGER NOT NULL,
+                outdegree INTEGER NOT NULL,
+                betweenness REAL NOT NULL,
+                FOREIGN KEY (siteurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );",
+        update: None,
+    },
+    // version 10
+    Migration {
+        description: "store site communities and components",
+        sql: "CREATE TABLE sitecluster (
+                siteurl TEXT NOT NULL PRIMARY KEY,
+                community INTEGER NOT NULL,
+                component INTEGER NOT NULL,
+                FOREIGN KEY (siteurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );",
+        update: None,
+    },
+    // version 11
+    // urls stored before they were canonicalized (e.g. "http://site.neocities.org/about.html")
+    // are rewritten, and rows that turn out to be the same are merged
+    Migration {
+        description: "canonicalize stored urls",
+        sql: "",
+        update: Some(canonicalize_urls),
+    },
+];
+
+/// The schema version that this build of neomap reads and writes.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Returns the schema version stored in the database (0 for new or unversioned databases).
+pub(crate) fn get_version(con: &Connection) -> Result<u32, DatabaseError> {
+    let version = con.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version)
+}
+
+/// Brings the database up to `SCHEMA_VERSION`.
+pub(crate) fn migrate(con: &mut Connection) -> Result<(), DatabaseError> {
+    migrate_with(con, MIGRATIONS)
+}
+
+/// Applies every migration in `migrations` that the database hasn't seen yet.
+/// Each migration runs in its own transaction together with the version bump,
+/// so a failed migration leaves the database at the previous version.
+fn migrate_with(con: &mut Connection, migrations: &[Migration]) -> Result<(), DatabaseError> {
+    let version = get_version(con)?;
+    let supported = migrations.len() as u32;
+    if version > supported {
+        return Err(DatabaseError::UnsupportedSchemaVersion {
+            found: version,
+            supported,
+        });
+    }
+
+    for (i, m) in migrations.iter().enumerate().skip(version as usize) {
+        let tx = con.transaction()?;
+        tx.execute_batch(m.sql)
+            .and_then(|_| m.update.map_or(Ok(()), |update| update(&tx)))
+            .map_err(|e| DatabaseError::MigrationFailed {
+                version: i as u32 + 1,
+                description: m.description,
+                source: e,
+            })?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Canonicalizes every url in the database, see `canonical::canonicalize()`.
+///
+/// When a url's canonical form is already there, the two are merged:
+/// the rows that belong to them are moved over unless the canonical url has the same row already,
+/// and a site keeps the latest crawltime of the two.
+fn canonicalize_urls(con: &Connection) -> rusqlite::Result<()> {
+    for (_, old, new) in changed_urls(con, "SELECT rowid, url FROM site")? {
+        con.execute(
+            "INSERT OR IGNORE INTO site (url, crawltime) VALUES (?1, 0)",
+            (&new,),
+        )?;
+        con.execute(
+            "UPDATE site SET crawltime = MAX(crawltime, (SELECT crawltime FROM site WHERE url = ?1))
+            WHERE url = ?2",
+            (&old, &new),
+        )?;
+        for (table, column) in [
+            ("link", "srcurl"),
+            ("page", "siteurl"),
+            ("sitedomain", "siteurl"),
+            ("siteschedule", "siteurl"),
+            ("sitescore", "siteurl"),
+            ("sitecluster", "siteurl"),
+        ] {
+            /* foreign keys are off while migrating, so nothing cascades by itself */
+            con.execute(
+                &format!("UPDATE OR IGNORE {table} SET {column} = ?1 WHERE {column} = ?2"),
+                (&new, &old),
+            )?;
+            con.execute(&format!("DELETE FROM {table} WHERE {column} = ?1"), (&old,))?;
+        }
+        con.execute("DELETE FROM site WHERE url = ?1", (&old,))?;
+    }
+
+    for (rowid, old, new) in changed_urls(con, "SELECT rowid, url FROM page")? {
+        con.execute(
+            "UPDATE OR IGNORE page SET url = ?1 WHERE rowid = ?2",
+            (&new, rowid),
+        )?;
+        con.execute(
+            "UPDATE OR IGNORE pagelink SET srcurl = ?1 WHERE srcurl = ?2",
+            (&new, &old),
+        )?;
+        con.execute("DELETE FROM pagelink WHERE srcurl = ?1", (&old,))?;
+        con.execute("DELETE FROM page WHERE url = ?1", (&old,))?;
+    }
+
+    /* pagelink has no index on dsturl, so rows are changed by rowid */
+    for table in ["link", "pagelink"] {
+        let sql = format!("SELECT rowid, dsturl FROM {table}");
+        for (rowid, old, new) in changed_urls(con, &sql)? {
+            con.execute(
+                &format!("UPDATE OR IGNORE {table} SET dsturl = ?1 WHERE rowid = ?2"),
+                (&new, rowid),
+            )?;
+            con.execute(
+                &format!("DELETE FROM {table} WHERE rowid = ?1 AND dsturl = ?2"),
+                (rowid, &old),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs `sql`, which selects a rowid and a url, and returns the rows whose url isn't canonical
+/// along with the canonical url. Urls that don't parse are left alone.
+fn changed_urls(con: &Connection, sql: &str) -> rusqlite::Result<Vec<(i64, String, String)>> {
+    let mut statement = con.prepare(sql)?;
+    let mut rows = statement.query(())?;
+    let mut changed = Vec::new();
+    while let Some(row) = rows.next()? {
+        let url: String = row.get(1)?;
+        let Ok(parsed) = Url::parse(&url) else {
+            continue;
+        };
+        let canonical = canonicalize(&parsed).to_string();
+        if canonical != url {
+            changed.push((row.get(0)?, url, canonical));
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_version, migrate, migrate_with, Migration, MIGRATIONS, SCHEMA_VERSION};
+    use crate::database::DatabaseError;
+    use rusqlite::Connection;
+
+    /// Creates a database the way neomap did before schema versioning.
+    fn create_unversioned() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE site (
+                url TEXT NOT NULL PRIMARY KEY,
+                crawltime INTEGER NOT NULL
+            );
+            CREATE TABLE link (
+                srcurl TEXT NOT NULL,
+                dsturl TEXT NOT NULL,
+                PRIMARY KEY (srcurl, dsturl),
+                FOREIGN KEY (srcurl) REFERENCES site (url)
+                    ON UPDATE CASCADE
+                    ON DELETE CASCADE
+            );
+            INSERT INTO site (url, crawltime) VALUES ('https://dawnvoid.neocities.org/', 100);
+            INSERT INTO site (url, crawltime) VALUES ('https://koyo.neocities.org/', 0);
+            INSERT INTO link (srcurl, dsturl)
+                VALUES ('https://dawnvoid.neocities.org/', 'https://koyo.neocities.org/');",
+        )
+        .unwrap();
+        con
+    }
+
+    fn count(con: &Connection, table: &str) -> i64 {
+        con.query_row(&format!("SELECT COUNT(*) FROM {table}"), (), |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn migrate_new() {
+        let mut con = Connection::open_in_memory().unwrap();
+        assert_eq!(get_version(&con).unwrap(), 0);
+
+        migrate(&mut con).unwrap();
+        assert_eq!(get_version(&con).unwrap(), SCHEMA_VERSION);
+        assert_eq!(count(&con, "site"), 0);
+        assert_eq!(count(&con, "link"), 0);
+        assert_eq!(count(&con, "page"), 0);
+        assert_eq!(count(&con, "pagelink"), 0);
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let mut con = create_unversioned();
+        assert_eq!(get_version(&con).unwrap(), 0);
+
+        migrate(&mut con).unwrap();
+        assert_eq!(get_version(&con).unwrap(), SCHEMA_VERSION);
+
+        // existing rows should survive the upgrade
+        assert_eq!(count(&con, "site"), 2);
+        assert_eq!(count(&con, "link"), 1);
+        let crawltime: i64 = con
+            .query_row(
+                "SELECT crawltime FROM site WHERE url = 'https://dawnvoid.neocities.org/'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(crawltime, 100);
+        let kind: String = con
+            .query_row("SELECT kind FROM link", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(kind, "hyperlink");
+    }
+
+    #[test]
+    fn migrate_canonicalizes_urls() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate_with(&mut con, &MIGRATIONS[..10]).unwrap();
+        con.execute_batch(
+            "INSERT INTO site (url, crawltime) VALUES ('http://dawnvoid.neocities.org/', 100);
+            INSERT INTO site (url, crawltime) VALUES ('https://dawnvoid.neocities.org/', 50);
+            INSERT INTO site (url, crawltime) VALUES ('http://koyo.neocities.org/', 0);
+            INSERT INTO link (srcurl, dsturl)
+                VALUES ('http://dawnvoid.neocities.org/', 'http://koyo.neocities.org/');
+            INSERT INTO link (srcurl, dsturl)
+                VALUES ('https://dawnvoid.neocities.org/', 'https://koyo.neocities.org/');
+            INSERT INTO siteschedule (siteurl, nextcrawl, interval, crawls, changes)
+                VALUES ('http://dawnvoid.neocities.org/', 200, 100, 1, 0);
+            INSERT INTO page (url, siteurl, size, fetchtime)
+                VALUES ('http://dawnvoid.neocities.org/about.html', 'http://dawnvoid.neocities.org/', 10, 100);
+            INSERT INTO page (url, siteurl, size, fetchtime)
+                VALUES ('https://dawnvoid.neocities.org/about', 'https://dawnvoid.neocities.org/', 20, 50);
+            INSERT INTO page (url, siteurl, size, fetchtime)
+                VALUES ('http://dawnvoid.neocities.org/index.html', 'http://dawnvoid.neocities.org/', 30, 100);
+            INSERT INTO pagelink (srcurl, dsturl)
+                VALUES ('http://dawnvoid.neocities.org/about.html', 'https://koyo.neocities.org/blog.html#top');
+            INSERT INTO pagelink (srcurl, dsturl)
+                VALUES ('http://dawnvoid.neocities.org/index.html', 'https://example.org/?utm_source=x');",
+        )
+        .unwrap();
+
+        migrate(&mut con).unwrap();
+        let rows = |sql: &str| -> Vec<String> {
+            let mut statement = con.prepare(sql).unwrap();
+            let rows = statement.query_map((), |row| row.get(0)).unwrap();
+            rows.collect::<Result<_, _>>().unwrap()
+        };
+        assert_eq!(
+            rows("SELECT url || ' ' || crawltime FROM site ORDER BY url"),
+            vec![
+                "https://dawnvoid.neocities.org/ 100",
+                "https://koyo.neocities.org/ 0"
+            ]
+        );
+        assert_eq!(
+            rows("SELECT srcurl || ' ' || dsturl FROM link"),
+            vec!["https://dawnvoid.neocities.org/ https://koyo.neocities.org/"]
+        );
+        assert_eq!(
+            rows("SELECT siteurl FROM siteschedule"),
+            vec!["https://dawnvoid.neocities.org/"]
+        );
+        // the page that was already canonical wins, but gets the other one's links
+        assert_eq!(
+            rows("SELECT url || ' ' || siteurl || ' ' || size FROM page ORDER BY url"),
+            vec![
+                "https://dawnvoid.neocities.org/ https://dawnvoid.neocities.org/ 30",
+                "https://dawnvoid.neocities.org/about https://dawnvoid.neocities.org/ 20"
+            ]
+        );
+        assert_eq!(
+            rows("SELECT srcurl || ' ' || dsturl FROM pagelink ORDER BY srcurl"),
+            vec![
+                "https://dawnvoid.neocities.org/ https://example.org/",
+                "https://dawnvoid.neocities.org/about https://koyo.neocities.org/blog"
+            ]
+        );
+    }
+
+    #[test]
+    fn migrate_twice() {
+        let mut con = create_unversioned();
+        migrate(&mut con).unwrap();
+        migrate(&mut con).unwrap();
+        assert_eq!(get_version(&con).unwrap(), SCHEMA_VERSION);
+        assert_eq!(count(&con, "site"), 2);
+    }
+
+    #[test]
+    fn migrate_newer_version() {
+        let mut con = Connection::open_in_memory().unwrap();
+        con.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        let result = migrate(&mut con);
+        assert!(matches!(
+            result,
+            Err(DatabaseError::UnsupportedSchemaVersion { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+        // the database should be left alone
+        assert_eq!(get_version(&con).unwrap(), SCHEMA_VERSION + 1);
+    }
+
+    #[test]
+    fn migrate_failure_rolls_back() {
+        let migrations = [
+            Migration {
+                description: "good",
+                sql: "CREATE TABLE a (x INTEGER);",
+                update: None,
+            },
+            Migration {
+                description: "bad",
+                sql: "CREATE TABLE b (x INTEGER); THIS IS NOT SQL;",
+                update: None,
+            },
+        ];
+        let mut con = Connection::open_in_memory().unwrap();
+
+        let result = migrate_with(&mut con, &migrations);
+        assert!(matches!(
+            result,
+            Err(DatabaseError::MigrationFailed { version: 2, .. })
+        ));
+
+        // the first migration should have been applied, but nothing from the second
+        assert_eq!(get_version(&con).unwrap(), 1);
+        assert_eq!(count(&con, "a"), 0);
+        assert!(con.prepare("SELECT * FROM b").is_err());
+    }
+}
@@ -1,20 +1,898 @@
-use crate::neocrawler;
-use std::collections::HashMap;
+use crate::database::{
+    Database, DatabaseError, LinkEntry, PageEntry, PageLinkEntry, ScheduleEntry, SiteDomainEntry,
+    SiteEntry,
+};
+use crate::fetcher::{Fetcher, Validators};
+use crate::get_site_url;
+use crate::page::{Link, LinkKind};
+use crate::pagecrawler::{CrawlReport, CrawledPage, KnownPage, PageCrawler};
+use crate::scheduler::Scheduler;
+use crate::site::{SiteId, SiteResolver};
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use url::Url;
 
+/// Crawls neocities site by site.
+///
+/// All crawl state lives in the `Database`, so a crawl that is interrupted
+/// will pick up where it left off the next time it is started.
+/// Which sites are crawled when is up to the `Scheduler`.
+///
+/// Several sites are crawled at the same time by a pool of worker threads,
+/// while only the thread that called `crawl()` touches the database.
 pub struct NeoCrawler {
-    sites: HashMap<Url, Vec<Url>>,
+    db: Database,
+    fetcher: Fetcher,
+    workers: usize,   // number of sites crawled at the same time
+    batchsize: usize, // most sites written to the database in one transaction
+    report: CrawlReport,
+    scheduler: Scheduler,
+    maxsites: Option<usize>, // most sites handed out by one call to `crawl_due()`
+    timelimit: Option<Duration>, // no new sites are handed out after this long
+}
+
+/// A site for a worker to crawl, along with what the last crawl of it found.
+struct SiteJob {
+    siteurl: Url,
+    known: Vec<KnownPage>,
+}
+
+/// The result of crawling one site, sent from a worker to the writer.
+struct SiteCrawl {
+    siteurl: Url,
+    pages: Vec<CrawledPage>,
+    customdomains: Vec<String>,
+    panicked: bool,
+}
+
+impl SiteCrawl {
+    fn empty(siteurl: Url) -> SiteCrawl {
+        SiteCrawl {
+            siteurl,
+            pages: Vec::new(),
+            customdomains: Vec::new(),
+            panicked: false,
+        }
+    }
 }
 
 impl NeoCrawler {
-    pub fn new() -> NeoCrawler {
+    pub fn new(db: Database, fetcher: Fetcher) -> NeoCrawler {
         NeoCrawler {
-            sites: HashMap::new(),
+            db,
+            fetcher,
+            workers: 4,
+            batchsize: 16,
+            report: CrawlReport::default(),
+            scheduler: Scheduler::default(),
+            maxsites: None,
+            timelimit: None,
+        }
+    }
+
+    /// Crawls up to `workers` sites at the same time.
+    ///
+    /// Every request still goes through the fetcher's politeness limits,
+    /// so more workers never means more load on any single host.
+    pub fn with_workers(mut self, workers: usize) -> NeoCrawler {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Writes up to `batchsize` crawled sites to the database in a single transaction.
+    pub fn with_batchsize(mut self, batchsize: usize) -> NeoCrawler {
+        self.batchsize = batchsize.max(1);
+        self
+    }
+
+    /// Uses `scheduler` to decide when crawled sites are due again.
+    pub fn with_scheduler(mut self, scheduler: Scheduler) -> NeoCrawler {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Stops a crawl after `maxsites` sites.
+    pub fn with_max_sites(mut self, maxsites: usize) -> NeoCrawler {
+        self.maxsites = Some(maxsites);
+        self
+    }
+
+    /// Stops handing out sites once a crawl has run for `timelimit`.
+    ///
+    /// Sites that are being crawled at that point are still finished and written,
+    /// so a crawl can run over by as long as its slowest site takes.
+    pub fn with_time_limit(mut self, timelimit: Duration) -> NeoCrawler {
+        self.timelimit = Some(timelimit);
+        self
+    }
+
+    /// Returns totals over every page crawled so far,
+    /// including how much was saved by not re-downloading or re-parsing unchanged pages,
+    /// and which sites couldn't be crawled because the crawler panicked.
+    pub fn report(&self) -> &CrawlReport {
+        &self.report
+    }
+
+    pub fn into_database(self) -> Database {
+        self.db
+    }
+
+    /// Adds `rootsite` to the database (if it isn't there already),
+    /// then crawls sites until none are due.
+    ///
+    /// Sites that were never crawled are always due,
+    /// so every site found along the way is crawled at least once.
+    pub fn crawl(&mut self, rootsite: &Url) -> Result<(), DatabaseError> {
+        let siteurl = match get_site_url(rootsite) {
+            Some(u) => u,
+            None => return Err(DatabaseError::InvalidUrl(rootsite.to_string())),
+        };
+        self.db.add_site(SiteEntry::new(siteurl, 0)?)?;
+        self.crawl_due()
+    }
+
+    /// Crawls sites until none are due, or until the limits set with
+    /// `with_max_sites()` and `with_time_limit()` are reached.
+    pub fn crawl_due(&mut self) -> Result<(), DatabaseError> {
+        let (jobsender, jobreceiver) = mpsc::channel::<SiteJob>();
+        let (resultsender, resultreceiver) = mpsc::channel::<SiteCrawl>();
+        let jobreceiver = Mutex::new(jobreceiver);
+        /* workers get the custom domains known at the start, they learn their own site's as they go */
+        let sites = self.db.get_site_resolver()?;
+
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                let fetcher = self.fetcher.clone();
+                let jobs = &jobreceiver;
+                let results = resultsender.clone();
+                let sites = &sites;
+                scope.spawn(move || work(&fetcher, sites, jobs, &results));
+            }
+            /* the workers hold the only senders now, so the receiver notices if they all die */
+            drop(resultsender);
+
+            /* returning drops `jobsender`, which lets the workers finish */
+            self.dispatch(sites.clone(), jobsender, &resultreceiver)
+        })
+    }
+
+    /// Hands out due sites to the workers and writes back what they found,
+    /// until no sites are due or the crawl's limits are reached.
+    fn dispatch(
+        &mut self,
+        mut sites: SiteResolver,
+        jobs: Sender<SiteJob>,
+        results: &Receiver<SiteCrawl>,
+    ) -> Result<(), DatabaseError> {
+        let mut inflight: HashSet<String> = HashSet::new();
+        let start = Instant::now();
+        let mut handedout = 0;
+        loop {
+            /* past the time limit, only wait for the sites already handed out */
+            let candidates = if self.timelimit.is_some_and(|t| start.elapsed() >= t) {
+                Vec::new()
+            } else {
+                /* sites being crawled are still due in the database, so look past them */
+                let now = Utc::now().timestamp();
+                self.db
+                    .get_due_schedule(now, inflight.len() + self.workers)?
+            };
+            for site in candidates {
+                if inflight.len() >= self.workers || self.maxsites.is_some_and(|m| handedout >= m) {
+                    break;
+                }
+                if inflight.contains(site.siteurl()) {
+                    continue;
+                }
+                let url = Url::parse(site.siteurl())
+                    .map_err(|_| DatabaseError::InvalidUrl(site.siteurl().to_string()))?;
+                let known = known_pages(&self.db, &url)?;
+                if jobs
+                    .send(SiteJob {
+                        siteurl: url,
+                        known,
+                    })
+                    .is_err()
+                {
+                    break;
+                }
+                inflight.insert(site.siteurl().to_string());
+                handedout += 1;
+            }
+            if inflight.is_empty() {
+                return Ok(());
+            }
+
+            /* wait for one site, then take whatever else is already done */
+            let Ok(first) = results.recv() else {
+                return Ok(());
+            };
+            let mut batch = vec![first];
+            while batch.len() < self.batchsize {
+                match results.try_recv() {
+                    Ok(r) => batch.push(r),
+                    Err(_) => break,
+                }
+            }
+
+            self.db.in_transaction(|db| {
+                for c in &batch {
+                    write_site(db, &self.scheduler, &mut sites, c)?;
+                }
+                Ok(())
+            })?;
+            for c in batch {
+                inflight.remove(c.siteurl.as_str());
+                for p in &c.pages {
+                    self.report.add(p);
+                }
+                if c.panicked {
+                    self.report.panicked.push(c.siteurl);
+                }
+            }
+        }
+    }
+
+    /// Crawls the site that is due next (even if it isn't due yet) and records its outgoing links.
+    ///
+    /// Returns the schedule the site was crawled at, or `None` if the database has no sites.
+    pub fn crawl_next(&mut self) -> Result<Option<ScheduleEntry>, DatabaseError> {
+        let site = match self.db.get_schedule(1)?.into_iter().next() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let siteurl = Url::parse(site.siteurl())
+            .map_err(|_| DatabaseError::InvalidUrl(site.siteurl().to_string()))?;
+
+        let mut sites = self.db.get_site_resolver()?;
+        let known = known_pages(&self.db, &siteurl)?;
+        /* a crawl that panics is rescheduled and reported, the same way as in `work()` */
+        let crawl = panic::catch_unwind(AssertUnwindSafe(|| {
+            crawl_site(&self.fetcher, &sites, &siteurl, known)
+        }));
+        let crawl = match crawl {
+            Ok(c) => c.ok_or_else(|| DatabaseError::InvalidUrl(site.siteurl().to_string()))?,
+            Err(_) => SiteCrawl {
+                panicked: true,
+                ..SiteCrawl::empty(siteurl)
+            },
+        };
+
+        /* write everything in one go, so an interrupted crawl never leaves a half-written site */
+        self.db
+            .in_transaction(|db| write_site(db, &self.scheduler, &mut sites, &crawl))?;
+        for p in &crawl.pages {
+            self.report.add(p);
+        }
+        if crawl.panicked {
+            self.report.panicked.push(crawl.siteurl);
+        }
+
+        Ok(Some(site))
+    }
+}
+
+/// Crawls sites from `jobs` until there are no more.
+fn work(
+    fetcher: &Fetcher,
+    sites: &SiteResolver,
+    jobs: &Mutex<Receiver<SiteJob>>,
+    results: &Sender<SiteCrawl>,
+) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok(SiteJob { siteurl, known }) = job else {
+            return;
+        };
+        /* a site that can't be crawled (or panics the crawler) is still sent back with no pages,
+        so it's rescheduled and the writer isn't left waiting for it */
+        let crawl = panic::catch_unwind(AssertUnwindSafe(|| {
+            crawl_site(fetcher, sites, &siteurl, known)
+        }));
+        let crawl = match crawl {
+            Ok(c) => c.unwrap_or_else(|| SiteCrawl::empty(siteurl)),
+            Err(_) => SiteCrawl {
+                panicked: true,
+                ..SiteCrawl::empty(siteurl)
+            },
+        };
+        if results.send(crawl).is_err() {
+            return;
+        }
+    }
+}
+
+/// Crawls every page of the site at `siteurl`, revisiting the `known` pages conditionally.
+/// Returns `None` if `siteurl` can't be crawled at all.
+fn crawl_site(
+    fetcher: &Fetcher,
+    sites: &SiteResolver,
+    siteurl: &Url,
+    known: Vec<KnownPage>,
+) -> Option<SiteCrawl> {
+    let mut crawler = PageCrawler::new(siteurl.clone(), fetcher.clone())
+        .ok()?
+        .with_sites(sites.clone())
+        .with_known_pages(known);
+    crawler.crawl();
+    let customdomains = crawler.get_custom_domains().to_vec();
+    Some(SiteCrawl {
+        siteurl: siteurl.clone(),
+        pages: crawler.into_pages(),
+        customdomains,
+        panicked: false,
+    })
+}
+
+/// Returns the pages that the last crawl of the site at `siteurl` fetched, with their links.
+fn known_pages(db: &Database, siteurl: &Url) -> Result<Vec<KnownPage>, DatabaseError> {
+    let site = SiteEntry::new(siteurl.clone(), 0)?;
+    let mut links: HashMap<String, Vec<Link>> = HashMap::new();
+    for l in db.get_page_links_by_siteurl(site.clone())? {
+        let Ok(url) = Url::parse(l.dsturl()) else {
+            continue;
+        };
+        links.entry(l.srcurl().to_string()).or_default().push(Link {
+            url,
+            kind: l.kind(),
+            text: l.text().map(String::from),
+            rel: l.rel().into_iter().map(String::from).collect(),
+            element: String::new(),
+            attribute: String::new(),
+        });
+    }
+
+    let mut known = Vec::new();
+    for p in db.get_pages_by_siteurl(site)? {
+        if p.status().is_none() {
+            continue;
         }
+        let Ok(url) = Url::parse(p.url()) else {
+            continue;
+        };
+        known.push(KnownPage {
+            url,
+            status: p.status(),
+            contenttype: p.contenttype().map(String::from),
+            size: p.size() as usize,
+            hash: p.hash().map(String::from),
+            validators: Validators {
+                etag: p.etag().map(String::from),
+                lastmodified: p.lastmodified().map(String::from),
+            },
+            links: links.remove(p.url()).unwrap_or_default(),
+        });
+    }
+    Ok(known)
+}
+
+/// Replaces everything known about the crawled site with what the crawl found,
+/// marks it as crawled and schedules its next crawl. Should run inside a transaction.
+///
+/// If the crawl couldn't fetch the site's root page (or the server failed), it is only retried later.
+///
+/// Custom domains the site turned out to have are added to `sites` as well.
+fn write_site(
+    db: &Database,
+    scheduler: &Scheduler,
+    sites: &mut SiteResolver,
+    crawl: &SiteCrawl,
+) -> Result<(), DatabaseError> {
+    let siteurl = &crawl.siteurl;
+    let pages = &crawl.pages;
+    let now = Utc::now().timestamp();
+    let previous = db.get_schedule_by_siteurl(SiteEntry::new(siteurl.clone(), 0)?)?;
+
+    /* a crawl that failed or couldn't fetch the root page says nothing about the site,
+    so whatever the last crawl found is kept, the site isn't marked as crawled
+    and it is retried without touching its schedule */
+    if pages
+        .first()
+        .is_none_or(|p| p.status.is_none_or(|s| s >= 500))
+    {
+        return db.set_schedule(scheduler.retry(siteurl.clone(), previous.as_ref(), now)?);
     }
 
-    pub fn crawl(&mut self, rootsite: &Url) {
-        let mut frontier: Vec<Url> = Vec::new();
-        frontier.push(rootsite.clone());
+    let site = SiteId::from_url(siteurl);
+    if let Some(site) = &site {
+        for d in &crawl.customdomains {
+            db.set_site_domain(SiteDomainEntry::new(d, site)?)?;
+            sites.add_domain(d, site.clone());
+        }
+    }
+
+    /* reduce every link to the neocities site it belongs to */
+    let mut dstsites: Vec<(Url, LinkKind)> = pages
+        .iter()
+        .flat_map(|p| p.links.iter())
+        .filter_map(|l| Some((sites.resolve(&l.url)?, l.kind)))
+        .filter(|(s, _)| Some(s) != site.as_ref())
+        .map(|(s, kind)| (s.url(), kind))
+        .collect();
+    dstsites.sort_unstable();
+    dstsites.dedup();
+
+    let oldlinks = db.get_links_by_srcurl(LinkEntry::new(siteurl.clone(), siteurl.clone())?)?;
+    let mut oldsites: Vec<(&str, LinkKind)> =
+        oldlinks.iter().map(|l| (l.dsturl(), l.kind())).collect();
+    oldsites.sort_unstable();
+    let changed = !oldsites
+        .iter()
+        .copied()
+        .eq(dstsites.iter().map(|(s, kind)| (s.as_str(), *kind)));
+
+    /* replace the old links and pages so anything that was removed from the site disappears */
+    db.delete_links_by_srcurl(LinkEntry::new(siteurl.clone(), siteurl.clone())?)?;
+    db.delete_pages_by_siteurl(SiteEntry::new(siteurl.clone(), 0)?)?;
+    for (dst, kind) in dstsites {
+        db.add_site(SiteEntry::new(dst.clone(), 0)?)?;
+        db.set_link(LinkEntry::new(siteurl.clone(), dst)?.with_kind(kind))?;
+    }
+    for p in pages {
+        db.set_page(
+            PageEntry::new(
+                p.url.clone(),
+                siteurl.clone(),
+                p.status,
+                p.contenttype.clone(),
+                p.size,
+                p.fetchtime,
+                p.hash.clone(),
+            )?
+            .with_blocked(p.blocked)
+            .with_validators(p.validators.etag.clone(), p.validators.lastmodified.clone()),
+        )?;
+        for l in &p.links {
+            if let Ok(pl) = PageLinkEntry::from_link(p.url.clone(), l) {
+                db.set_page_link(pl)?;
+            }
+        }
+    }
+
+    db.set_schedule(scheduler.reschedule(siteurl.clone(), previous.as_ref(), now, changed)?)?;
+    let crawled = SiteEntry::new(siteurl.clone(), now)?;
+    db.update_site_crawltime(crawled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NeoCrawler;
+    use crate::database::{Database, LinkEntry, ScheduleEntry, SiteDomainEntry, SiteEntry};
+    use crate::fetcher::Fetcher;
+    use crate::scheduler::{Scheduler, SchedulerConfig};
+    use crate::site::SiteId;
+    use crate::testserver::{self, Response, TestServer};
+    use chrono::Utc;
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+    use url::Url;
+
+    /// Starts a server for each site and points the fetcher at them.
+    fn serve(sites: Vec<(&str, Vec<(&str, Response)>)>) -> (Vec<TestServer>, Fetcher) {
+        let mut fetcher = testserver::fetcher();
+        let mut servers = Vec::new();
+        for (name, routes) in sites {
+            let server = TestServer::start(routes);
+            fetcher = fetcher.with_host_override(&format!("{name}.neocities.org"), server.url("/"));
+            servers.push(server);
+        }
+        (servers, fetcher)
+    }
+
+    fn webring() -> Vec<(&'static str, Vec<(&'static str, Response)>)> {
+        vec![
+            (
+                "alice",
+                vec![
+                    (
+                        "/",
+                        Response::html(
+                            r#"<a href="https://bob.neocities.org/">bob</a> <a href="/about.html">about</a>"#,
+                        ),
+                    ),
+                    (
+                        "/about",
+                        Response::html(r#"<a href="https://carol.neocities.org/">carol</a>"#),
+                    ),
+                ],
+            ),
+            (
+                "bob",
+                vec![(
+                    "/",
+                    Response::html(
+                        r#"<a href="https://carol.neocities.org/">c</a> <img src="https://dave.neocities.org/cat.png">"#,
+                    ),
+                )],
+            ),
+            (
+                "carol",
+                vec![(
+                    "/",
+                    Response::html(
+                        r#"<a href="https://alice.neocities.org/">a</a> <a href="/gone.html">x</a>"#,
+                    ),
+                )],
+            ),
+            ("dave", vec![("/", Response::html("no links here"))]),
+        ]
+    }
+
+    /// Everything the crawl wrote, minus timestamps.
+    fn graph(db: &Database) -> Vec<String> {
+        let mut result = Vec::new();
+        for site in db.get_sites_with_oldest_crawltime(100).unwrap() {
+            assert_ne!(site.crawltime(), 0, "{} wasn't crawled", site.url());
+            let url = Url::parse(site.url()).unwrap();
+            let links = db
+                .get_links_by_srcurl(LinkEntry::new(url.clone(), url.clone()).unwrap())
+                .unwrap();
+            for l in links {
+                result.push(format!("link {} {} {:?}", l.srcurl(), l.dsturl(), l.kind()));
+            }
+            let pages = db
+                .get_pages_by_siteurl(SiteEntry::new(url, 0).unwrap())
+                .unwrap();
+            for p in pages {
+                result.push(format!("page {} {:?}", p.url(), p.status()));
+            }
+        }
+        result.sort();
+        result
+    }
+
+    fn crawl(fetcher: Fetcher, workers: usize) -> Database {
+        let db = Database::connect_virtual().unwrap();
+        let mut crawler = NeoCrawler::new(db, fetcher).with_workers(workers);
+        crawler
+            .crawl(&Url::parse("https://alice.neocities.org/").unwrap())
+            .unwrap();
+        crawler.into_database()
+    }
+
+    #[test]
+    fn crawl_resumes() {
+        // a crawl that was killed after alice, with bob still waiting to be crawled
+        // and alice not due again for an hour
+        let (servers, fetcher) = serve(vec![
+            ("alice", vec![("/", Response::html("alice"))]),
+            (
+                "bob",
+                vec![(
+                    "/",
+                    Response::html(r#"<a href="https://alice.neocities.org/">alice</a>"#),
+                )],
+            ),
+        ]);
+        let alice = Url::parse("https://alice.neocities.org/").unwrap();
+        let bob = Url::parse("https://bob.neocities.org/").unwrap();
+        let db = Database::connect_virtual().unwrap();
+        db.set_site(SiteEntry::new(alice.clone(), 123).unwrap())
+            .unwrap();
+        db.add_site(SiteEntry::new(bob.clone(), 0).unwrap())
+            .unwrap();
+        let nextcrawl = Utc::now().timestamp() + 3600;
+        db.set_schedule(ScheduleEntry::new(alice.clone(), nextcrawl, 3600).unwrap())
+            .unwrap();
+
+        let mut crawler = NeoCrawler::new(db, fetcher);
+        crawler.crawl(&alice).unwrap();
+        let db = crawler.into_database();
+
+        // only bob was crawled, alice keeps its crawltime
+        assert!(servers[0].requests().is_empty());
+        let oldest = db.get_site_with_oldest_crawltime().unwrap().unwrap();
+        assert_eq!((oldest.url(), oldest.crawltime()), (alice.as_str(), 123));
+        assert_eq!(
+            graph(&db),
+            vec![
+                "link https://bob.neocities.org/ https://alice.neocities.org/ Hyperlink",
+                "page https://bob.neocities.org/ Some(200)",
+            ]
+        );
+    }
+
+    #[test]
+    fn concurrent_crawl_matches_serial() {
+        let (_servers, fetcher) = serve(webring());
+        let serial = graph(&crawl(fetcher, 1));
+
+        let (servers, fetcher) = serve(webring());
+        let concurrent = graph(&crawl(fetcher, 4));
+
+        assert_eq!(serial, concurrent);
+        assert!(serial.contains(&String::from(
+            "link https://bob.neocities.org/ https://dave.neocities.org/ Image"
+        )));
+        assert!(serial.contains(&String::from(
+            "page https://carol.neocities.org/gone Some(404)"
+        )));
+
+        // every page was fetched exactly once
+        for server in servers {
+            let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+            let unique: HashSet<&String> = paths.iter().collect();
+            assert_eq!(paths.len(), unique.len(), "duplicate fetches: {paths:?}");
+        }
+    }
+
+    #[test]
+    fn concurrent_crawl_is_faster() {
+        let delay = Duration::from_millis(300);
+        let slow = |body: &str| Response::html(body).delay(delay);
+        let (_servers, fetcher) = serve(vec![
+            (
+                "alice",
+                vec![(
+                    "/",
+                    slow(
+                        r#"<a href="https://bob.neocities.org/">b</a> <a href="https://carol.neocities.org/">c</a>
+                        <a href="https://dave.neocities.org/">d</a> <a href="https://erin.neocities.org/">e</a>"#,
+                    ),
+                )],
+            ),
+            ("bob", vec![("/", slow("bob"))]),
+            ("carol", vec![("/", slow("carol"))]),
+            ("dave", vec![("/", slow("dave"))]),
+            ("erin", vec![("/", slow("erin"))]),
+        ]);
+
+        // alice first, then the other four side by side
+        let start = Instant::now();
+        let db = crawl(fetcher, 4);
+        assert!(start.elapsed() < delay * 4, "took {:?}", start.elapsed());
+        assert_eq!(db.get_sites_with_oldest_crawltime(100).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn crawl_one_node_per_site() {
+        let custom = TestServer::start(vec![("/", Response::html("alice's own domain"))]);
+        let (_servers, fetcher) = serve(vec![
+            (
+                "bob",
+                vec![(
+                    "/",
+                    Response::html(
+                        r#"<a href="https://koyo.example/">koyo</a>
+                        <a href="https://www.carol.neocities.org/x">carol</a>
+                        <a href="https://neocities.org/site/dave">dave</a>
+                        <a href="https://neocities.org/browse">browse</a>
+                        <a href="https://alice.neocities.org/">alice</a>"#,
+                    ),
+                )],
+            ),
+            ("koyo", vec![("/", Response::html("koyo"))]),
+            ("carol", vec![("/", Response::html("carol"))]),
+            ("dave", vec![("/", Response::html("dave"))]),
+            (
+                "alice",
+                vec![(
+                    "/",
+                    Response::new(301, "").header("Location", custom.url("/").as_str()),
+                )],
+            ),
+        ]);
+
+        // koyo's custom domain is already known
+        let db = Database::connect_virtual().unwrap();
+        let koyo = SiteId::new("koyo").unwrap();
+        db.add_site(SiteEntry::new(koyo.url(), 0).unwrap()).unwrap();
+        db.set_site_domain(SiteDomainEntry::new("koyo.example", &koyo).unwrap())
+            .unwrap();
+
+        let mut crawler = NeoCrawler::new(db, fetcher).with_workers(1);
+        crawler
+            .crawl(&Url::parse("https://www.bob.neocities.org/").unwrap())
+            .unwrap();
+        let db = crawler.into_database();
+
+        let mut sites: Vec<String> = db
+            .get_sites_with_oldest_crawltime(100)
+            .unwrap()
+            .iter()
+            .map(|s| s.url().to_string())
+            .collect();
+        sites.sort();
+        assert_eq!(
+            sites,
+            vec![
+                "https://alice.neocities.org/",
+                "https://bob.neocities.org/",
+                "https://carol.neocities.org/",
+                "https://dave.neocities.org/",
+                "https://koyo.neocities.org/",
+            ]
+        );
+
+        // alice's custom domain was found when crawling her site
+        let domains = db.get_site_domains().unwrap();
+        let domains: Vec<(&str, &str)> =
+            domains.iter().map(|d| (d.domain(), d.siteurl())).collect();
+        assert!(domains.contains(&("localhost", "https://alice.neocities.org/")));
+    }
+
+    #[test]
+    fn crawl_stops_at_limits() {
+        let (_servers, fetcher) = serve(webring());
+        let db = Database::connect_virtual().unwrap();
+        let mut crawler = NeoCrawler::new(db, fetcher.clone())
+            .with_workers(2)
+            .with_max_sites(2);
+        crawler
+            .crawl(&Url::parse("https://alice.neocities.org/").unwrap())
+            .unwrap();
+        let db = crawler.into_database();
+        let stats = db.get_stats(0).unwrap();
+        assert_eq!(stats.crawledsites, 2);
+        assert!(stats.sites > 2);
+
+        // a crawl that is out of time doesn't start on anything
+        let mut crawler = NeoCrawler::new(db, fetcher).with_time_limit(Duration::ZERO);
+        crawler.crawl_due().unwrap();
+        assert_eq!(crawler.report().pages, 0);
+        let db = crawler.into_database();
+        assert_eq!(db.get_stats(0).unwrap().crawledsites, 2);
+    }
+
+    #[test]
+    fn recrawl_skips_unchanged_pages() {
+        let root =
+            r#"<a href="https://bob.neocities.org/">bob</a> <a href="/about.html">about</a>"#;
+        let (servers, fetcher) = serve(vec![
+            (
+                "alice",
+                vec![
+                    ("/", Response::html(root).header("ETag", "\"v1\"")),
+                    ("/", Response::new(304, "")),
+                    ("/about", Response::html("the same every time")),
+                ],
+            ),
+            ("bob", vec![("/", Response::html("no links here"))]),
+        ]);
+        let alice = Url::parse("https://alice.neocities.org/").unwrap();
+        let mut crawler =
+            NeoCrawler::new(Database::connect_virtual().unwrap(), fetcher.clone()).with_workers(1);
+        crawler.crawl(&alice).unwrap();
+        assert_eq!(crawler.report().pages, 3);
+        assert_eq!(crawler.report().pagessaved(), 0);
+        let db = crawler.into_database();
+        let before = graph(&db);
+
+        // alice was crawled first, so she is up next
+        let mut crawler = NeoCrawler::new(db, fetcher);
+        let site = crawler.crawl_next().unwrap().unwrap();
+        assert_eq!(site.siteurl(), alice.as_str());
+
+        let report = crawler.report();
+        assert_eq!(report.pages, 2);
+        assert_eq!(report.notmodified, 1);
+        assert_eq!(report.samecontent, 1);
+        assert_eq!(report.bytessaved, root.len() as u64);
+        assert_eq!(report.bytes, "the same every time".len() as u64);
+
+        let requests = servers[0].requests();
+        let revisit = requests.iter().filter(|r| r.path == "/").nth(1).unwrap();
+        assert_eq!(
+            revisit.headers.get("if-none-match").map(String::as_str),
+            Some("\"v1\"")
+        );
+        // nothing was lost by not parsing the pages again
+        let db = crawler.into_database();
+        assert_eq!(graph(&db), before);
+        let pages = db
+            .get_pages_by_siteurl(SiteEntry::new(alice, 0).unwrap())
+            .unwrap();
+        assert_eq!(pages[0].etag(), Some("\"v1\""));
+    }
+
+    #[test]
+    fn crawl_schedules_sites() {
+        let (_servers, fetcher) = serve(vec![
+            (
+                "alice",
+                vec![
+                    (
+                        "/",
+                        Response::html(r#"<a href="https://bob.neocities.org/">b</a>"#),
+                    ),
+                    (
+                        "/",
+                        Response::html(r#"<a href="https://carol.neocities.org/">c</a>"#),
+                    ),
+                ],
+            ),
+            ("bob", vec![("/", Response::html("no links here"))]),
+        ]);
+        let hour = Duration::from_secs(60 * 60);
+        let scheduler = Scheduler::new(SchedulerConfig {
+            mininterval: hour,
+            maxinterval: hour * 64,
+            initialinterval: hour * 8,
+        });
+        let alice = Url::parse("https://alice.neocities.org/").unwrap();
+        let mut crawler = NeoCrawler::new(Database::connect_virtual().unwrap(), fetcher.clone())
+            .with_scheduler(scheduler.clone())
+            .with_workers(1);
+        crawler.crawl(&alice).unwrap();
+        assert_eq!(crawler.report().pages, 2);
+
+        // everything was just crawled, so nothing is due
+        let db = crawler.into_database();
+        let schedule = db.get_schedule(10).unwrap();
+        assert_eq!(schedule.len(), 2);
+        assert!(schedule
+            .iter()
+            .all(|s| s.crawls() == 1 && s.interval() == 8 * 3600));
+        let mut crawler = NeoCrawler::new(db, fetcher).with_scheduler(scheduler);
+        crawler.crawl(&alice).unwrap();
+        assert_eq!(crawler.report().pages, 0);
+
+        // alice's links changed, so she is due again sooner
+        let site = crawler.crawl_next().unwrap().unwrap();
+        assert_eq!(site.siteurl(), alice.as_str());
+        let db = crawler.into_database();
+        let schedule = db
+            .get_schedule_by_siteurl(SiteEntry::new(alice, 0).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(schedule.interval(), 4 * 3600);
+        assert_eq!((schedule.crawls(), schedule.changes()), (2, 1));
+        // and carol is new, so she is first in line
+        let next = db.get_schedule(1).unwrap();
+        assert_eq!(next[0].siteurl(), "https://carol.neocities.org/");
+        assert!(next[0].is_new());
+    }
+
+    #[test]
+    fn failed_recrawl_keeps_site() {
+        let (_servers, fetcher) = serve(vec![
+            (
+                "alice",
+                vec![
+                    (
+                        "/",
+                        Response::html(r#"<a href="https://bob.neocities.org/">b</a>"#),
+                    ),
+                    ("/", Response::new(500, "down for maintenance")),
+                ],
+            ),
+            ("bob", vec![("/", Response::html("no links here"))]),
+        ]);
+        let alice = Url::parse("https://alice.neocities.org/").unwrap();
+        let mut crawler =
+            NeoCrawler::new(Database::connect_virtual().unwrap(), fetcher.clone()).with_workers(1);
+        crawler.crawl(&alice).unwrap();
+        let db = crawler.into_database();
+        let schedule = |db: &Database| {
+            db.get_schedule_by_siteurl(SiteEntry::new(alice.clone(), 0).unwrap())
+                .unwrap()
+                .unwrap()
+        };
+        let before = schedule(&db);
+        let crawled = graph(&db);
+
+        // the server is down the next time alice is crawled
+        let mut crawler = NeoCrawler::new(db, fetcher);
+        crawler.crawl_next().unwrap().unwrap();
+        let db = crawler.into_database();
+        assert_eq!(graph(&db), crawled);
+        let pages = db
+            .get_pages_by_siteurl(SiteEntry::new(alice.clone(), 0).unwrap())
+            .unwrap();
+        assert_eq!(pages[0].status(), Some(200));
+
+        // and she is retried at the same interval, without counting the crawl
+        let after = schedule(&db);
+        assert_eq!(after.interval(), before.interval());
+        assert_eq!(
+            (after.crawls(), after.changes()),
+            (before.crawls(), before.changes())
+        );
+        assert!(after.nextcrawl() >= before.nextcrawl());
     }
 }
@@ -1,84 +1,340 @@
-use regex::Regex;
-use reqwest::blocking;
-use url::{ParseError, Url};
+use crate::fetcher::{FetchError, Fetcher, Redirect, Validators};
+use crate::html;
+use url::Url;
 
 pub struct Page {
     pub url: Url,
+    pub finalurl: Url, // url after following redirects, links are relative to this
     pub html: String,
+    pub status: u16,                 // http status code of the last fetch
+    pub contenttype: Option<String>, // content-type header of the last fetch
+    pub size: usize,                 // body size in bytes of the last fetch
+    pub fetchtime: i64,              // timestamp of the last fetch, 0 if never fetched
+    pub redirects: Vec<Redirect>,    // redirects followed to get from `url` to `finalurl`
+    pub validators: Validators,      // etag and last-modified of the last fetch
+    hash: String,                    // `content_hash()` of the body of the last fetch
 }
 
 impl Page {
     pub fn new(url: Url) -> Option<Page> {
         assert!(!url.cannot_be_a_base());
         Some(Page {
-            url: url,
+            finalurl: url.clone(),
+            url,
             html: String::new(),
+            status: 0,
+            contenttype: None,
+            size: 0,
+            fetchtime: 0,
+            redirects: Vec::new(),
+            validators: Validators::default(),
+            hash: content_hash(b""),
         })
     }
 
-    pub fn fetch(&mut self) -> &str {
-        let response = match blocking::get(self.url.clone()) {
-            Ok(r) => r,
-            Err(e) => panic!("http get failed: {}", e.to_string()),
-        };
-        let html = match response.text() {
-            Ok(t) => t,
-            Err(e) => panic!("text extraction failed: {}", e.to_string()),
-        };
-        self.html = html;
-        &self.html
+    pub fn fetch(&mut self, fetcher: &Fetcher) -> Result<&str, FetchError> {
+        self.fetch_conditional(fetcher, &Validators::default())
+    }
+
+    /// Like `fetch()`, but asks the server to only send the page if it changed since `validators`.
+    /// If it didn't, `status` is 304 and the page is empty.
+    pub fn fetch_conditional(
+        &mut self,
+        fetcher: &Fetcher,
+        validators: &Validators,
+    ) -> Result<&str, FetchError> {
+        let response = fetcher.fetch_conditional(&self.url, validators)?;
+        self.finalurl = response.finalurl.clone();
+        self.status = response.status;
+        self.contenttype = response.contenttype().map(String::from);
+        self.size = response.body.len();
+        self.hash = content_hash(&response.body);
+        self.fetchtime = response.fetchtime;
+        self.html = response.text();
+        self.validators = response.validators();
+        self.redirects = response.redirects;
+        Ok(&self.html)
+    }
+
+    /// Returns a hash of the fetched body, for noticing when a page changes.
+    /// The body is hashed as it was sent, so changes that don't survive decoding it still count.
+    pub fn content_hash(&self) -> String {
+        self.hash.clone()
     }
 
+    /// Returns every http(s) link on the page, sorted and deduplicated.
     pub fn get_links(&self) -> Vec<Url> {
-        let mut links = get_href_links(&self.html);
-        links.append(&mut get_src_links(&self.html));
-        links.sort_unstable();
-        links.dedup();
+        let mut urls: Vec<Url> = self.get_html_links().into_iter().map(|l| l.url).collect();
+        urls.sort_unstable();
+        urls.dedup();
+        urls
+    }
+
+    /// Returns every http(s) link on the page in document order,
+    /// along with where in the html it was found.
+    ///
+    /// Relative links are resolved against the page's `<base href>` if it has one,
+    /// otherwise against the url the page was fetched from.
+    pub fn get_html_links(&self) -> Vec<Link> {
+        let (base, links) = html::extract_links(&self.html);
+        let base = base
+            .and_then(|b| self.finalurl.join(&b).ok())
+            .unwrap_or_else(|| self.finalurl.clone());
 
-        let mut urls: Vec<Url> = Vec::with_capacity(links.len());
+        let mut result = Vec::with_capacity(links.len());
         for l in links {
-            let u = match Url::parse(l) {
-                Ok(u) => u,
-                Err(ParseError::RelativeUrlWithoutBase) => match self.url.join(l) {
-                    Ok(u) => u,
-                    Err(e) => panic!("{e:?}"),
-                },
-                Err(e) => {
-                    println!(r#"failed to parse url "{l}": {e:?}"#);
-                    continue;
-                }
+            /* hrefs that aren't urls at all are left out, just like non-http(s) ones */
+            let Ok(url) = base.join(&l.value) else {
+                continue;
             };
+            /* skip mailto:, javascript:, data: and friends */
+            if url.scheme() != "http" && url.scheme() != "https" {
+                continue;
+            }
+            result.push(Link {
+                url,
+                kind: LinkKind::classify(&l.element, &l.attribute, &l.rel),
+                text: l.text,
+                rel: l.rel,
+                element: l.element,
+                attribute: l.attribute,
+            });
+        }
+        result
+    }
+}
+
+/// A link on a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub url: Url, // absolute url
+    pub kind: LinkKind,
+    pub text: Option<String>, // anchor text for hyperlinks, alt text for images
+    pub rel: Vec<String>,     // lowercase rel values (e.g. "nofollow", "me")
+    pub element: String, // element the link was found in (e.g. "a", "img"), empty for http redirects
+    pub attribute: String, // attribute the link was found in (e.g. "href", "srcset")
+}
+
+impl Link {
+    /// An http redirect to `url`.
+    pub fn redirect(url: Url) -> Link {
+        Link {
+            url,
+            kind: LinkKind::Redirect,
+            text: None,
+            rel: Vec::new(),
+            element: String::new(),
+            attribute: String::from("location"),
+        }
+    }
+
+    pub fn has_rel(&self, rel: &str) -> bool {
+        self.rel.iter().any(|r| r == rel)
+    }
+}
+
+/// What a link is used for.
+///
+/// Only `Hyperlink`s are links that a visitor can follow,
+/// everything else is something the page embeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LinkKind {
+    Hyperlink,
+    Image,
+    Stylesheet,
+    Script,
+    Iframe,
+    Media,    // audio, video, and other embedded objects
+    Redirect, // http redirects, meta refresh and javascript location stubs
+}
+
+impl LinkKind {
+    pub const ALL: [LinkKind; 7] = [
+        LinkKind::Hyperlink,
+        LinkKind::Image,
+        LinkKind::Stylesheet,
+        LinkKind::Script,
+        LinkKind::Iframe,
+        LinkKind::Media,
+        LinkKind::Redirect,
+    ];
 
-            /* if not base, assume it's relative and join with page url as base */
-            let mut based = u;
-            if based.cannot_be_a_base() {
-                based = match self.url.join(based.path()) {
-                    Ok(j) => j,
-                    Err(_) => {
-                        println!(r#"failed to join non-base url "{l}""#);
-                        continue;
-                    }
-                };
+    /// Works out the kind of a link from where it was found in the html.
+    pub fn classify(element: &str, attribute: &str, rel: &[String]) -> LinkKind {
+        match (element, attribute) {
+            ("meta", "content") | ("script", "location") => LinkKind::Redirect,
+            ("a" | "area" | "meta", _) => LinkKind::Hyperlink,
+            ("link", _) => {
+                if rel.iter().any(|r| r == "stylesheet") {
+                    LinkKind::Stylesheet
+                } else if rel.iter().any(|r| r.contains("icon")) {
+                    LinkKind::Image
+                } else {
+                    /* rel=me, alternate, next, prev, ... */
+                    LinkKind::Hyperlink
+                }
             }
+            ("script", _) => LinkKind::Script,
+            ("iframe" | "frame", _) => LinkKind::Iframe,
+            ("video", "poster") => LinkKind::Image,
+            ("video" | "audio" | "track" | "embed" | "object", _) => LinkKind::Media,
+            /* only <picture> sources have a srcset */
+            ("source", "srcset") => LinkKind::Image,
+            ("source", _) => LinkKind::Media,
+            /* css in a <style> element can import other stylesheets */
+            ("style", _) => LinkKind::Stylesheet,
+            _ => LinkKind::Image,
+        }
+    }
 
-            urls.push(based);
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkKind::Hyperlink => "hyperlink",
+            LinkKind::Image => "image",
+            LinkKind::Stylesheet => "stylesheet",
+            LinkKind::Script => "script",
+            LinkKind::Iframe => "iframe",
+            LinkKind::Media => "media",
+            LinkKind::Redirect => "redirect",
         }
-        urls
+    }
+
+    pub fn parse(s: &str) -> Option<LinkKind> {
+        LinkKind::ALL.into_iter().find(|k| k.as_str() == s)
     }
 }
 
-fn get_href_links(html: &str) -> Vec<&str> {
-    // let re = Regex::new(r#"<a(\s+|\s+.*?\s+)href="(.*?)"(\s*|\s+.*?\s+)>(.*?)<\/a(\s*|\s+.*?)>"#).unwrap();
-    // let re = Regex::new(r"\/[\w.-]+\/").unwrap();
-    let re = Regex::new(r#"href="(?<url>.*?)""#).unwrap();
-    re.captures_iter(html)
-        .map(|m| m.name("url").unwrap().as_str())
-        .collect()
+/// 64-bit FNV-1a hash of `bytes` as a hex string.
+///
+/// Not cryptographic, it only needs to tell whether content has changed.
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")
 }
 
-fn get_src_links(html: &str) -> Vec<&str> {
-    let re = Regex::new(r#"src="(?<url>.*?)""#).unwrap();
-    re.captures_iter(html)
-        .map(|m| m.name("url").unwrap().as_str())
-        .collect()
+#[cfg(test)]
+mod tests {
+    use super::{content_hash, LinkKind, Page};
+    use crate::testserver::{self, Response, TestServer};
+    use url::Url;
+
+    fn page(url: &str, html: &str) -> Page {
+        let mut p = Page::new(Url::parse(url).unwrap()).unwrap();
+        p.html = html.to_string();
+        p
+    }
+
+    #[test]
+    fn links_resolve_against_page() {
+        let p = page(
+            "https://dawnvoid.neocities.org/blog/post.html",
+            r##"<a href="other.html">a</a> <a href="/index.html">b</a>
+            <a href="https://koyo.neocities.org/">c</a> <a href="//errormine.neocities.org/x">d</a>
+            <a href="mailto:me@example.org">e</a> <a href="javascript:void(0)">f</a> <a href="#top">g</a>"##,
+        );
+        let links: Vec<String> = p.get_links().into_iter().map(String::from).collect();
+        assert_eq!(
+            links,
+            vec![
+                "https://dawnvoid.neocities.org/blog/other.html",
+                "https://dawnvoid.neocities.org/blog/post.html#top",
+                "https://dawnvoid.neocities.org/index.html",
+                "https://errormine.neocities.org/x",
+                "https://koyo.neocities.org/",
+            ]
+        );
+    }
+
+    #[test]
+    fn links_resolve_against_base() {
+        let p = page(
+            "https://dawnvoid.neocities.org/blog/post.html",
+            r#"<base href="/assets/"><img src="cat.png">"#,
+        );
+        let links = p.get_html_links();
+        assert_eq!(links.len(), 1);
+        assert_eq!(
+            links[0].url.as_str(),
+            "https://dawnvoid.neocities.org/assets/cat.png"
+        );
+        assert_eq!(links[0].element, "img");
+        assert_eq!(links[0].attribute, "src");
+        assert_eq!(links[0].kind, LinkKind::Image);
+    }
+
+    #[test]
+    fn link_kinds() {
+        let p = page(
+            "https://dawnvoid.neocities.org/",
+            r#"<link rel="stylesheet" href="style.css"><link rel="icon" href="favicon.ico">
+            <link rel="me" href="https://social.example/@dawnvoid">
+            <script src="app.js"></script>
+            <a href="https://koyo.neocities.org/" rel="nofollow">koyo</a>
+            <img src="https://koyo.neocities.org/cat.png" alt="a cat">
+            <iframe src="https://errormine.neocities.org/widget.html"></iframe>
+            <video src="clip.mp4" poster="clip.png"></video>
+            <picture><source srcset="big.webp"></picture>
+            <div style="background: url(bg.gif)"></div>"#,
+        );
+        let links = p.get_html_links();
+        let kinds: Vec<(&str, LinkKind)> = links
+            .iter()
+            .map(|l| (l.url.path().rsplit('/').next().unwrap(), l.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("style.css", LinkKind::Stylesheet),
+                ("favicon.ico", LinkKind::Image),
+                ("@dawnvoid", LinkKind::Hyperlink),
+                ("app.js", LinkKind::Script),
+                ("", LinkKind::Hyperlink),
+                ("cat.png", LinkKind::Image),
+                ("widget.html", LinkKind::Iframe),
+                ("clip.mp4", LinkKind::Media),
+                ("clip.png", LinkKind::Image),
+                ("big.webp", LinkKind::Image),
+                ("bg.gif", LinkKind::Image),
+            ]
+        );
+
+        let koyo = &links[4];
+        assert_eq!(koyo.text.as_deref(), Some("koyo"));
+        assert!(koyo.has_rel("nofollow"));
+        let cat = &links[5];
+        assert_eq!(cat.text.as_deref(), Some("a cat"));
+    }
+
+    #[test]
+    fn content_hash_of_body() {
+        /* invalid utf-8 decodes to the same replacement character either way */
+        let body = |bytes: &[u8]| Response {
+            body: bytes.to_vec(),
+            ..Response::html("")
+        };
+        let server = TestServer::start(vec![
+            ("/a", body(b"<p>\xff</p>")),
+            ("/b", body(b"<p>\xfe</p>")),
+        ]);
+        let fetcher = testserver::fetcher();
+        let mut a = Page::new(server.url("/a")).unwrap();
+        a.fetch(&fetcher).unwrap();
+        let mut b = Page::new(server.url("/b")).unwrap();
+        b.fetch(&fetcher).unwrap();
+        assert_eq!(a.html, b.html);
+        assert_eq!(a.content_hash(), content_hash(b"<p>\xff</p>"));
+        assert_ne!(a.content_hash(), b.content_hash());
+    }
+
+    #[test]
+    fn content_hash_fnv1a() {
+        // reference values for 64-bit FNV-1a
+        assert_eq!(content_hash(b""), "cbf29ce484222325");
+        assert_eq!(content_hash(b"a"), "af63dc4c8601ec8c");
+        assert_ne!(content_hash(b"<p>hi</p>"), content_hash(b"<p>hi!</p>"));
+    }
 }
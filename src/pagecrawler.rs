@@ -1,65 +1,442 @@
-use crate::page::Page;
+use crate::canonical::canonicalize;
+use crate::fetcher::{FetchError, Fetcher, Validators};
+use crate::page::{Link, Page};
+use crate::robots::RobotsCache;
+use crate::site::{SiteId, SiteResolver};
+use chrono::Utc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use url::Url;
 
 pub struct PageCrawler {
     url: Url,
-    links: Vec<Url>,
-    pages: Vec<Url>,
+    fetcher: Fetcher,
+    links: Vec<Url>, // every distinct url seen, in the order it was found
+    seen: HashSet<Url>,
+    done: HashSet<Url>, // pages that were fetched (or blocked), including ones reached by redirects
+    frontier: VecDeque<(Url, usize)>, // pages waiting to be fetched, with their depth
+    crawled: Vec<CrawledPage>,
+    known: HashMap<Url, KnownPage>, // what an earlier crawl found, by url
+    robots: RobotsCache,
+    sites: SiteResolver,
+    customdomains: Vec<String>, // custom domains the site turned out to be served from
+    maxdepth: Option<usize>,    // how many links away from `url` to go
+    maxpages: Option<usize>,    // how many pages to crawl at most
+    fetched: usize,             // pages fetched (or blocked) so far, not counting redirect hops
+}
+
+/// Everything the crawler learned about a single fetched page.
+#[derive(Debug, Clone)]
+pub struct CrawledPage {
+    pub url: Url,
+    pub status: Option<u16>, // `None` if the page couldn't be fetched
+    pub contenttype: Option<String>,
+    pub size: usize, // size of the body in bytes
+    pub fetchtime: i64,
+    pub hash: Option<String>,
+    pub links: Vec<Link>, // every link on the page, in document order
+    pub blocked: bool,    // not fetched because robots.txt disallows it
+    pub depth: usize,     // number of links between the start page and this one
+    pub validators: Validators,
+    pub unchanged: Option<Unchanged>, // set if the page is the same as in the earlier crawl
+    pub error: Option<FetchError>,    // why the page couldn't be fetched, if it couldn't
+}
+
+/// Why a page was left as an earlier crawl found it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unchanged {
+    NotModified, // the server answered 304, so the page wasn't downloaded again
+    SameContent, // the page was downloaded again, but its hash didn't change
+}
+
+/// What an earlier crawl found at a url, so revisits can skip unchanged pages.
+#[derive(Debug, Clone)]
+pub struct KnownPage {
+    pub url: Url,
+    pub status: Option<u16>,
+    pub contenttype: Option<String>,
+    pub size: usize,
+    pub hash: Option<String>,
+    pub validators: Validators,
+    pub links: Vec<Link>,
+}
+
+/// Totals over the pages of one or more crawls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrawlReport {
+    pub pages: usize,       // pages crawled, unchanged or not
+    pub bytes: u64,         // bytes downloaded
+    pub notmodified: usize, // pages the server answered with a 304
+    pub samecontent: usize, // pages downloaded again that hadn't changed
+    pub bytessaved: u64,    // bytes that didn't have to be downloaded thanks to 304s
+    pub panicked: Vec<Url>, // sites whose crawl panicked, so nothing of them was written
+}
+
+impl CrawlReport {
+    pub fn add(&mut self, page: &CrawledPage) {
+        self.pages += 1;
+        match page.unchanged {
+            Some(Unchanged::NotModified) => {
+                self.notmodified += 1;
+                self.bytessaved += page.size as u64;
+            }
+            Some(Unchanged::SameContent) => {
+                self.samecontent += 1;
+                self.bytes += page.size as u64;
+            }
+            None => self.bytes += page.size as u64,
+        }
+    }
+
+    pub fn merge(&mut self, other: &CrawlReport) {
+        self.pages += other.pages;
+        self.bytes += other.bytes;
+        self.notmodified += other.notmodified;
+        self.samecontent += other.samecontent;
+        self.bytessaved += other.bytessaved;
+        self.panicked.extend(other.panicked.iter().cloned());
+    }
+
+    /// Pages whose links didn't have to be extracted again.
+    pub fn pagessaved(&self) -> usize {
+        self.notmodified + self.samecontent
+    }
+}
+
+impl fmt::Display for CrawlReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pages, {} bytes downloaded, {} pages unchanged ({} not modified, {} same content), {} bytes saved",
+            self.pages,
+            self.bytes,
+            self.pagessaved(),
+            self.notmodified,
+            self.samecontent,
+            self.bytessaved
+        )
+    }
 }
 
 impl PageCrawler {
-    pub fn new(url: Url) -> Result<PageCrawler, String> {
+    pub fn new(url: Url, fetcher: Fetcher) -> Result<PageCrawler, String> {
         if url.cannot_be_a_base() {
             return Err(String::from("invalid url"));
         }
         Ok(PageCrawler {
-            url: url,
+            url: canonicalize(&url),
+            fetcher,
             links: Vec::new(),
-            pages: Vec::new(),
+            seen: HashSet::new(),
+            done: HashSet::new(),
+            frontier: VecDeque::new(),
+            crawled: Vec::new(),
+            known: HashMap::new(),
+            robots: RobotsCache::new(),
+            sites: SiteResolver::new(),
+            customdomains: Vec::new(),
+            maxdepth: None,
+            maxpages: None,
+            fetched: 0,
         })
     }
 
-    pub fn crawl(&mut self) {
-        let mut frontier: Vec<Url> = Vec::new();
-        frontier.push(self.url.clone());
+    /// Uses `robots` instead of an empty cache, so robots.txt files that
+    /// were already fetched (or are provided by tests) are reused.
+    pub fn with_robots(mut self, robots: RobotsCache) -> PageCrawler {
+        self.robots = robots;
+        self
+    }
+
+    /// Uses `sites` to tell which urls are on the site being crawled,
+    /// so pages on custom domains that are already known are crawled too.
+    pub fn with_sites(mut self, sites: SiteResolver) -> PageCrawler {
+        self.sites = sites;
+        self
+    }
 
-        /* perform bfs */
-        while !frontier.is_empty() {
-            let currenturl = frontier.pop().unwrap(); /* should never fail */
+    /// Revisits `pages` from an earlier crawl of the site conditionally,
+    /// and keeps what was found on the ones that haven't changed instead of parsing them again.
+    pub fn with_known_pages(mut self, pages: Vec<KnownPage>) -> PageCrawler {
+        self.known = pages
+            .into_iter()
+            .map(|p| (canonicalize(&p.url), p))
+            .collect();
+        self
+    }
 
-            // println!("processing {}", currenturl.as_str());
+    /// Only crawls pages at most `maxdepth` links away from the start page
+    /// (0 crawls just the start page).
+    pub fn with_max_depth(mut self, maxdepth: usize) -> PageCrawler {
+        self.maxdepth = Some(maxdepth);
+        self
+    }
+
+    /// Stops after `maxpages` pages, including pages blocked by robots.txt.
+    /// The redirects followed on the way to a page don't count.
+    pub fn with_max_pages(mut self, maxpages: usize) -> PageCrawler {
+        self.maxpages = Some(maxpages);
+        self
+    }
 
-            /* only process pages that we haven't processed before */
-            /* slow but i don't care right now */
-            if self.pages.contains(&currenturl) {
+    /// Crawls the site breadth first, so when a budget runs out
+    /// it's the pages furthest from the start page that are left out.
+    pub fn crawl(&mut self) {
+        self.enqueue(self.url.clone(), 0);
+
+        while let Some((currenturl, depth)) = self.frontier.pop_front() {
+            if self.maxpages.is_some_and(|m| self.fetched >= m) {
+                break;
+            }
+            /* a redirect might have got here before the queue did */
+            if !self.done.insert(currenturl.clone()) {
                 continue;
             }
+            self.fetched += 1;
 
-            self.links.push(currenturl.clone());
+            /* ask robots.txt first, and remember pages we aren't allowed to fetch */
+            let useragent = self.fetcher.useragent();
+            let robots = self.robots.get(&self.fetcher, &currenturl);
+            if !robots.is_allowed(useragent, &currenturl) {
+                let error = self.robots.error(&currenturl).cloned();
+                self.crawled.push(CrawledPage {
+                    url: currenturl,
+                    status: None,
+                    contenttype: None,
+                    size: 0,
+                    fetchtime: Utc::now().timestamp(),
+                    hash: None,
+                    links: Vec::new(),
+                    blocked: true,
+                    depth,
+                    validators: Validators::default(),
+                    unchanged: None,
+                    error,
+                });
+                continue;
+            }
+            /* the fetcher does the waiting, it just needs to know about the crawl-delay */
+            if let (Some(delay), Some(host)) =
+                (robots.crawl_delay(useragent), currenturl.host_str())
+            {
+                self.fetcher.politeness().set_host_delay(host, delay);
+            }
 
-            /* try to only visit html pages */
-            let cd = currenturl.domain().unwrap();
-            let d = self.url.domain().unwrap();
-            if cd != d {
+            let known = self.known.remove(&currenturl);
+            let validators = known
+                .as_ref()
+                .map(|k| k.validators.clone())
+                .unwrap_or_default();
+            let mut currentpage = Page::new(currenturl.clone()).unwrap(); /* should never fail as long as url was constructed correctly */
+            if let Err(e) = currentpage.fetch_conditional(&self.fetcher, &validators) {
+                /* remember the page as dead and carry on with the rest of the site */
+                self.crawled.push(CrawledPage {
+                    url: currentpage.url,
+                    status: None,
+                    contenttype: None,
+                    size: 0,
+                    fetchtime: Utc::now().timestamp(),
+                    hash: None,
+                    links: Vec::new(),
+                    blocked: false,
+                    depth,
+                    validators: Validators::default(),
+                    unchanged: None,
+                    error: Some(e),
+                });
                 continue;
             }
-            if !is_url_html(&currenturl) {
+            if depth == 0 {
+                self.learn_custom_domain(&currentpage.url, &currentpage.finalurl);
+            }
+            let known = match known {
+                Some(k) if currentpage.status == 304 => {
+                    self.reuse(k, currenturl, &currentpage, depth, Unchanged::NotModified);
+                    continue;
+                }
+                k => k,
+            };
+
+            /* every hop of a redirect chain on this site is a page that links to the next hop */
+            let redirects = std::mem::take(&mut currentpage.redirects);
+            for r in &redirects {
+                let from = canonicalize(&r.from);
+                if from != currenturl
+                    && (!self.sites.is_in_site(&from, &self.url) || !self.done.insert(from.clone()))
+                {
+                    continue;
+                }
+                self.remember(canonicalize(&r.to));
+                self.crawled.push(CrawledPage {
+                    url: from,
+                    status: Some(r.status),
+                    contenttype: None,
+                    size: 0,
+                    fetchtime: currentpage.fetchtime,
+                    hash: None,
+                    links: vec![Link::redirect(r.to.clone())],
+                    blocked: false,
+                    depth,
+                    validators: Validators::default(),
+                    unchanged: None,
+                    error: None,
+                });
+            }
+            /* the page at the end of the chain belongs to whichever site it is on */
+            let url = canonicalize(&currentpage.finalurl);
+            if !redirects.is_empty()
+                && (!self.sites.is_in_site(&url, &self.url) || !self.done.insert(url.clone()))
+            {
                 continue;
             }
 
-            // println!("processing {}", currenturl.as_str());
+            /* a page that was downloaded again but hasn't changed doesn't need parsing again */
+            let known = if redirects.is_empty() {
+                known
+            } else {
+                self.known.remove(&url)
+            };
+            let hash = currentpage.content_hash();
+            if let Some(k) = known {
+                if k.status == Some(currentpage.status) && k.hash.as_ref() == Some(&hash) {
+                    self.reuse(k, url, &currentpage, depth, Unchanged::SameContent);
+                    continue;
+                }
+            }
+
+            let links = currentpage.get_html_links();
+            for l in &links {
+                self.enqueue(l.url.clone(), depth + 1);
+            }
+
+            self.crawled.push(CrawledPage {
+                url,
+                status: Some(currentpage.status),
+                contenttype: currentpage.contenttype.clone(),
+                size: currentpage.size,
+                fetchtime: currentpage.fetchtime,
+                hash: Some(hash),
+                links,
+                blocked: false,
+                depth,
+                validators: currentpage.validators.clone(),
+                unchanged: None,
+                error: None,
+            });
+        }
+    }
+
+    /// Records `known` as crawled again at `url` just now, and follows its links.
+    fn reuse(&mut self, known: KnownPage, url: Url, page: &Page, depth: usize, why: Unchanged) {
+        for l in &known.links {
+            self.enqueue(l.url.clone(), depth + 1);
+        }
+        /* a 304 doesn't have to repeat the validators */
+        let validators = if page.validators.is_empty() {
+            known.validators
+        } else {
+            page.validators.clone()
+        };
+        self.crawled.push(CrawledPage {
+            url,
+            status: known.status,
+            contenttype: known.contenttype,
+            size: known.size,
+            fetchtime: page.fetchtime,
+            hash: known.hash,
+            links: known.links,
+            blocked: false,
+            depth,
+            validators,
+            unchanged: Some(why),
+            error: None,
+        });
+    }
+
+    /// Neocities sites with a custom domain redirect there, so if the start page
+    /// ends up on the same path of a domain that isn't a known site,
+    /// that domain is the site's custom domain.
+    fn learn_custom_domain(&mut self, url: &Url, finalurl: &Url) {
+        let Some(site) = SiteId::from_url(url) else {
+            return;
+        };
+        let Some(domain) = finalurl.domain() else {
+            return;
+        };
+        if finalurl.path() != url.path() || self.sites.resolve(finalurl).is_some() {
+            return;
+        }
+        self.sites.add_domain(domain, site);
+        self.customdomains.push(domain.to_ascii_lowercase());
+    }
+
+    /// Remembers `url`, and queues it for fetching if it's an html page on this site
+    /// that hasn't been seen before and is within the depth budget.
+    fn enqueue(&mut self, url: Url, depth: usize) {
+        let url = canonicalize(&url);
+        if !self.remember(url.clone()) {
+            return;
+        }
 
-            self.pages.push(currenturl.clone());
+        /* try to only visit html pages */
+        if !self.sites.is_in_site(&url, &self.url) || !is_url_html(&url) {
+            return;
+        }
+        if self.maxdepth.is_some_and(|m| depth > m) {
+            return;
+        }
+        self.frontier.push_back((url, depth));
+    }
 
-            let mut currentpage = Page::new(currenturl).unwrap(); /* should never fail as long as url was constructed correctly */
-            let _ = currentpage.fetch();
-            frontier.append(&mut currentpage.get_links());
+    /// Remembers the canonical `url` as found, without queueing it.
+    /// Returns whether it is new.
+    fn remember(&mut self, url: Url) -> bool {
+        if !self.seen.insert(url.clone()) {
+            return false;
         }
+        self.links.push(url);
+        true
     }
 
+    /// Returns every distinct url found during the crawl (canonicalized),
+    /// including links to other sites and to things that aren't pages.
     pub fn get_links(&self) -> Vec<Url> {
         self.links.clone()
     }
+
+    /// Returns every page that was fetched during the crawl,
+    /// including pages that were skipped because of robots.txt.
+    pub fn get_pages(&self) -> &[CrawledPage] {
+        &self.crawled
+    }
+
+    /// Like `get_pages()`, but hands over the pages instead of borrowing them.
+    pub fn into_pages(self) -> Vec<CrawledPage> {
+        self.crawled
+    }
+
+    /// Returns totals over every page crawled so far.
+    pub fn report(&self) -> CrawlReport {
+        let mut report = CrawlReport::default();
+        for p in &self.crawled {
+            report.add(p);
+        }
+        report
+    }
+
+    /// Returns the custom domains that the site turned out to be served from.
+    pub fn get_custom_domains(&self) -> &[String] {
+        &self.customdomains
+    }
+
+    /// Returns the pages that were skipped because of robots.txt.
+    pub fn get_blocked_pages(&self) -> Vec<&Url> {
+        self.crawled
+            .iter()
+            .filter(|p| p.blocked)
+            .map(|p| &p.url)
+            .collect()
+    }
 }
 
 pub fn is_url_html(url: &Url) -> bool {
@@ -75,7 +452,7 @@ pub fn is_url_html(url: &Url) -> bool {
     ok
 }
 
-fn is_url_image(url: &Url) -> bool {
+pub fn is_url_image(url: &Url) -> bool {
     let path = url.path().to_lowercase();
     if path.ends_with(".png") {
         return true;
@@ -97,3 +474,346 @@ fn is_url_image(url: &Url) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PageCrawler;
+    use crate::fetcher::FetchError;
+    use crate::page::LinkKind;
+    use crate::politeness::{MockClock, Politeness, PolitenessConfig};
+    use crate::robots::{Robots, RobotsCache};
+    use crate::testserver::{self, Response, TestServer};
+    use std::collections::HashSet;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn crawl_keeps_going_after_dead_page() {
+        let server = TestServer::start(vec![
+            (
+                "/",
+                Response::html(r#"<a href="/missing.html">x</a> <a href="/about.html">y</a>"#),
+            ),
+            ("/about.html", Response::html("about")),
+        ]);
+        let fetcher = testserver::fetcher();
+
+        let mut crawler = PageCrawler::new(server.url("/"), fetcher).unwrap();
+        crawler.crawl();
+
+        let pages = crawler.get_pages();
+        assert_eq!(pages.len(), 3);
+        let missing = pages
+            .iter()
+            .find(|p| p.url == server.url("/missing.html"))
+            .unwrap();
+        assert_eq!(missing.status, Some(404));
+        let about = pages
+            .iter()
+            .find(|p| p.url == server.url("/about.html"))
+            .unwrap();
+        assert_eq!(about.status, Some(200));
+        assert!(pages.iter().all(|p| p.error.is_none()));
+    }
+
+    #[test]
+    fn crawl_records_fetch_errors() {
+        /* nothing listens on a port that was just given back */
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = Url::parse(&format!("http://127.0.0.1:{port}/")).unwrap();
+
+        let mut robots = RobotsCache::new();
+        robots.insert(&url.origin().ascii_serialization(), Robots::allow_all());
+
+        let mut crawler = PageCrawler::new(url.clone(), testserver::fetcher())
+            .unwrap()
+            .with_robots(robots);
+        crawler.crawl();
+
+        let pages = crawler.get_pages();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].status, None);
+        assert!(matches!(pages[0].error, Some(FetchError::Connect(ref u, _)) if *u == url));
+    }
+
+    #[test]
+    fn crawl_obeys_robots() {
+        let server = TestServer::start(vec![
+            (
+                "/robots.txt",
+                Response::new(200, "User-agent: *\nDisallow: /private/\n"),
+            ),
+            (
+                "/",
+                Response::html(
+                    r#"<a href="/private/diary.html">x</a> <a href="/about.html">y</a>"#,
+                ),
+            ),
+            ("/about.html", Response::html("about")),
+            ("/private/diary.html", Response::html("secret")),
+        ]);
+        let fetcher = testserver::fetcher();
+
+        let mut crawler = PageCrawler::new(server.url("/"), fetcher).unwrap();
+        crawler.crawl();
+
+        assert_eq!(
+            crawler.get_blocked_pages(),
+            vec![&server.url("/private/diary.html")]
+        );
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert!(!paths.contains(&String::from("/private/diary.html")));
+        assert_eq!(paths.iter().filter(|&p| p == "/robots.txt").count(), 1);
+    }
+
+    #[test]
+    fn crawl_obeys_crawl_delay() {
+        let server = TestServer::start(vec![
+            (
+                "/robots.txt",
+                Response::new(200, "User-agent: *\nCrawl-delay: 3\n"),
+            ),
+            ("/", Response::html(r#"<a href="/about.html">y</a>"#)),
+            ("/about.html", Response::html("about")),
+        ]);
+        let clock = Arc::new(MockClock::new());
+        let fetcher = testserver::fetcher().with_politeness(Politeness::with_clock(
+            PolitenessConfig::none(),
+            clock.clone(),
+        ));
+
+        let mut crawler = PageCrawler::new(server.url("/"), fetcher.clone()).unwrap();
+        crawler.crawl();
+
+        assert_eq!(crawler.get_pages().len(), 2);
+        assert_eq!(
+            fetcher.politeness().host_delay("localhost"),
+            Duration::from_secs(3)
+        );
+        // robots.txt and / go out right away, /about.html has to wait
+        assert_eq!(clock.elapsed(), Duration::from_secs(3));
+    }
+
+    /// / links to a and b, a links to c, b links to d, and every page links back to /.
+    fn tree() -> TestServer {
+        TestServer::start(vec![
+            (
+                "/",
+                Response::html(r#"<a href="/a.html">a</a> <a href="/b.html#top">b</a>"#),
+            ),
+            (
+                "/a.html",
+                Response::html(r#"<a href="/">home</a> <a href="/c.html">c</a>"#),
+            ),
+            (
+                "/b.html",
+                Response::html(
+                    r#"<a href="/">home</a> <a href="/d.html">d</a> <a href="/b.html#bottom">b</a>"#,
+                ),
+            ),
+            ("/c.html", Response::html(r#"<a href="/a.html">a</a>"#)),
+            ("/d.html", Response::html(r#"<a href="/c.html">c</a>"#)),
+        ])
+    }
+
+    fn fetched_paths(server: &TestServer) -> Vec<String> {
+        server
+            .requests()
+            .into_iter()
+            .map(|r| r.path)
+            .filter(|p| p != "/robots.txt")
+            .collect()
+    }
+
+    #[test]
+    fn crawl_breadth_first_once() {
+        let server = tree();
+        let mut crawler = PageCrawler::new(server.url("/"), testserver::fetcher()).unwrap();
+        crawler.crawl();
+
+        assert_eq!(
+            fetched_paths(&server),
+            vec!["/", "/a.html", "/b.html", "/c.html", "/d.html"]
+        );
+        let depths: Vec<usize> = crawler.get_pages().iter().map(|p| p.depth).collect();
+        assert_eq!(depths, vec![0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn crawl_max_depth() {
+        let server = tree();
+        let mut crawler = PageCrawler::new(server.url("/"), testserver::fetcher())
+            .unwrap()
+            .with_max_depth(1);
+        crawler.crawl();
+
+        assert_eq!(fetched_paths(&server), vec!["/", "/a.html", "/b.html"]);
+        // links beyond the budget are still known
+        assert!(crawler.get_links().contains(&server.url("/c.html")));
+    }
+
+    #[test]
+    fn crawl_max_pages() {
+        let server = tree();
+        let mut crawler = PageCrawler::new(server.url("/"), testserver::fetcher())
+            .unwrap()
+            .with_max_pages(2);
+        crawler.crawl();
+
+        assert_eq!(fetched_paths(&server), vec!["/", "/a.html"]);
+        assert_eq!(crawler.get_pages().len(), 2);
+    }
+
+    #[test]
+    fn crawl_max_pages_skips_redirects() {
+        let server = TestServer::start(vec![
+            (
+                "/",
+                Response::html(r#"<a href="/old.html">old</a> <a href="/a.html">a</a>"#),
+            ),
+            (
+                "/old.html",
+                Response::new(301, "").header("Location", "/new.html"),
+            ),
+            ("/new.html", Response::html("new")),
+            ("/a.html", Response::html("a")),
+        ]);
+        let mut crawler = PageCrawler::new(server.url("/"), testserver::fetcher())
+            .unwrap()
+            .with_max_pages(3);
+        crawler.crawl();
+
+        // the hop through /old.html is recorded, but only /new.html counts as a page
+        assert_eq!(
+            fetched_paths(&server),
+            vec!["/", "/old.html", "/new.html", "/a.html"]
+        );
+        assert_eq!(crawler.get_pages().len(), 4);
+    }
+
+    #[test]
+    fn crawl_large_site() {
+        // every page links to the next few pages and back to the first ones,
+        // so each page is linked to from many places
+        let n = 500;
+        let bodies: Vec<(String, String)> = (0..n)
+            .map(|i| {
+                let links: String = [i + 1, i + 2, i + 3, 0, 1, 2]
+                    .iter()
+                    .filter(|&&j| j < n)
+                    .map(|j| format!(r#"<a href="/p{j}.html">{j}</a>"#))
+                    .collect();
+                let path = if i == 0 {
+                    String::from("/")
+                } else {
+                    format!("/p{i}.html")
+                };
+                (path, links)
+            })
+            .collect();
+        let server = TestServer::start(
+            bodies
+                .iter()
+                .map(|(p, b)| (p.as_str(), Response::html(b)))
+                .collect(),
+        );
+
+        let mut crawler = PageCrawler::new(server.url("/"), testserver::fetcher()).unwrap();
+        crawler.crawl();
+
+        // p0.html is a separate url from /, everything else is fetched exactly once
+        let paths = fetched_paths(&server);
+        let unique: HashSet<&String> = paths.iter().collect();
+        assert_eq!(paths.len(), unique.len());
+        assert_eq!(paths.len(), n + 1);
+    }
+
+    #[test]
+    fn crawl_learns_custom_domain() {
+        // the neocities site redirects to its custom domain, like neocities does
+        let custom = TestServer::start(vec![
+            ("/", Response::html(r#"<a href="/about.html">about</a>"#)),
+            ("/about.html", Response::html("about")),
+        ]);
+        let neocities = TestServer::start(vec![(
+            "/",
+            Response::new(301, "").header("Location", custom.url("/").as_str()),
+        )]);
+        let fetcher =
+            testserver::fetcher().with_host_override("alice.neocities.org", neocities.url("/"));
+
+        let root = Url::parse("https://alice.neocities.org/").unwrap();
+        let mut crawler = PageCrawler::new(root, fetcher).unwrap();
+        crawler.crawl();
+
+        assert_eq!(crawler.get_custom_domains(), ["localhost"]);
+        assert!(crawler
+            .get_pages()
+            .iter()
+            .any(|p| p.url == custom.url("/about.html")));
+    }
+
+    #[test]
+    fn crawl_records_redirect_chains() {
+        let server = TestServer::start(vec![
+            (
+                "/",
+                Response::html(
+                    r#"<a href="/old.html">old</a> <a href="/moved.html">moved</a> <a href="/new.html">new</a>"#,
+                ),
+            ),
+            (
+                "/old.html",
+                Response::new(301, "").header("Location", "/older.html"),
+            ),
+            (
+                "/older.html",
+                Response::new(302, "").header("Location", "/new.html"),
+            ),
+            ("/new.html", Response::html(r#"<a href="/">home</a>"#)),
+            (
+                "/moved.html",
+                Response::html(r#"<meta http-equiv="refresh" content="0; url=/new.html">"#),
+            ),
+        ]);
+        let mut crawler = PageCrawler::new(server.url("/"), testserver::fetcher()).unwrap();
+        crawler.crawl();
+
+        // the page at the end of the chain is only fetched once
+        assert_eq!(
+            fetched_paths(&server),
+            vec!["/", "/old.html", "/older.html", "/new.html", "/moved.html"]
+        );
+
+        let page = |path: &str| {
+            crawler
+                .get_pages()
+                .iter()
+                .find(|p| p.url == server.url(path))
+                .unwrap()
+        };
+        let hops: Vec<(Option<u16>, &str, LinkKind)> = ["/old.html", "/older.html"]
+            .iter()
+            .map(|&p| {
+                let link = &page(p).links[0];
+                (page(p).status, link.url.path(), link.kind)
+            })
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (Some(301), "/older.html", LinkKind::Redirect),
+                (Some(302), "/new.html", LinkKind::Redirect)
+            ]
+        );
+        assert_eq!(page("/new.html").status, Some(200));
+        assert_eq!(page("/moved.html").links[0].kind, LinkKind::Redirect);
+        assert_eq!(crawler.get_pages().len(), 5);
+    }
+}
@@ -0,0 +1,382 @@
+//! Finding how one site reaches another through links.
+//!
+//! Unlike `analysis::LinkGraph`, nothing is loaded up front: a `PathFinder` asks the
+//! `Database` for the links of each site as a search reaches it (which the indexes on
+//! link sources and destinations make cheap) and keeps them for later searches.
+//! Shortest paths are searched from both ends at once, so a search only reads
+//! the sites around the shortest path rather than everything within its length.
+//!
+//! Links are followed as they are stored, so a path through a site that moved
+//! goes through the old site and its redirect.
+
+use crate::database::{Database, DatabaseError, SiteEntry};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+use url::Url;
+
+/// Searches for paths between sites in a `Database`.
+pub struct PathFinder<'a> {
+    db: &'a Database,
+    maxhops: Option<usize>,
+    outlinks: HashMap<String, Rc<[String]>>,
+    inlinks: HashMap<String, Rc<[String]>>,
+}
+
+/// Where a search came from on one side: the previous site and how many links away it is.
+type Visited = HashMap<String, (Option<String>, usize)>;
+
+impl<'a> PathFinder<'a> {
+    pub fn new(db: &'a Database) -> PathFinder<'a> {
+        PathFinder {
+            db,
+            maxhops: None,
+            outlinks: HashMap::new(),
+            inlinks: HashMap::new(),
+        }
+    }
+
+    /// Leaves out paths with more than `maxhops` links, which also stops
+    /// searches for sites that are far apart or can't reach each other early.
+    pub fn with_max_hops(mut self, maxhops: usize) -> PathFinder<'a> {
+        self.maxhops = Some(maxhops);
+        self
+    }
+
+    /// Returns one of the shortest paths from the site at `from` to the site at `to`,
+    /// as the url of every site on it, or `None` if it can't be reached.
+    ///
+    /// Sites are tried in order of their url, so the same database always gives the same path.
+    pub fn shortest_path(
+        &mut self,
+        from: &str,
+        to: &str,
+    ) -> Result<Option<Vec<String>>, DatabaseError> {
+        self.search(from, to, &HashSet::new(), &HashSet::new(), self.maxhops)
+    }
+
+    /// Returns up to `k` paths from `from` to `to` that don't visit any site twice,
+    /// shortest first, using Yen's algorithm.
+    pub fn shortest_paths(
+        &mut self,
+        from: &str,
+        to: &str,
+        k: usize,
+    ) -> Result<Vec<Vec<String>>, DatabaseError> {
+        let mut paths: Vec<Vec<String>> = Vec::new();
+        if k == 0 {
+            return Ok(paths);
+        }
+        match self.shortest_path(from, to)? {
+            Some(p) => paths.push(p),
+            None => return Ok(paths),
+        }
+        /* ordered by length and then by the sites on them, so ties come out the same every time */
+        let mut candidates: BTreeSet<(usize, Vec<String>)> = BTreeSet::new();
+        while paths.len() < k {
+            let last = paths.last().unwrap().clone();
+            for i in 0..last.len() - 1 {
+                /* take the path up to its i-th site, and go somewhere else from there */
+                let root = &last[..=i];
+                let mut bannedlinks = HashSet::new();
+                for p in &paths {
+                    if p.len() > i + 1 && p[..=i] == *root {
+                        bannedlinks.insert((p[i].clone(), p[i + 1].clone()));
+                    }
+                }
+                let bannedsites: HashSet<String> = root[..i].iter().cloned().collect();
+                let maxhops = self.maxhops.map(|m| m.saturating_sub(i));
+                let Some(spur) = self.search(&last[i], to, &bannedsites, &bannedlinks, maxhops)?
+                else {
+                    continue;
+                };
+                let mut path = root[..i].to_vec();
+                path.extend(spur);
+                if !paths.contains(&path) {
+                    candidates.insert((path.len(), path));
+                }
+            }
+            match candidates.pop_first() {
+                Some((_, p)) => paths.push(p),
+                None => break,
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Returns every site that `from` reaches with at most `maxhops` links,
+    /// with the number of links it takes, nearest first and then by url.
+    pub fn reachable(
+        &mut self,
+        from: &str,
+        maxhops: usize,
+    ) -> Result<Vec<(String, usize)>, DatabaseError> {
+        let mut seen: HashSet<String> = HashSet::from([from.to_string()]);
+        let mut frontier = vec![from.to_string()];
+        let mut resultlist = Vec::new();
+        for hops in 1..=maxhops {
+            let mut next = Vec::new();
+            for site in &frontier {
+                for w in self.outlinks(site)?.iter() {
+                    if seen.insert(w.clone()) {
+                        next.push(w.clone());
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            next.sort();
+            resultlist.extend(next.iter().map(|s| (s.clone(), hops)));
+            frontier = next;
+        }
+        Ok(resultlist)
+    }
+
+    /// Breadth-first search from both ends, one whole level at a time on whichever side
+    /// has fewer sites to look at, never visiting `bannedsites` or following `bannedlinks`.
+    fn search(
+        &mut self,
+        from: &str,
+        to: &str,
+        bannedsites: &HashSet<String>,
+        bannedlinks: &HashSet<(String, String)>,
+        maxhops: Option<usize>,
+    ) -> Result<Option<Vec<String>>, DatabaseError> {
+        if from == to {
+            return Ok(Some(vec![from.to_string()]));
+        }
+        let mut forward: Visited = HashMap::from([(from.to_string(), (None, 0))]);
+        let mut backward: Visited = HashMap::from([(to.to_string(), (None, 0))]);
+        let mut forwardfrontier = vec![from.to_string()];
+        let mut backwardfrontier = vec![to.to_string()];
+        let (mut forwarddepth, mut backwarddepth) = (0, 0);
+
+        while !forwardfrontier.is_empty() && !backwardfrontier.is_empty() {
+            if maxhops.is_some_and(|m| forwarddepth + backwarddepth >= m) {
+                break;
+            }
+            let isforward = forwardfrontier.len() <= backwardfrontier.len();
+            let (frontier, visited, other) = if isforward {
+                forwarddepth += 1;
+                (&mut forwardfrontier, &mut forward, &backward)
+            } else {
+                backwarddepth += 1;
+                (&mut backwardfrontier, &mut backward, &forward)
+            };
+
+            /* the first meeting isn't always the shortest, so finish the level and take the best */
+            let mut best: Option<(usize, String, String)> = None;
+            let mut next = Vec::new();
+            for v in frontier.iter() {
+                let depth = visited[v].1;
+                let neighbours = if isforward {
+                    self.outlinks(v)?
+                } else {
+                    self.inlinks(v)?
+                };
+                for w in neighbours.iter() {
+                    if bannedsites.contains(w) {
+                        continue;
+                    }
+                    if !bannedlinks.is_empty() {
+                        let link = if isforward {
+                            (v.clone(), w.clone())
+                        } else {
+                            (w.clone(), v.clone())
+                        };
+                        if bannedlinks.contains(&link) {
+                            continue;
+                        }
+                    }
+                    if let Some(&(_, otherdepth)) = other.get(w) {
+                        let length = depth + 1 + otherdepth;
+                        if best.as_ref().is_none_or(|b| length < b.0) {
+                            best = Some((length, v.clone(), w.clone()));
+                        }
+                    }
+                    if !visited.contains_key(w) {
+                        visited.insert(w.clone(), (Some(v.clone()), depth + 1));
+                        next.push(w.clone());
+                    }
+                }
+            }
+            *frontier = next;
+
+            if let Some((_, v, w)) = best {
+                /* v is on this side and w on the other, so put the two halves together */
+                let (last, first) = if isforward { (v, w) } else { (w, v) };
+                let mut path = trace(&forward, last);
+                path.reverse();
+                path.extend(trace(&backward, first));
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the sites that `url` links to, from the database the first time.
+    fn outlinks(&mut self, url: &str) -> Result<Rc<[String]>, DatabaseError> {
+        if let Some(links) = self.outlinks.get(url) {
+            return Ok(links.clone());
+        }
+        let links: Rc<[String]> = self.db.get_linked_sites(site(url)?)?.into();
+        self.outlinks.insert(url.to_string(), links.clone());
+        Ok(links)
+    }
+
+    /// Returns the sites that link to `url`, from the database the first time.
+    fn inlinks(&mut self, url: &str) -> Result<Rc<[String]>, DatabaseError> {
+        if let Some(links) = self.inlinks.get(url) {
+            return Ok(links.clone());
+        }
+        let links: Rc<[String]> = self.db.get_linking_sites(site(url)?)?.into();
+        self.inlinks.insert(url.to_string(), links.clone());
+        Ok(links)
+    }
+}
+
+/// Follows the way a search came back from `url` to where it started.
+fn trace(visited: &Visited, url: String) -> Vec<String> {
+    let mut path = vec![url];
+    while let Some((Some(previous), _)) = visited.get(path.last().unwrap()) {
+        path.push(previous.clone());
+    }
+    path
+}
+
+fn site(url: &str) -> Result<SiteEntry, DatabaseError> {
+    let url = Url::parse(url).map_err(|_| DatabaseError::InvalidUrl(url.to_string()))?;
+    SiteEntry::new(url, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathFinder;
+    use crate::database::Database;
+    use crate::testgraph::database;
+
+    fn url(name: &str) -> String {
+        format!("https://{name}.neocities.org/")
+    }
+
+    fn name(url: &str) -> &str {
+        url.trim_start_matches("https://")
+            .split('.')
+            .next()
+            .unwrap()
+    }
+
+    fn names(path: &[String]) -> Vec<&str> {
+        path.iter().map(|u| name(u)).collect()
+    }
+
+    /// a -> b -> c -> d -> e, with shortcuts a -> c and b -> e, and f off on its own.
+    fn shortcuts() -> Database {
+        database(&[
+            ("a", "b"),
+            ("b", "c"),
+            ("c", "d"),
+            ("d", "e"),
+            ("a", "c"),
+            ("b", "e"),
+            ("f", "a"),
+        ])
+    }
+
+    #[test]
+    fn shortest() {
+        let db = shortcuts();
+        let mut finder = PathFinder::new(&db);
+        let path = finder.shortest_path(&url("a"), &url("e")).unwrap().unwrap();
+        assert_eq!(names(&path), vec!["a", "b", "e"]);
+        let path = finder.shortest_path(&url("a"), &url("d")).unwrap().unwrap();
+        assert_eq!(names(&path), vec!["a", "c", "d"]);
+        let path = finder.shortest_path(&url("f"), &url("e")).unwrap().unwrap();
+        assert_eq!(names(&path), vec!["f", "a", "b", "e"]);
+        assert_eq!(
+            finder.shortest_path(&url("a"), &url("a")).unwrap(),
+            Some(vec![url("a")])
+        );
+
+        // links only go one way
+        assert_eq!(finder.shortest_path(&url("e"), &url("a")).unwrap(), None);
+        assert_eq!(finder.shortest_path(&url("a"), &url("f")).unwrap(), None);
+        // and sites too far apart aren't looked for
+        let mut finder = PathFinder::new(&db).with_max_hops(2);
+        assert_eq!(finder.shortest_path(&url("f"), &url("e")).unwrap(), None);
+        assert!(finder
+            .shortest_path(&url("a"), &url("e"))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn k_shortest() {
+        let db = shortcuts();
+        let mut finder = PathFinder::new(&db);
+        let paths = finder.shortest_paths(&url("a"), &url("e"), 10).unwrap();
+        let paths: Vec<Vec<&str>> = paths.iter().map(|p| names(p)).collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec!["a", "b", "e"],
+                vec!["a", "c", "d", "e"],
+                vec!["a", "b", "c", "d", "e"],
+            ]
+        );
+        assert_eq!(
+            finder
+                .shortest_paths(&url("a"), &url("e"), 2)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(finder
+            .shortest_paths(&url("e"), &url("a"), 3)
+            .unwrap()
+            .is_empty());
+
+        let mut finder = PathFinder::new(&db).with_max_hops(3);
+        assert_eq!(
+            finder
+                .shortest_paths(&url("a"), &url("e"), 10)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn reachable() {
+        let db = shortcuts();
+        let mut finder = PathFinder::new(&db);
+        let reached = finder.reachable(&url("f"), 2).unwrap();
+        let reached: Vec<(&str, usize)> =
+            reached.iter().map(|(u, hops)| (name(u), *hops)).collect();
+        assert_eq!(reached, vec![("a", 1), ("b", 2), ("c", 2)]);
+        assert_eq!(finder.reachable(&url("f"), 10).unwrap().len(), 5);
+        assert!(finder.reachable(&url("e"), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn long_chain() {
+        // a search only reads the sites it needs, so a long chain takes as long as it is
+        let names: Vec<String> = (0..2000).map(|i| format!("site{i}")).collect();
+        let links: Vec<(&str, &str)> = names
+            .windows(2)
+            .map(|w| (w[0].as_str(), w[1].as_str()))
+            .collect();
+        let db = database(&links);
+        let mut finder = PathFinder::new(&db);
+        let path = finder
+            .shortest_path(&url("site0"), &url("site1999"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.len(), 2000);
+        let path = finder
+            .shortest_path(&url("site500"), &url("site510"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(path.len(), 11);
+    }
+}
@@ -0,0 +1,355 @@
+//! Keeps the crawler from hammering servers.
+//!
+//! Every request goes through `Politeness::wait()`, which enforces a minimum
+//! delay between requests to the same host and an overall request rate.
+//! Servers that answer 429 or 503 are backed off from, honouring `Retry-After`.
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The longest any request is held back, however slow the settings ask for,
+/// so that absurd delays or rates can't overflow the clock.
+const MAX_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Where `Politeness` gets the time from, so tests don't have to actually wait.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Settings for `Politeness`.
+#[derive(Debug, Clone)]
+pub struct PolitenessConfig {
+    pub hostdelay: Duration,  // minimum time between requests to the same host
+    pub maxrps: Option<f64>,  // maximum requests per second over all hosts
+    pub maxretries: u32,      // how often a 429/503 response is retried
+    pub backoff: Duration,    // first backoff when there is no Retry-After, doubled every retry
+    pub maxbackoff: Duration, // upper limit for any backoff, including Retry-After
+}
+
+impl Default for PolitenessConfig {
+    fn default() -> Self {
+        PolitenessConfig {
+            hostdelay: Duration::from_secs(1),
+            maxrps: Some(5.0),
+            maxretries: 3,
+            backoff: Duration::from_secs(5),
+            maxbackoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl PolitenessConfig {
+    /// No delays at all, for talking to local test servers.
+    pub fn none() -> PolitenessConfig {
+        PolitenessConfig {
+            hostdelay: Duration::ZERO,
+            maxrps: None,
+            maxretries: 0,
+            backoff: Duration::ZERO,
+            maxbackoff: Duration::ZERO,
+        }
+    }
+}
+
+/// Schedules requests so no host (and no crawl) goes faster than allowed.
+///
+/// Safe to share between threads: each caller reserves its slot under a lock,
+/// then sleeps without holding it.
+pub struct Politeness {
+    config: PolitenessConfig,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    nexthost: HashMap<String, Instant>, // earliest time of the next request per host
+    nextglobal: Option<Instant>,        // earliest time of the next request to any host
+    hostdelays: HashMap<String, Duration>, // per host delays that override `hostdelay`
+}
+
+impl Politeness {
+    pub fn new(config: PolitenessConfig) -> Politeness {
+        Politeness::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: PolitenessConfig, clock: Arc<dyn Clock>) -> Politeness {
+        Politeness {
+            config,
+            clock,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn config(&self) -> &PolitenessConfig {
+        &self.config
+    }
+
+    /// Makes requests to `host` wait at least `delay` between each other
+    /// (e.g. from a robots.txt Crawl-delay), if that is longer than the configured delay.
+    pub fn set_host_delay(&self, host: &str, delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.hostdelays.insert(host.to_string(), delay);
+    }
+
+    /// Returns the delay between requests to `host`.
+    pub fn host_delay(&self, host: &str) -> Duration {
+        let state = self.state.lock().unwrap();
+        self.delay_for(&state, host)
+    }
+
+    fn delay_for(&self, state: &State, host: &str) -> Duration {
+        match state.hostdelays.get(host) {
+            Some(&d) => d.max(self.config.hostdelay),
+            None => self.config.hostdelay,
+        }
+    }
+
+    /// Blocks until a request to `host` is allowed, and reserves that slot.
+    /// Returns how long it waited.
+    pub fn wait(&self, host: &str) -> Duration {
+        let now = self.clock.now();
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let mut slot = now;
+            if let Some(&t) = state.nexthost.get(host) {
+                slot = slot.max(t);
+            }
+            if let Some(t) = state.nextglobal {
+                slot = slot.max(t);
+            }
+
+            let delay = self.delay_for(&state, host).min(MAX_WAIT);
+            state.nexthost.insert(host.to_string(), slot + delay);
+            if let Some(rps) = self.config.maxrps.filter(|&r| r > 0.0) {
+                let gap = Duration::try_from_secs_f64(1.0 / rps).unwrap_or(MAX_WAIT);
+                state.nextglobal = Some(slot + gap.min(MAX_WAIT));
+            }
+            slot
+        };
+
+        let wait = slot.saturating_duration_since(now);
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
+        }
+        wait
+    }
+
+    /// Called when `host` answered 429 or 503. Pushes back the next request to the host
+    /// by `retryafter` if the server sent one, otherwise by an exponential backoff.
+    ///
+    /// Returns `None` if the request shouldn't be retried anymore
+    /// (`attempt` starts at 0 for the first retry).
+    pub fn backoff(
+        &self,
+        host: &str,
+        retryafter: Option<Duration>,
+        attempt: u32,
+    ) -> Option<Duration> {
+        if attempt >= self.config.maxretries {
+            return None;
+        }
+        let backoff = retryafter
+            .unwrap_or_else(|| {
+                self.config
+                    .backoff
+                    .saturating_mul(2u32.saturating_pow(attempt))
+            })
+            .min(self.config.maxbackoff);
+
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let next = state.nexthost.entry(host.to_string()).or_insert(now);
+        *next = (*next).max(now + backoff);
+        Some(backoff)
+    }
+}
+
+impl fmt::Debug for Politeness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Politeness")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an http date.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = (date.with_timezone(&Utc) - now).num_seconds();
+    Some(Duration::from_secs(secs.max(0) as u64))
+}
+
+/// A clock that only moves when something sleeps.
+#[cfg(test)]
+pub(crate) struct MockClock {
+    start: Instant,
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> MockClock {
+        let start = Instant::now();
+        MockClock {
+            start,
+            now: Mutex::new(start),
+        }
+    }
+
+    /// Time that has passed on this clock.
+    pub fn elapsed(&self) -> Duration {
+        *self.now.lock().unwrap() - self.start
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_retry_after, MockClock, Politeness, PolitenessConfig, MAX_WAIT};
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn config(hostdelay: u64, maxrps: Option<f64>) -> PolitenessConfig {
+        PolitenessConfig {
+            hostdelay: Duration::from_millis(hostdelay),
+            maxrps,
+            maxretries: 3,
+            backoff: Duration::from_secs(1),
+            maxbackoff: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn host_delay() {
+        let clock = Arc::new(MockClock::new());
+        let p = Politeness::with_clock(config(1000, None), clock.clone());
+
+        assert_eq!(p.wait("a.neocities.org"), Duration::ZERO);
+        assert_eq!(p.wait("a.neocities.org"), Duration::from_secs(1));
+        // another host doesn't have to wait
+        assert_eq!(p.wait("b.neocities.org"), Duration::ZERO);
+        assert_eq!(p.wait("a.neocities.org"), Duration::from_secs(1));
+        assert_eq!(clock.elapsed(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn host_delay_override() {
+        let clock = Arc::new(MockClock::new());
+        let p = Politeness::with_clock(config(1000, None), clock.clone());
+
+        // a crawl-delay shorter than the configured delay doesn't speed things up
+        p.set_host_delay("a.neocities.org", Duration::from_millis(100));
+        p.set_host_delay("b.neocities.org", Duration::from_secs(5));
+        assert_eq!(p.host_delay("a.neocities.org"), Duration::from_secs(1));
+        assert_eq!(p.host_delay("b.neocities.org"), Duration::from_secs(5));
+
+        p.wait("b.neocities.org");
+        assert_eq!(p.wait("b.neocities.org"), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn global_rate() {
+        let clock = Arc::new(MockClock::new());
+        let p = Politeness::with_clock(config(0, Some(4.0)), clock.clone());
+
+        for i in 0..8 {
+            p.wait(&format!("site{i}.neocities.org"));
+        }
+        // 8 requests at 4 per second: the last one goes out after 1.75s
+        assert_eq!(clock.elapsed(), Duration::from_millis(1750));
+    }
+
+    #[test]
+    fn absurd_settings() {
+        // a delay or a rate too slow for the clock only holds requests back for MAX_WAIT
+        let clock = Arc::new(MockClock::new());
+        let p = Politeness::with_clock(config(0, Some(1e-300)), clock.clone());
+        p.set_host_delay("a.neocities.org", Duration::MAX);
+
+        assert_eq!(p.wait("a.neocities.org"), Duration::ZERO);
+        assert_eq!(p.wait("a.neocities.org"), MAX_WAIT);
+        assert_eq!(p.wait("b.neocities.org"), MAX_WAIT);
+    }
+
+    #[test]
+    fn backoff() {
+        let clock = Arc::new(MockClock::new());
+        let p = Politeness::with_clock(config(0, None), clock.clone());
+
+        // exponential without retry-after
+        assert_eq!(p.backoff("a", None, 0), Some(Duration::from_secs(1)));
+        assert_eq!(p.wait("a"), Duration::from_secs(1));
+        assert_eq!(p.backoff("a", None, 1), Some(Duration::from_secs(2)));
+        assert_eq!(p.wait("a"), Duration::from_secs(2));
+        assert_eq!(p.backoff("a", None, 2), Some(Duration::from_secs(4)));
+        assert_eq!(p.backoff("a", None, 3), None);
+
+        // retry-after wins, but is capped
+        let clock = Arc::new(MockClock::new());
+        let p = Politeness::with_clock(config(0, None), clock.clone());
+        assert_eq!(
+            p.backoff("a", Some(Duration::from_secs(7)), 0),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(p.wait("a"), Duration::from_secs(7));
+        assert_eq!(
+            p.backoff("a", Some(Duration::from_secs(3600)), 1),
+            Some(Duration::from_secs(10))
+        );
+        // other hosts aren't affected
+        assert_eq!(p.wait("b"), Duration::ZERO);
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
@@ -0,0 +1,457 @@
+//! robots.txt parsing and matching, following RFC 9309.
+
+use crate::fetcher::{FetchError, Fetcher};
+use std::collections::HashMap;
+use std::time::Duration;
+use url::Url;
+
+/// The longest Crawl-delay that is honoured, longer ones are cut down to it
+/// so a robots.txt can't stall the crawl (or overflow the politeness timers).
+pub const MAX_CRAWL_DELAY: Duration = Duration::from_secs(60);
+
+/// A parsed robots.txt file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Robots {
+    groups: Vec<Group>,
+    pub sitemaps: Vec<String>, // urls of any Sitemap: lines
+}
+
+/// Rules that apply to a set of user agents.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Group {
+    agents: Vec<String>, // lowercase product tokens, "*" matches everyone
+    rules: Vec<Rule>,
+    crawldelay: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Robots {
+    /// A robots.txt that allows everything, used when a site doesn't have one.
+    pub fn allow_all() -> Robots {
+        Robots::default()
+    }
+
+    /// A robots.txt that disallows everything, used when a site's robots.txt can't be fetched.
+    pub fn disallow_all() -> Robots {
+        Robots::parse("User-agent: *\nDisallow: /\n")
+    }
+
+    /// Parses the contents of a robots.txt file.
+    /// Anything that can't be understood is ignored.
+    pub fn parse(text: &str) -> Robots {
+        let mut robots = Robots::default();
+        let mut current: Option<Group> = None;
+        let mut inagents = false; // whether the last line was a user-agent line
+
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let line = match line.find('#') {
+                Some(c) => &line[..c],
+                None => line,
+            };
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim();
+
+            match key.as_str() {
+                "user-agent" => {
+                    /* consecutive user-agent lines share one group */
+                    if !inagents {
+                        if let Some(g) = current.take() {
+                            robots.groups.push(g);
+                        }
+                        current = Some(Group::default());
+                    }
+                    inagents = true;
+                    if let Some(g) = current.as_mut() {
+                        g.agents.push(product_token(value));
+                    }
+                }
+                "allow" | "disallow" => {
+                    inagents = false;
+                    /* rules before the first user-agent line don't belong to anyone */
+                    let Some(g) = current.as_mut() else { continue };
+                    /* an empty disallow allows everything, which is the same as no rule */
+                    if value.is_empty() {
+                        continue;
+                    }
+                    g.rules.push(Rule {
+                        allow: key == "allow",
+                        pattern: value.to_string(),
+                    });
+                }
+                "crawl-delay" => {
+                    inagents = false;
+                    let Some(g) = current.as_mut() else { continue };
+                    let Ok(secs) = value.parse::<f64>() else {
+                        continue;
+                    };
+                    /* too long to be a Duration (even infinite) still asks for a long delay */
+                    g.crawldelay = match Duration::try_from_secs_f64(secs) {
+                        Ok(d) => Some(d.min(MAX_CRAWL_DELAY)),
+                        Err(_) if secs > 0.0 => Some(MAX_CRAWL_DELAY),
+                        Err(_) => g.crawldelay,
+                    };
+                }
+                "sitemap" => {
+                    /* sitemaps don't belong to a group */
+                    if !value.is_empty() {
+                        robots.sitemaps.push(value.to_string());
+                    }
+                }
+                _ => {
+                    inagents = false;
+                }
+            }
+        }
+        if let Some(g) = current {
+            robots.groups.push(g);
+        }
+        robots
+    }
+
+    /// Returns the groups that apply to `useragent`.
+    /// Groups naming the agent win over "*" groups, and matching groups are merged.
+    fn groups_for(&self, useragent: &str) -> Vec<&Group> {
+        let token = product_token(useragent);
+        let named: Vec<&Group> = self
+            .groups
+            .iter()
+            .filter(|g| g.agents.contains(&token))
+            .collect();
+        if !named.is_empty() {
+            return named;
+        }
+        self.groups
+            .iter()
+            .filter(|g| g.agents.iter().any(|a| a == "*"))
+            .collect()
+    }
+
+    /// Whether `useragent` may fetch `url`.
+    ///
+    /// The rule with the longest matching pattern wins,
+    /// if an allow and a disallow rule are equally long the allow rule wins.
+    pub fn is_allowed(&self, useragent: &str, url: &Url) -> bool {
+        let mut path = url.path().to_string();
+        if let Some(q) = url.query() {
+            path.push('?');
+            path.push_str(q);
+        }
+        self.is_path_allowed(useragent, &path)
+    }
+
+    /// Like `is_allowed()`, but for a path (plus query) instead of a whole url.
+    pub fn is_path_allowed(&self, useragent: &str, path: &str) -> bool {
+        /* robots.txt itself is always allowed */
+        if path == "/robots.txt" {
+            return true;
+        }
+
+        let mut best: Option<&Rule> = None;
+        for g in self.groups_for(useragent) {
+            for r in &g.rules {
+                if !pattern_matches(&r.pattern, path) {
+                    continue;
+                }
+                best = match best {
+                    Some(b) if b.pattern.len() > r.pattern.len() => Some(b),
+                    Some(b) if b.pattern.len() == r.pattern.len() && b.allow => Some(b),
+                    _ => Some(r),
+                };
+            }
+        }
+        best.is_none_or(|r| r.allow)
+    }
+
+    /// Returns how long `useragent` should wait between requests, if the site asks for a delay.
+    pub fn crawl_delay(&self, useragent: &str) -> Option<Duration> {
+        self.groups_for(useragent)
+            .iter()
+            .filter_map(|g| g.crawldelay)
+            .max()
+    }
+}
+
+/// Returns the lowercase product token of a user agent,
+/// e.g. "neomap/0.1.0 (+https://github.com/dawnvoid/neomap)" -> "neomap".
+pub fn product_token(useragent: &str) -> String {
+    useragent
+        .trim()
+        .split(|c: char| c == '/' || c.is_whitespace())
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
+
+/// Matches a robots.txt path pattern, where `*` matches any run of characters
+/// and a trailing `$` anchors the pattern to the end of the path.
+/// Patterns without a `$` only need to match the start of the path.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let pattern = pattern.as_bytes();
+    let path = path.as_bytes();
+
+    /* classic wildcard matching with backtracking to the last '*' */
+    let (mut pi, mut si) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    loop {
+        if pi == pattern.len() {
+            if !anchored || si == path.len() {
+                return true;
+            }
+        } else if pattern[pi] == b'*' {
+            star = Some((pi, si));
+            pi += 1;
+            continue;
+        } else if si < path.len() && pattern[pi] == path[si] {
+            pi += 1;
+            si += 1;
+            continue;
+        }
+
+        /* mismatch: let the last '*' swallow one more character */
+        match star {
+            Some((sp, ss)) if ss < path.len() => {
+                star = Some((sp, ss + 1));
+                pi = sp + 1;
+                si = ss + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// robots.txt files by origin (e.g. "https://dawnvoid.neocities.org"),
+/// so every site's robots.txt is only fetched once.
+#[derive(Debug, Default)]
+pub struct RobotsCache {
+    robots: HashMap<String, Robots>,
+    errors: HashMap<String, FetchError>, // why robots.txt couldn't be fetched, by origin
+}
+
+impl RobotsCache {
+    pub fn new() -> RobotsCache {
+        RobotsCache::default()
+    }
+
+    /// Returns the robots.txt for the site of `url`, fetching it if it isn't cached yet.
+    ///
+    /// As RFC 9309 says, a missing robots.txt (4xx) allows everything,
+    /// while a robots.txt that can't be fetched (5xx or a network error) disallows everything.
+    /// Network errors are kept, see `error()`.
+    pub fn get(&mut self, fetcher: &Fetcher, url: &Url) -> &Robots {
+        let origin = url.origin().ascii_serialization();
+        self.robots
+            .entry(origin.clone())
+            .or_insert_with(|| match fetch_robots(fetcher, url) {
+                Ok(r) => r,
+                Err(e) => {
+                    self.errors.insert(origin, e);
+                    Robots::disallow_all()
+                }
+            })
+    }
+
+    /// Returns why the robots.txt for the site of `url` couldn't be fetched,
+    /// or `None` if it was fetched (or hasn't been asked for yet).
+    pub fn error(&self, url: &Url) -> Option<&FetchError> {
+        self.errors.get(&url.origin().ascii_serialization())
+    }
+
+    /// Adds a robots.txt for an origin without fetching it.
+    pub fn insert(&mut self, origin: &str, robots: Robots) {
+        self.robots.insert(origin.to_string(), robots);
+    }
+}
+
+fn fetch_robots(fetcher: &Fetcher, url: &Url) -> Result<Robots, FetchError> {
+    let robotsurl = url
+        .join("/robots.txt")
+        .map_err(|e| FetchError::Request(url.clone(), e.to_string()))?;
+    let response = fetcher.fetch(&robotsurl)?;
+    let robots = match response.status {
+        200..=299 => Robots::parse(&response.text()),
+        400..=499 => Robots::allow_all(),
+        _ => Robots::disallow_all(),
+    };
+    Ok(robots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pattern_matches, product_token, Robots, RobotsCache, MAX_CRAWL_DELAY};
+    use crate::fetcher::FetchError;
+    use crate::testserver::{self, Response, TestServer};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use url::Url;
+
+    const GROUPS: &str = include_str!("../tests/fixtures/robots/groups.txt");
+    const DISALLOW_ALL: &str = include_str!("../tests/fixtures/robots/disallow_all.txt");
+    const MESSY: &str = include_str!("../tests/fixtures/robots/messy.txt");
+    const PRECEDENCE: &str = include_str!("../tests/fixtures/robots/precedence.txt");
+    const CRAWL_DELAY: &str = include_str!("../tests/fixtures/robots/crawl_delay.txt");
+
+    const NEOMAP: &str = "neomap/0.1.0 (+https://github.com/dawnvoid/neomap)";
+    const SOMEBOT: &str = "somebot/1.0";
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("/", "/anything"));
+        assert!(pattern_matches("/private/", "/private/x.html"));
+        assert!(!pattern_matches("/private/", "/private"));
+        assert!(pattern_matches("/*.gif$", "/img/cat.gif"));
+        assert!(!pattern_matches("/*.gif$", "/img/cat.gif?v=2"));
+        assert!(pattern_matches("/*.gif", "/img/cat.gif?v=2"));
+        assert!(pattern_matches("/a*b*c", "/a-x-b-y-c-z"));
+        assert!(!pattern_matches("/a*b*c", "/a-x-c-y-b"));
+        assert!(pattern_matches("/page$", "/page"));
+        assert!(!pattern_matches("/page$", "/pages"));
+        assert!(pattern_matches("*", ""));
+    }
+
+    #[test]
+    fn product_tokens() {
+        assert_eq!(product_token(NEOMAP), "neomap");
+        assert_eq!(product_token("NeoMap/2.0"), "neomap");
+        assert_eq!(product_token("*"), "*");
+    }
+
+    #[test]
+    fn groups() {
+        let robots = Robots::parse(GROUPS);
+
+        // neomap has its own group, so the "*" rules don't apply to it
+        assert!(robots.is_path_allowed(NEOMAP, "/private/secret.html"));
+        assert!(!robots.is_path_allowed(NEOMAP, "/no-neomap/index.html"));
+        assert!(robots.is_path_allowed(NEOMAP, "/cat.gif"));
+        assert_eq!(robots.crawl_delay(NEOMAP), Some(Duration::from_millis(500)));
+
+        // everyone else gets the "*" group
+        assert!(!robots.is_path_allowed(SOMEBOT, "/private/secret.html"));
+        assert!(robots.is_path_allowed(SOMEBOT, "/private/public.html"));
+        assert!(!robots.is_path_allowed(SOMEBOT, "/img/cat.gif"));
+        assert!(robots.is_path_allowed(SOMEBOT, "/img/cat.gif?v=2"));
+        assert!(robots.is_path_allowed(SOMEBOT, "/no-neomap/index.html"));
+        assert_eq!(robots.crawl_delay(SOMEBOT), Some(Duration::from_secs(2)));
+
+        assert_eq!(
+            robots.sitemaps,
+            vec!["https://example.neocities.org/sitemap.xml"]
+        );
+    }
+
+    #[test]
+    fn disallow_all() {
+        let robots = Robots::parse(DISALLOW_ALL);
+        assert!(!robots.is_path_allowed(NEOMAP, "/"));
+        assert!(!robots.is_path_allowed(NEOMAP, "/index.html"));
+        assert!(robots.is_path_allowed(NEOMAP, "/robots.txt"));
+        assert_eq!(robots, Robots::disallow_all());
+        assert_eq!(robots.crawl_delay(NEOMAP), None);
+    }
+
+    #[test]
+    fn messy() {
+        let robots = Robots::parse(MESSY);
+
+        // rules before the first user-agent are ignored, keys are case insensitive
+        assert!(robots.is_path_allowed(SOMEBOT, "/ignored/x"));
+        assert!(!robots.is_path_allowed(SOMEBOT, "/secret"));
+        assert!(!robots.is_path_allowed(SOMEBOT, "/secret/x"));
+        assert!(robots.is_path_allowed(SOMEBOT, "/secret/ok"));
+        assert!(robots.is_path_allowed(SOMEBOT, "/public"));
+        assert_eq!(robots.crawl_delay(SOMEBOT), None);
+
+        // the agent name is matched by product token, ignoring case and version
+        assert!(robots.is_path_allowed(NEOMAP, "/secret"));
+        assert!(!robots.is_path_allowed(NEOMAP, "/page?x=1&print=yes"));
+        assert!(robots.is_path_allowed(NEOMAP, "/page?x=1"));
+    }
+
+    #[test]
+    fn precedence() {
+        let robots = Robots::parse(PRECEDENCE);
+
+        // equally long allow and disallow: allow wins
+        assert!(robots.is_path_allowed(SOMEBOT, "/page"));
+        // the longest match wins
+        assert!(!robots.is_path_allowed(SOMEBOT, "/dir/x.png"));
+        assert!(!robots.is_path_allowed(SOMEBOT, "/dir/x.html"));
+        assert!(robots.is_path_allowed(SOMEBOT, "/dir/x.html?v=1"));
+    }
+
+    #[test]
+    fn crawl_delay_limit() {
+        let robots = Robots::parse(CRAWL_DELAY);
+
+        // far too long for a Duration, or just far too long: both are cut down
+        assert_eq!(robots.crawl_delay(NEOMAP), Some(MAX_CRAWL_DELAY));
+        assert_eq!(robots.crawl_delay("otherbot"), Some(MAX_CRAWL_DELAY));
+        // a negative delay is ignored
+        assert_eq!(robots.crawl_delay(SOMEBOT), None);
+    }
+
+    #[test]
+    fn empty() {
+        let robots = Robots::parse("");
+        assert!(robots.is_path_allowed(NEOMAP, "/anything"));
+        assert_eq!(robots, Robots::allow_all());
+    }
+
+    #[test]
+    fn cache_fetches_once() {
+        let server = TestServer::start(vec![("/robots.txt", Response::new(200, DISALLOW_ALL))]);
+        let fetcher = testserver::fetcher();
+        let mut cache = RobotsCache::new();
+
+        assert!(!cache
+            .get(&fetcher, &server.url("/a.html"))
+            .is_allowed(NEOMAP, &server.url("/a.html")));
+        assert!(!cache
+            .get(&fetcher, &server.url("/b.html"))
+            .is_allowed(NEOMAP, &server.url("/b.html")));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn cache_missing_and_broken() {
+        let missing = TestServer::start(vec![]);
+        let broken = TestServer::start(vec![("/robots.txt", Response::new(503, ""))]);
+        let fetcher = testserver::fetcher();
+        let mut cache = RobotsCache::new();
+
+        let url = missing.url("/a.html");
+        assert!(cache.get(&fetcher, &url).is_allowed(NEOMAP, &url));
+        let url = broken.url("/a.html");
+        assert!(!cache.get(&fetcher, &url).is_allowed(NEOMAP, &url));
+        assert!(cache.error(&url).is_none());
+    }
+
+    #[test]
+    fn cache_unreachable() {
+        /* nothing listens on a port that was just given back */
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = Url::parse(&format!("http://127.0.0.1:{port}/a.html")).unwrap();
+        let fetcher = testserver::fetcher();
+        let mut cache = RobotsCache::new();
+
+        assert!(cache.error(&url).is_none());
+        assert!(!cache.get(&fetcher, &url).is_allowed(NEOMAP, &url));
+        assert!(matches!(cache.error(&url), Some(FetchError::Connect(..))));
+    }
+}
@@ -0,0 +1,177 @@
+//! Deciding when each site should be crawled again.
+//!
+//! Every crawl of a site compares its links to what the crawl before found.
+//! Sites whose links keep changing are revisited more and more often,
+//! sites that stay the same are backed off exponentially,
+//! and sites that were never crawled are due right away.
+
+use crate::database::{DatabaseError, ScheduleEntry};
+use std::time::Duration;
+use url::Url;
+
+/// Bounds for the time between two crawls of the same site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    pub mininterval: Duration, // shortest time between crawls, for sites that change all the time
+    pub maxinterval: Duration, // longest time between crawls, for sites that never change
+    pub initialinterval: Duration, // time until the second crawl of a site
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            mininterval: Duration::from_secs(60 * 60),
+            maxinterval: Duration::from_secs(30 * 24 * 60 * 60),
+            initialinterval: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Gives every site its own next crawl time based on how often its links change.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    config: SchedulerConfig,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Scheduler {
+        Scheduler { config }
+    }
+
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.config
+    }
+
+    /// Returns how long to wait before crawling a site again.
+    ///
+    /// `previous` is the interval the site was crawled at (`None` for its first crawl).
+    /// The interval is halved if the site's links `changed` since the crawl before, doubled if not.
+    pub fn next_interval(&self, previous: Option<Duration>, changed: bool) -> Duration {
+        let interval = match previous {
+            None => self.config.initialinterval,
+            Some(p) if changed => p / 2,
+            Some(p) => p.saturating_mul(2),
+        };
+        interval
+            .min(self.config.maxinterval)
+            .max(self.config.mininterval)
+    }
+
+    /// Returns the schedule of the site at `siteurl` after a crawl at `now`
+    /// found that its links `changed` (or not) since its `previous` schedule.
+    pub fn reschedule(
+        &self,
+        siteurl: Url,
+        previous: Option<&ScheduleEntry>,
+        now: i64,
+        changed: bool,
+    ) -> Result<ScheduleEntry, DatabaseError> {
+        let (interval, crawls, changes) = match previous.filter(|p| !p.is_new()) {
+            Some(p) => {
+                let interval = Duration::from_secs(p.interval().max(0) as u64);
+                (
+                    self.next_interval(Some(interval), changed),
+                    p.crawls() + 1,
+                    p.changes() + changed as i64,
+                )
+            }
+            None => (self.next_interval(None, changed), 1, 0),
+        };
+        let interval = interval.as_secs() as i64;
+        let s = ScheduleEntry::new(siteurl, now.saturating_add(interval), interval)?
+            .with_history(crawls, changes);
+        Ok(s)
+    }
+
+    /// Returns the schedule of the site at `siteurl` after a crawl at `now` failed.
+    ///
+    /// A failure says nothing about whether the site changed, so it is retried
+    /// after the same interval as before (or `mininterval` if it was never crawled)
+    /// and its crawl history is left as it was.
+    pub fn retry(
+        &self,
+        siteurl: Url,
+        previous: Option<&ScheduleEntry>,
+        now: i64,
+    ) -> Result<ScheduleEntry, DatabaseError> {
+        let (interval, crawls, changes) = match previous.filter(|p| !p.is_new()) {
+            Some(p) => (p.interval().max(0), p.crawls(), p.changes()),
+            None => (self.config.mininterval.as_secs() as i64, 0, 0),
+        };
+        let s = ScheduleEntry::new(siteurl, now.saturating_add(interval), interval)?
+            .with_history(crawls, changes);
+        Ok(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, SchedulerConfig};
+    use std::time::Duration;
+    use url::Url;
+
+    const HOUR: u64 = 60 * 60;
+
+    fn scheduler() -> Scheduler {
+        Scheduler::new(SchedulerConfig {
+            mininterval: Duration::from_secs(HOUR),
+            maxinterval: Duration::from_secs(16 * HOUR),
+            initialinterval: Duration::from_secs(4 * HOUR),
+        })
+    }
+
+    #[test]
+    fn intervals() {
+        let s = scheduler();
+        let hours = |h: u64| Duration::from_secs(h * HOUR);
+        assert_eq!(s.next_interval(None, true), hours(4));
+        assert_eq!(s.next_interval(Some(hours(4)), true), hours(2));
+        assert_eq!(s.next_interval(Some(hours(4)), false), hours(8));
+        // but never outside the bounds
+        assert_eq!(s.next_interval(Some(hours(1)), true), hours(1));
+        assert_eq!(s.next_interval(Some(hours(16)), false), hours(16));
+        assert_eq!(s.next_interval(Some(Duration::MAX), false), hours(16));
+    }
+
+    #[test]
+    fn reschedule() {
+        let s = scheduler();
+        let url = Url::parse("https://dawnvoid.neocities.org/").unwrap();
+        let hour = HOUR as i64;
+
+        let first = s.reschedule(url.clone(), None, 1000, false).unwrap();
+        assert_eq!(first.nextcrawl(), 1000 + 4 * hour);
+        assert_eq!((first.crawls(), first.changes()), (1, 0));
+
+        // a dormant site is backed off
+        let second = s
+            .reschedule(url.clone(), Some(&first), 2000, false)
+            .unwrap();
+        assert_eq!(second.interval(), 8 * hour);
+        assert_eq!(second.nextcrawl(), 2000 + 8 * hour);
+
+        // and comes back sooner once it changes again
+        let third = s.reschedule(url, Some(&second), 3000, true).unwrap();
+        assert_eq!(third.interval(), 4 * hour);
+        assert_eq!((third.crawls(), third.changes()), (3, 1));
+    }
+
+    #[test]
+    fn retry() {
+        let s = scheduler();
+        let url = Url::parse("https://dawnvoid.neocities.org/").unwrap();
+        let hour = HOUR as i64;
+
+        // a site that was never crawled is tried again soon
+        let first = s.retry(url.clone(), None, 1000).unwrap();
+        assert_eq!(first.nextcrawl(), 1000 + hour);
+        assert!(first.is_new());
+
+        // a failed recrawl neither backs the site off nor counts as a crawl
+        let crawled = s.reschedule(url.clone(), Some(&first), 2000, true).unwrap();
+        let failed = s.retry(url, Some(&crawled), 3000).unwrap();
+        assert_eq!(failed.interval(), crawled.interval());
+        assert_eq!(failed.nextcrawl(), 3000 + crawled.interval());
+        assert_eq!((failed.crawls(), failed.changes()), (1, 0));
+    }
+}
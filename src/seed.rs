@@ -0,0 +1,580 @@
+//! Finding sites to start crawling from.
+//!
+//! Seeds come from sitemaps, from lists of sites (plain text, CSV or JSON files),
+//! and from saved neocities pages that list sites (browse, following and followers pages).
+//! Whatever the source, seeds end up in the `Database` as sites that were never crawled.
+
+use crate::database::{Database, DatabaseError, SiteEntry};
+use crate::fetcher::{FetchError, Fetcher};
+use crate::html;
+use crate::robots::RobotsCache;
+use crate::site::{self, SiteId, SiteResolver};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use url::Url;
+
+/// Most sitemaps fetched for one site, so a sitemap index can't send us on forever.
+const MAX_SITEMAPS: usize = 20;
+
+/// The urls in a sitemap.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sitemap {
+    pub urls: Vec<Url>,     // pages, from a <urlset>
+    pub sitemaps: Vec<Url>, // more sitemaps, from a <sitemapindex>
+}
+
+/// Parses a sitemap or sitemap index, resolving relative urls against `base`.
+///
+/// Only the `<loc>` of each entry is read, everything else (including image sitemaps) is ignored.
+/// Gzipped sitemaps have to be unpacked first.
+pub fn parse_sitemap(xml: &str, base: &Url) -> Sitemap {
+    let xml = strip_xml_comments(xml);
+    /* lowercasing ascii keeps byte offsets, so positions in `lower` work in `xml` too */
+    let lower = xml.to_ascii_lowercase();
+    let isindex = lower.contains("<sitemapindex");
+
+    let mut sitemap = Sitemap::default();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<loc>").map(|i| pos + i + "<loc>".len()) {
+        let Some(end) = lower[start..].find("</loc>").map(|i| start + i) else {
+            break;
+        };
+        pos = end + "</loc>".len();
+
+        let loc = xml[start..end].trim();
+        let loc = match loc
+            .strip_prefix("<![CDATA[")
+            .and_then(|l| l.strip_suffix("]]>"))
+        {
+            Some(cdata) => cdata.trim().to_string(),
+            None => html::decode_entities(loc),
+        };
+        let Ok(url) = base.join(&loc) else {
+            continue;
+        };
+        if isindex {
+            sitemap.sitemaps.push(url);
+        } else {
+            sitemap.urls.push(url);
+        }
+    }
+    sitemap
+}
+
+/// Returns `xml` without `<!-- -->` comments.
+fn strip_xml_comments(xml: &str) -> String {
+    let mut result = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find("<!--") {
+        result.push_str(&rest[..start]);
+        rest = match rest[start..].find("-->") {
+            Some(end) => &rest[start + end + "-->".len()..],
+            None => "",
+        };
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Returns the sitemaps an html page points to with `<link rel="sitemap">`.
+pub fn sitemap_links(html: &str, base: &Url) -> Vec<Url> {
+    let (docbase, links) = html::extract_links(html);
+    let base = docbase
+        .and_then(|b| base.join(&b).ok())
+        .unwrap_or_else(|| base.clone());
+    links
+        .iter()
+        .filter(|l| l.element == "link" && l.rel.iter().any(|r| r == "sitemap"))
+        .filter_map(|l| base.join(&l.value).ok())
+        .collect()
+}
+
+/// Finds and fetches the sitemaps of the site at `siteurl`, and returns every page url in them
+/// along with whatever couldn't be fetched.
+///
+/// Sitemaps are looked for in robots.txt, in `<link rel="sitemap">` on the front page,
+/// and at `/sitemap.xml`. Sitemap indexes are followed, up to `MAX_SITEMAPS` sitemaps in total.
+/// Nothing is fetched that robots.txt disallows, including the front page and the sitemaps themselves.
+pub fn fetch_sitemaps(fetcher: &Fetcher, siteurl: &Url) -> (Vec<Url>, Vec<FetchError>) {
+    let mut robots = RobotsCache::new();
+    let mut errors = Vec::new();
+    let mut queue: VecDeque<Url> = VecDeque::new();
+    let allowed = is_allowed(fetcher, &mut robots, siteurl, &mut errors);
+    queue.extend(
+        robots
+            .get(fetcher, siteurl)
+            .sitemaps
+            .iter()
+            .filter_map(|s| siteurl.join(s).ok()),
+    );
+    if allowed {
+        match fetcher.fetch(siteurl) {
+            Ok(page) if page.is_success() => {
+                queue.extend(sitemap_links(&page.text(), &page.finalurl));
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+    queue.extend(siteurl.join("/sitemap.xml").ok());
+
+    let mut fetched: HashSet<Url> = HashSet::new();
+    let mut urls = Vec::new();
+    while let Some(sitemapurl) = queue.pop_front() {
+        if fetched.len() >= MAX_SITEMAPS {
+            break;
+        }
+        if !fetched.insert(sitemapurl.clone()) {
+            continue;
+        }
+        /* robots.txt can list sitemaps it doesn't let us fetch */
+        if !is_allowed(fetcher, &mut robots, &sitemapurl, &mut errors) {
+            continue;
+        }
+        let page = match fetcher.fetch(&sitemapurl) {
+            Ok(p) if p.is_success() => p,
+            Ok(_) => continue,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        let sitemap = parse_sitemap(&page.text(), &page.finalurl);
+        urls.extend(sitemap.urls);
+        queue.extend(sitemap.sitemaps);
+    }
+    (urls, errors)
+}
+
+/// Returns whether robots.txt lets `fetcher` fetch `url`.
+/// If the robots.txt couldn't be fetched, why is added to `errors` the first time it's asked for.
+fn is_allowed(
+    fetcher: &Fetcher,
+    robots: &mut RobotsCache,
+    url: &Url,
+    errors: &mut Vec<FetchError>,
+) -> bool {
+    let failed = robots.error(url).is_some();
+    let allowed = robots
+        .get(fetcher, url)
+        .is_allowed(fetcher.useragent(), url);
+    if !failed {
+        errors.extend(robots.error(url).cloned());
+    }
+    allowed
+}
+
+/// Returns the sites that `urls` are on, in order and without duplicates.
+pub fn sites_of(urls: &[Url], sites: &SiteResolver) -> Vec<SiteId> {
+    unique(urls.iter().filter_map(|u| sites.resolve(u)))
+}
+
+/// Parses a list of sites with one site per line, as a url, a domain or just the site's name.
+/// Blank lines and lines starting with `#` are skipped, as is anything after the first word.
+pub fn parse_text_list(text: &str) -> Vec<SiteId> {
+    unique(
+        text.lines()
+            .map(str::trim)
+            .filter(|l| !l.starts_with('#'))
+            .filter_map(|l| l.split_whitespace().next())
+            .filter_map(|w| parse_site(w, true)),
+    )
+}
+
+/// Parses a CSV file of sites, taking every field that is a url or domain of a site.
+///
+/// Plain site names are only taken from a column whose header is "name", "site" or "sitename".
+pub fn parse_csv_list(text: &str) -> Vec<SiteId> {
+    let mut rows = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(csv_fields);
+    let Some(header) = rows.next() else {
+        return Vec::new();
+    };
+    let namecolumn = header.iter().position(|h| {
+        matches!(
+            h.trim().to_ascii_lowercase().as_str(),
+            "name" | "site" | "sitename"
+        )
+    });
+
+    let mut seeds = Vec::new();
+    /* without a name column the first row is probably data, not a header */
+    let firstrow = if namecolumn.is_none() {
+        Some(header)
+    } else {
+        None
+    };
+    for row in firstrow.into_iter().chain(rows) {
+        for (i, field) in row.iter().enumerate() {
+            if let Some(s) = parse_site(field, Some(i) == namecolumn) {
+                seeds.push(s);
+            }
+        }
+    }
+    unique(seeds.into_iter())
+}
+
+/// Splits a CSV line into fields, handling quoted fields with commas and `""` in them.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Parses a JSON file of sites, taking every string in it that is a url or domain of a site,
+/// however the file is structured (e.g. an array of urls, or objects with a "url" key).
+pub fn parse_json_list(text: &str) -> Vec<SiteId> {
+    unique(
+        json_strings(text)
+            .iter()
+            .filter_map(|s| parse_site(s, false)),
+    )
+}
+
+/// Returns every string literal in a JSON document, unescaped.
+fn json_strings(json: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut chars = json.chars();
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            continue;
+        }
+        let mut s = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        s.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    Some(c) => s.push(c), /* \" \\ and \/ */
+                    None => break,
+                },
+                c => s.push(c),
+            }
+        }
+        strings.push(s);
+    }
+    strings
+}
+
+/// Parses a saved neocities page that lists sites, like /browse or a site's follows and followers,
+/// and returns the sites whose profiles it links to.
+pub fn parse_listing_page(html: &str) -> Vec<SiteId> {
+    let base = Url::parse("https://neocities.org/").unwrap();
+    let (_, links) = html::extract_links(html);
+    unique(
+        links
+            .iter()
+            .filter_map(|l| base.join(&l.value).ok())
+            .filter(|u| u.domain().map(site::normalize_host).as_deref() == Some("neocities.org"))
+            .filter_map(|u| SiteId::from_url(&u)),
+    )
+}
+
+/// Reads a list of sites from the file at `path`, parsed by its extension:
+/// `.csv`, `.json`, `.html`/`.htm` (a saved listing page) or anything else as a text list.
+pub fn read_list_file(path: &Path) -> io::Result<Vec<SiteId>> {
+    let text = fs::read_to_string(path)?;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let seeds = match extension.as_deref() {
+        Some("csv") => parse_csv_list(&text),
+        Some("json") => parse_json_list(&text),
+        Some("html") | Some("htm") => parse_listing_page(&text),
+        _ => parse_text_list(&text),
+    };
+    Ok(seeds)
+}
+
+/// Adds `seeds` to the database as sites that were never crawled.
+/// Sites that are already in the database are left alone.
+pub fn insert_seeds(db: &Database, seeds: &[SiteId]) -> Result<(), DatabaseError> {
+    db.in_transaction(|db| {
+        for s in seeds {
+            db.add_site(SiteEntry::new(s.url(), 0)?)?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns the site `s` names, as a url, a domain (e.g. "dawnvoid.neocities.org"),
+/// or if `bare` is set just a name (e.g. "dawnvoid").
+pub fn parse_site(s: &str, bare: bool) -> Option<SiteId> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    if let Ok(url) = Url::parse(s) {
+        if url.has_host() {
+            return SiteId::from_url(&url);
+        }
+    }
+    if s.contains('.') || s.contains('/') {
+        let url = Url::parse(&format!("https://{s}")).ok()?;
+        return SiteId::from_url(&url);
+    }
+    if bare {
+        SiteId::new(s)
+    } else {
+        None
+    }
+}
+
+/// Removes duplicates from `sites`, keeping the first of each.
+fn unique(sites: impl Iterator<Item = SiteId>) -> Vec<SiteId> {
+    let mut seen = HashSet::new();
+    sites.filter(|s| seen.insert(s.clone())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fetch_sitemaps, insert_seeds, parse_csv_list, parse_json_list, parse_listing_page,
+        parse_sitemap, parse_text_list, sitemap_links, sites_of,
+    };
+    use crate::database::Database;
+    use crate::fetcher::FetchError;
+    use crate::site::{SiteId, SiteResolver};
+    use crate::testserver::{self, Response, TestServer};
+    use std::net::TcpListener;
+    use url::Url;
+
+    const SITEMAP: &str = include_str!("../tests/fixtures/seed/sitemap.xml");
+    const SITEMAP_INDEX: &str = include_str!("../tests/fixtures/seed/sitemapindex.xml");
+    const TEXT_LIST: &str = include_str!("../tests/fixtures/seed/sites.txt");
+    const CSV_LIST: &str = include_str!("../tests/fixtures/seed/sites.csv");
+    const JSON_LIST: &str = include_str!("../tests/fixtures/seed/sites.json");
+    const BROWSE: &str = include_str!("../tests/fixtures/seed/browse.html");
+    const FOLLOWING: &str = include_str!("../tests/fixtures/seed/following.html");
+
+    fn names(sites: &[SiteId]) -> Vec<&str> {
+        sites.iter().map(|s| s.name()).collect()
+    }
+
+    fn base() -> Url {
+        Url::parse("https://dawnvoid.neocities.org/").unwrap()
+    }
+
+    #[test]
+    fn sitemap() {
+        let sitemap = parse_sitemap(SITEMAP, &base());
+        let urls: Vec<&str> = sitemap.urls.iter().map(Url::as_str).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://dawnvoid.neocities.org/",
+                "https://dawnvoid.neocities.org/blog/index.html",
+                "https://dawnvoid.neocities.org/search?q=cats&page=2",
+                "https://koyo.neocities.org/art/",
+                "https://dawnvoid.neocities.org/relative.html",
+            ]
+        );
+        assert!(sitemap.sitemaps.is_empty());
+
+        let sites = sites_of(&sitemap.urls, &SiteResolver::new());
+        assert_eq!(names(&sites), vec!["dawnvoid", "koyo"]);
+    }
+
+    #[test]
+    fn sitemap_index() {
+        let sitemap = parse_sitemap(SITEMAP_INDEX, &base());
+        assert!(sitemap.urls.is_empty());
+        assert_eq!(
+            sitemap.sitemaps,
+            vec![
+                base().join("/sitemap-pages.xml").unwrap(),
+                base().join("/sitemap-blog.xml").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn sitemap_link() {
+        let html = r#"<head><link rel="sitemap" type="application/xml" href="/map.xml">
+            <link rel="stylesheet" href="/style.css"></head><a rel="sitemap" href="/no.xml">x</a>"#;
+        assert_eq!(
+            sitemap_links(html, &base()),
+            vec![base().join("/map.xml").unwrap()]
+        );
+    }
+
+    #[test]
+    fn text_list() {
+        assert_eq!(
+            names(&parse_text_list(TEXT_LIST)),
+            vec!["dawnvoid", "koyo", "errormine", "undoified", "kryptonaut"]
+        );
+    }
+
+    #[test]
+    fn csv_list() {
+        assert_eq!(
+            names(&parse_csv_list(CSV_LIST)),
+            vec!["dawnvoid", "koyo", "errormine", "undoified", "notes"]
+        );
+        // without a name column, only urls count
+        assert_eq!(
+            names(&parse_csv_list("alice,https://bob.neocities.org/\n")),
+            vec!["bob"]
+        );
+    }
+
+    #[test]
+    fn json_list() {
+        assert_eq!(
+            names(&parse_json_list(JSON_LIST)),
+            vec!["dawnvoid", "koyo", "errormine", "kryptonaut"]
+        );
+    }
+
+    #[test]
+    fn listing_pages() {
+        assert_eq!(
+            names(&parse_listing_page(BROWSE)),
+            vec!["dawnvoid", "koyo", "errormine"]
+        );
+        assert_eq!(
+            names(&parse_listing_page(FOLLOWING)),
+            vec!["undoified", "kryptonaut", "koyo"]
+        );
+    }
+
+    #[test]
+    fn fetch_site_sitemaps() {
+        let server = TestServer::start(vec![
+            (
+                "/robots.txt",
+                Response::new(200, "User-agent: *\nSitemap: /index.xml\n"),
+            ),
+            (
+                "/",
+                Response::html(r#"<link rel="sitemap" href="/linked.xml">"#),
+            ),
+            (
+                "/index.xml",
+                Response::new(
+                    200,
+                    "<sitemapindex><sitemap><loc>/linked.xml</loc></sitemap>\
+                    <sitemap><loc>/index.xml</loc></sitemap></sitemapindex>",
+                ),
+            ),
+            (
+                "/linked.xml",
+                Response::new(
+                    200,
+                    "<urlset><url><loc>https://koyo.neocities.org/</loc></url></urlset>",
+                ),
+            ),
+            (
+                "/sitemap.xml",
+                Response::new(
+                    200,
+                    "<urlset><url><loc>https://errormine.neocities.org/x</loc></url></urlset>",
+                ),
+            ),
+        ]);
+        let (urls, errors) = fetch_sitemaps(&testserver::fetcher(), &server.url("/"));
+        assert!(errors.is_empty());
+        let sites = sites_of(&urls, &SiteResolver::new());
+        // every sitemap is only fetched once, even when they point at each other
+        assert_eq!(names(&sites), vec!["koyo", "errormine"]);
+        let fetches = server
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/linked.xml")
+            .count();
+        assert_eq!(fetches, 1);
+    }
+
+    #[test]
+    fn fetch_sitemaps_obeys_robots() {
+        let server = TestServer::start(vec![
+            (
+                "/robots.txt",
+                Response::new(
+                    200,
+                    "User-agent: *\nDisallow: /private/\nSitemap: /private/map.xml\n",
+                ),
+            ),
+            ("/", Response::html("hi")),
+            (
+                "/private/map.xml",
+                Response::new(
+                    200,
+                    "<urlset><url><loc>https://koyo.neocities.org/</loc></url></urlset>",
+                ),
+            ),
+            (
+                "/sitemap.xml",
+                Response::new(
+                    200,
+                    "<urlset><url><loc>https://errormine.neocities.org/</loc></url></urlset>",
+                ),
+            ),
+        ]);
+        let (urls, errors) = fetch_sitemaps(&testserver::fetcher(), &server.url("/"));
+        assert!(errors.is_empty());
+        assert_eq!(
+            names(&sites_of(&urls, &SiteResolver::new())),
+            vec!["errormine"]
+        );
+        assert!(server
+            .requests()
+            .iter()
+            .all(|r| r.path != "/private/map.xml"));
+
+        /* nothing listens on a port that was just given back */
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = Url::parse(&format!("http://127.0.0.1:{port}/")).unwrap();
+        // a site without a robots.txt we can read is left alone
+        let (urls, errors) = fetch_sitemaps(&testserver::fetcher(), &url);
+        assert!(urls.is_empty());
+        assert!(matches!(errors[..], [FetchError::Connect(..)]));
+    }
+
+    #[test]
+    fn insert() {
+        let db = Database::connect_virtual().unwrap();
+        let seeds = parse_text_list("dawnvoid\nkoyo\n");
+        insert_seeds(&db, &seeds).unwrap();
+        insert_seeds(&db, &seeds).unwrap();
+
+        let sites = db.get_sites_with_oldest_crawltime(10).unwrap();
+        assert_eq!(sites.len(), 2);
+        assert!(sites.iter().all(|s| s.crawltime() == 0));
+        let schedule = db.get_schedule(10).unwrap();
+        assert!(schedule.iter().all(|s| s.is_new()));
+    }
+}
@@ -0,0 +1,177 @@
+//! Working out which neocities site a url belongs to.
+//!
+//! A site can be reached at `name.neocities.org`, `www.name.neocities.org`,
+//! `neocities.org/site/name` (its profile), and any custom domain it has set up.
+//! All of them are the same `SiteId`, so the graph gets one node per site.
+
+use std::collections::HashMap;
+use std::fmt;
+use url::Url;
+
+/// A neocities site, identified by its name (e.g. "dawnvoid" for dawnvoid.neocities.org).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SiteId {
+    name: String, // lowercase
+}
+
+impl SiteId {
+    /// Returns `None` if `name` can't be a neocities site name.
+    pub fn new(name: &str) -> Option<SiteId> {
+        let name = name.to_ascii_lowercase();
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        valid.then_some(SiteId { name })
+    }
+
+    /// Returns the site that `url` is on, or `None` if it isn't on a neocities site.
+    /// Doesn't know about custom domains, use a `SiteResolver` for those.
+    pub fn from_url(url: &Url) -> Option<SiteId> {
+        let host = normalize_host(url.domain()?);
+        if host == "neocities.org" {
+            /* profile pages are neocities.org/site/<name> */
+            let mut segments = url.path_segments()?;
+            return match (segments.next(), segments.next()) {
+                (Some("site"), Some(name)) => SiteId::new(name),
+                _ => None,
+            };
+        }
+        let rest = host.strip_suffix(".neocities.org")?;
+        /* the site name is the label right before neocities.org */
+        SiteId::new(rest.rsplit('.').next()?)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The root url of the site, which is how sites are stored in the `Database`.
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("https://{}.neocities.org/", self.name)).unwrap()
+    }
+}
+
+impl fmt::Display for SiteId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Lowercases `host` and strips a trailing dot and a leading "www.".
+pub(crate) fn normalize_host(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match host.strip_prefix("www.") {
+        Some(h) => h.to_string(),
+        None => host,
+    }
+}
+
+/// Maps urls to sites, including custom domains that neocities sites are served from.
+#[derive(Debug, Clone, Default)]
+pub struct SiteResolver {
+    domains: HashMap<String, SiteId>, // custom domain (without "www.") -> site
+}
+
+impl SiteResolver {
+    pub fn new() -> SiteResolver {
+        SiteResolver::default()
+    }
+
+    /// Makes urls on `domain` (and www.`domain`) belong to `site`.
+    pub fn add_domain(&mut self, domain: &str, site: SiteId) {
+        self.domains.insert(normalize_host(domain), site);
+    }
+
+    /// Returns the custom domains, in no particular order.
+    pub fn domains(&self) -> impl Iterator<Item = (&str, &SiteId)> {
+        self.domains.iter().map(|(d, s)| (d.as_str(), s))
+    }
+
+    /// Returns the site that `url` is on, or `None` if it isn't on a neocities site.
+    pub fn resolve(&self, url: &Url) -> Option<SiteId> {
+        let domain = url.domain()?;
+        match self.domains.get(&normalize_host(domain)) {
+            Some(site) => Some(site.clone()),
+            None => SiteId::from_url(url),
+        }
+    }
+
+    /// Returns whether `url` is on the same site as `siteurl`.
+    /// Urls that aren't on a neocities site are compared by domain instead.
+    pub fn is_in_site(&self, url: &Url, siteurl: &Url) -> bool {
+        match (self.resolve(url), self.resolve(siteurl)) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => match (url.domain(), siteurl.domain()) {
+                (Some(a), Some(b)) => normalize_host(a) == normalize_host(b),
+                _ => url.host_str() == siteurl.host_str(),
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SiteId, SiteResolver};
+    use url::Url;
+
+    fn site(url: &str) -> Option<String> {
+        SiteId::from_url(&Url::parse(url).unwrap()).map(|s| s.name().to_string())
+    }
+
+    #[test]
+    fn site_names() {
+        let dawnvoid = Some(String::from("dawnvoid"));
+        assert_eq!(site("https://dawnvoid.neocities.org/"), dawnvoid);
+        assert_eq!(site("https://DawnVoid.neocities.org/about"), dawnvoid);
+        assert_eq!(site("https://www.dawnvoid.neocities.org/"), dawnvoid);
+        assert_eq!(site("https://dawnvoid.neocities.org./"), dawnvoid);
+        assert_eq!(site("https://neocities.org/site/dawnvoid"), dawnvoid);
+        assert_eq!(
+            site("https://www.neocities.org/site/dawnvoid?page=2"),
+            dawnvoid
+        );
+
+        assert_eq!(site("https://neocities.org/"), None);
+        assert_eq!(site("https://neocities.org/browse"), None);
+        assert_eq!(site("https://example.com/"), None);
+        assert_eq!(site("https://neocities.org.example.com/"), None);
+        assert_eq!(site("http://127.0.0.1/"), None);
+    }
+
+    #[test]
+    fn site_url() {
+        let id = SiteId::new("Koyo").unwrap();
+        assert_eq!(id.url().as_str(), "https://koyo.neocities.org/");
+        assert!(SiteId::new("").is_none());
+        assert!(SiteId::new("a/b").is_none());
+    }
+
+    #[test]
+    fn custom_domains() {
+        let mut sites = SiteResolver::new();
+        sites.add_domain("www.koyo.example", SiteId::new("koyo").unwrap());
+
+        let koyo = Url::parse("https://koyo.example/art/").unwrap();
+        assert_eq!(sites.resolve(&koyo), SiteId::new("koyo"));
+        let www = Url::parse("https://www.koyo.example/").unwrap();
+        assert_eq!(sites.resolve(&www), SiteId::new("koyo"));
+
+        let root = Url::parse("https://koyo.neocities.org/").unwrap();
+        assert!(sites.is_in_site(&koyo, &root));
+        assert!(sites.is_in_site(&root, &koyo));
+        let other = Url::parse("https://dawnvoid.neocities.org/").unwrap();
+        assert!(!sites.is_in_site(&other, &root));
+    }
+
+    #[test]
+    fn in_site_other_hosts() {
+        let sites = SiteResolver::new();
+        let a = Url::parse("http://localhost:1234/a.html").unwrap();
+        let b = Url::parse("http://localhost:1234/b.html").unwrap();
+        let c = Url::parse("http://example.com/").unwrap();
+        assert!(sites.is_in_site(&a, &b));
+        assert!(!sites.is_in_site(&a, &c));
+    }
+}
@@ -0,0 +1,28 @@
+//! Small site graphs for tests, so each test only has to list its links.
+
+use crate::analysis::LinkGraph;
+use crate::database::{Database, LinkEntry, SiteEntry};
+use url::Url;
+
+/// Returns a graph with a link for every pair in `links`, using the names as they are.
+pub fn graph(links: &[(&str, &str)]) -> LinkGraph {
+    let mut g = LinkGraph::new();
+    for (src, dst) in links {
+        g.add_link(src, dst);
+    }
+    g
+}
+
+/// Returns a new in-memory database with a link for every pair of site names in `links`
+/// (e.g. "alice" for "https://alice.neocities.org/").
+/// The site every link starts at is added too, as a site that was never crawled.
+pub fn database(links: &[(&str, &str)]) -> Database {
+    let url = |name: &str| Url::parse(&format!("https://{name}.neocities.org/")).unwrap();
+    let db = Database::connect_virtual().unwrap();
+    for (src, dst) in links {
+        db.add_site(SiteEntry::new(url(src), 0).unwrap()).unwrap();
+        db.set_link(LinkEntry::new(url(src), url(dst)).unwrap())
+            .unwrap();
+    }
+    db
+}
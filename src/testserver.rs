@@ -0,0 +1,145 @@
+//! A tiny http server for tests, so nothing has to touch the real internet.
+
+use crate::fetcher::{Fetcher, FetcherConfig};
+use crate::politeness::PolitenessConfig;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+/// A canned response for one path.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub delay: Duration, // how long to wait before responding
+}
+
+impl Response {
+    pub fn new(status: u16, body: &str) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn html(body: &str) -> Response {
+        Response::new(200, body).header("Content-Type", "text/html; charset=utf-8")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Response {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A request that the server received.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub path: String,
+    pub headers: HashMap<String, String>, // names are lowercase
+}
+
+/// Serves `routes` (path and query -> response) on localhost until the test process exits.
+/// Unknown paths get a 404. A path that is listed more than once gets its
+/// responses in order, and keeps getting the last one.
+pub struct TestServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub fn start(routes: Vec<(&str, Response)>) -> TestServer {
+        let mut table: HashMap<String, Vec<Response>> = HashMap::new();
+        for (p, r) in routes {
+            table.entry(p.to_string()).or_default().push(r);
+        }
+        let routes = Arc::new(Mutex::new(table));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let r = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let routes = Arc::clone(&routes);
+                let requests = Arc::clone(&r);
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream);
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).is_err() {
+                        return;
+                    }
+                    let path = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    let mut headers = HashMap::new();
+                    loop {
+                        let mut h = String::new();
+                        if reader.read_line(&mut h).is_err() || h.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = h.split_once(':') {
+                            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                        }
+                    }
+                    requests.lock().unwrap().push(Request {
+                        path: path.clone(),
+                        headers,
+                    });
+
+                    let response = match routes.lock().unwrap().get_mut(&path) {
+                        Some(r) if r.len() > 1 => r.remove(0),
+                        Some(r) => r[0].clone(),
+                        None => Response::new(404, "not found"),
+                    };
+                    thread::sleep(response.delay);
+
+                    let mut out = format!("HTTP/1.1 {} X\r\n", response.status);
+                    for (name, value) in &response.headers {
+                        out.push_str(&format!("{name}: {value}\r\n"));
+                    }
+                    out.push_str(&format!(
+                        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        response.body.len()
+                    ));
+                    let mut stream = reader.into_inner();
+                    let _ = stream.write_all(out.as_bytes());
+                    let _ = stream.write_all(&response.body);
+                });
+            }
+        });
+
+        TestServer { port, requests }
+    }
+
+    /// Returns the url of `path` on this server.
+    /// Uses "localhost" rather than an ip address so the url has a domain.
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://localhost:{}{path}", self.port)).unwrap()
+    }
+
+    /// Returns every request received so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// A `Fetcher` without any politeness delays, so tests against a `TestServer` don't crawl along.
+pub fn fetcher() -> Fetcher {
+    let config = FetcherConfig {
+        politeness: PolitenessConfig::none(),
+        ..Default::default()
+    };
+    Fetcher::new(config).unwrap()
+}